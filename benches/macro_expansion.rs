@@ -0,0 +1,72 @@
+//! Measures macro expansion on generated sources of increasing size.
+//!
+//! Run with `cargo bench --bench macro_expansion`. Expansion is linear, so the time per
+//! megabyte should stay roughly constant as the source grows.
+
+use rustatex::macros::expand_source;
+use rustatex::parser::{parse_input, ParserConfig};
+use std::time::{Duration, Instant};
+
+const SIZES_MB: [usize; 4] = [1, 2, 4, 8];
+const RUNS: usize = 3;
+
+/// Builds a source of roughly `size` bytes that defines a few macros and uses them in
+/// every paragraph.
+fn generate(size: usize) -> String {
+    let mut source = String::with_capacity(size + 1024);
+    source.push_str("\\newcommand{\\product}{RustaTex}\n");
+    source.push_str("\\newcommand{\\term}[1]{\\emph{#1}}\n");
+    source.push_str("\\newcommand{\\note}[2][Note]{\\textbf{#1:} #2}\n");
+    source.push_str("\\newcommand{\\nested}[1]{\\note{\\term{#1} in \\product}}\n\n");
+
+    let mut index = 0;
+    while source.len() < size {
+        source.push_str(&format!(
+            "\\section{{Chapter {index}}}\n\
+             \\product{{}} expands \\term{{macros}} in paragraph {index}. \
+             Escaped \\\\ backslashes, 100\\% signs and \\{{braces\\}} stay as they are.\n\
+             \\note{{Remember this.}} \\nested{{expansion}} % a comment with \\product\n\n"
+        ));
+        index += 1;
+    }
+    source
+}
+
+/// Returns the fastest of a few runs of `run`.
+fn time(mut run: impl FnMut()) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+fn main() {
+    println!(
+        "{:>6} {:>12} {:>10} {:>12} {:>10}",
+        "size", "expand", "per MB", "parse", "per MB"
+    );
+    for size_mb in SIZES_MB {
+        let source = generate(size_mb * 1024 * 1024);
+        let megabytes = source.len() as f64 / (1024.0 * 1024.0);
+
+        let expand = time(|| {
+            expand_source(&source, None, &ParserConfig::default()).expect("expansion failed");
+        });
+        let parse = time(|| {
+            parse_input(&source).expect("parsing failed");
+        });
+
+        println!(
+            "{:>4}MB {:>10.1}ms {:>8.1}ms {:>10.1}ms {:>8.1}ms",
+            size_mb,
+            expand.as_secs_f64() * 1000.0,
+            expand.as_secs_f64() * 1000.0 / megabytes,
+            parse.as_secs_f64() * 1000.0,
+            parse.as_secs_f64() * 1000.0 / megabytes,
+        );
+    }
+}
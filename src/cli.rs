@@ -21,21 +21,29 @@ pub struct Cli {
     pub pdf: Option<String>,
 
     /// Output HTML file
-    #[arg(short = 'h', long, value_name = "HTML_FILE", conflicts_with = "pdf")]
+    #[arg(long, value_name = "HTML_FILE", conflicts_with = "pdf")]
     pub html: Option<String>,
 
     /// Specify the paper size (e.g., A4, Letter)
-    #[arg(short, long, value_name = "SIZE", default_value = "A4")]
+    #[arg(long, value_name = "SIZE", default_value = "A4")]
     pub paper_size: String,
 
     /// Enable verbose logging
     #[arg(short, long, action = clap::ArgAction::SetTrue)]
     pub verbose: bool,
 
-    /// Set the font family (e.g., Times, Arial)
+    /// Set the font: Times, Helvetica, Courier, a TrueType (.ttf) file or the name of one (e.g., DejaVuSans); OpenType CFF fonts are not supported
     #[arg(long, value_name = "FONT", default_value = "Times")]
     pub font: String,
 
+    /// Set the font for code, in the same form as --font
+    #[arg(long, value_name = "FONT", default_value = "Courier")]
+    pub mono_font: String,
+
+    /// Add a directory to search for fonts given by name
+    #[arg(long, value_name = "DIR", number_of_values = 1)]
+    pub font_dir: Vec<String>,
+
     /// Set the font size (in points)
     #[arg(long, value_name = "SIZE", default_value = "12")]
     pub font_size: u32,
@@ -56,6 +64,14 @@ pub struct Cli {
     #[arg(short, long, value_name = "MACRO", number_of_values = 1)]
     pub macro_def: Vec<String>,
 
+    /// Add a directory to search for \input and \include files
+    #[arg(short = 'I', long, value_name = "DIR", number_of_values = 1)]
+    pub include_path: Vec<String>,
+
+    /// Set a flag for \ifdef conditionals (draft and the output format are set automatically)
+    #[arg(long, value_name = "FLAG", number_of_values = 1)]
+    pub flag: Vec<String>,
+
     /// Set the line spacing
     #[arg(long, value_name = "SPACING", default_value = "1.5")]
     pub line_spacing: f32,
@@ -84,14 +100,22 @@ pub struct Cli {
     #[arg(long, value_name = "MARGINS", default_value = "1in")]
     pub margins: String,
 
-    /// Specify header content
+    /// Header on every page as left|center|right, with {page}, {pages} (the page count), {title}, {section} and {date}
     #[arg(long, value_name = "HEADER")]
     pub header: Option<String>,
 
-    /// Specify footer content
+    /// Footer on every page, in the same form as the header
     #[arg(long, value_name = "FOOTER")]
     pub footer: Option<String>,
 
+    /// Set the distance from the top of the page to the header (e.g., 10mm)
+    #[arg(long, value_name = "LENGTH")]
+    pub header_offset: Option<String>,
+
+    /// Set the distance from the bottom of the page to the footer (e.g., 10mm)
+    #[arg(long, value_name = "LENGTH")]
+    pub footer_offset: Option<String>,
+
     /// Enable syntax highlighting for code blocks
     #[arg(long, action = clap::ArgAction::SetTrue)]
     pub syntax_highlighting: bool,
@@ -100,14 +124,40 @@ pub struct Cli {
     #[arg(long, value_name = "THEME", default_value = "light")]
     pub theme: String,
 
-    /// Set the page numbering style (e.g., arabic, roman)
+    /// Set the page numbering style (arabic, roman, Roman, alph or Alph)
     #[arg(long, value_name = "STYLE", default_value = "arabic")]
     pub page_numbering: String,
 
-    /// Enable watermark
+    /// Draw watermark text across the pages
     #[arg(long, value_name = "TEXT")]
     pub watermark: Option<String>,
 
+    /// Set the watermark opacity, from 0 to 1
+    #[arg(long, value_name = "OPACITY", default_value = "0.3")]
+    pub watermark_opacity: f64,
+
+    /// Set the watermark angle in degrees, counterclockwise
+    #[arg(
+        long,
+        value_name = "DEGREES",
+        default_value = "45",
+        allow_negative_numbers = true
+    )]
+    pub watermark_angle: f64,
+
+    /// Set the watermark colour (e.g., grey, red, #336699)
+    #[arg(long, value_name = "COLOR", default_value = "grey")]
+    pub watermark_color: String,
+
+    /// Set the watermark font size (in points)
+    #[arg(long, value_name = "SIZE", default_value = "72")]
+    pub watermark_size: f64,
+
+    /// Only draw the watermark on these pages, counted from the first page of the PDF
+    /// whatever its printed number (e.g., 1,3-5,10-)
+    #[arg(long, value_name = "PAGES")]
+    pub watermark_pages: Option<String>,
+
     /// Specify the DPI for images
     #[arg(long, value_name = "DPI", default_value = "300")]
     pub image_dpi: u32,
@@ -120,10 +170,35 @@ pub struct Cli {
     #[arg(long, value_name = "COLUMNS", default_value = "1")]
     pub columns: u32,
 
+    /// Set how paragraph lines are aligned (justify, left, right or center)
+    #[arg(long, value_name = "ALIGNMENT", default_value = "justify")]
+    pub align: String,
+
+    /// Set the highest badness a line may have before breaking gets looser (TeX's \tolerance)
+    #[arg(long, value_name = "TOLERANCE", default_value = "200")]
+    pub tolerance: f64,
+
+    /// Make paragraphs this many lines longer, or if negative shorter, where possible
+    #[arg(
+        long,
+        value_name = "LINES",
+        default_value = "0",
+        allow_negative_numbers = true
+    )]
+    pub looseness: i32,
+
     /// Enable automatic hyphenation
     #[arg(long, action = clap::ArgAction::SetTrue)]
     pub hyphenation: bool,
 
+    /// Set the fewest characters kept before a hyphen
+    #[arg(long, value_name = "CHARS", default_value = "2")]
+    pub left_hyphen_min: usize,
+
+    /// Set the fewest characters carried over after a hyphen
+    #[arg(long, value_name = "CHARS", default_value = "3")]
+    pub right_hyphen_min: usize,
+
     /// Specify custom styles file
     #[arg(long, value_name = "STYLES_FILE")]
     pub styles_file: Option<String>,
@@ -132,7 +207,7 @@ pub struct Cli {
     #[arg(long, action = clap::ArgAction::SetTrue)]
     pub bookmarks: bool,
 
-    /// Set the default language for the document
+    /// Set the default language for the document, which hyphenation follows (en, de or fr)
     #[arg(long, value_name = "LANGUAGE", default_value = "en")]
     pub language: String,
 
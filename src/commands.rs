@@ -0,0 +1,401 @@
+use crate::document::*;
+use crate::errors::RustaTexError;
+use crate::parser::{unescape, Command, CommandContext};
+use log::{debug, error};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// What a command produces where it appears.
+#[derive(Debug)]
+pub enum CommandOutput {
+    /// Inline content that continues the current paragraph.
+    Inline(Inline),
+    /// A block element, which ends the current paragraph.
+    Element(DocumentElement),
+    /// Nothing; the command only changed the parser or document state.
+    None,
+}
+
+/// Handles a `\command` found while parsing.
+///
+/// Handlers are registered by name in a [`CommandRegistry`], usually through
+/// [`Parser::register`](crate::parser::Parser::register). Any function or closure
+/// taking a [`Command`] and a [`CommandContext`] is a handler.
+pub trait CommandHandler {
+    fn handle(
+        &self,
+        command: &Command,
+        context: &mut CommandContext,
+    ) -> Result<CommandOutput, RustaTexError>;
+}
+
+impl<F> CommandHandler for F
+where
+    F: Fn(&Command, &mut CommandContext) -> Result<CommandOutput, RustaTexError>,
+{
+    fn handle(
+        &self,
+        command: &Command,
+        context: &mut CommandContext,
+    ) -> Result<CommandOutput, RustaTexError> {
+        self(command, context)
+    }
+}
+
+/// The commands a parser knows, by name.
+#[derive(Default)]
+pub struct CommandRegistry {
+    handlers: HashMap<String, Box<dyn CommandHandler>>,
+}
+
+impl CommandRegistry {
+    /// Creates a registry without any commands.
+    pub fn new() -> Self {
+        CommandRegistry::default()
+    }
+
+    /// Creates a registry with the built-in commands.
+    pub fn with_builtins() -> Self {
+        let mut registry = CommandRegistry::new();
+        registry.register("textbf", textbf);
+        registry.register("emph", emph);
+        registry.register("textit", emph);
+        registry.register("texttt", texttt);
+        registry.register("href", href);
+        registry.register("url", url);
+        registry.register("label", label);
+        registry.register("ref", reference);
+        registry.register("pageref", reference);
+        registry.register("cite", cite);
+        registry.register("footnote", footnote);
+        for level in SectionLevel::ALL {
+            registry.register(level.command(), heading);
+        }
+        registry.register("appendix", appendix);
+        registry.register("tableofcontents", table_of_contents);
+        registry.register("frontmatter", matter);
+        registry.register("mainmatter", matter);
+        registry.register("backmatter", matter);
+        registry.register("item", item);
+        registry.register("title", title);
+        registry.register("author", author);
+        registry.register("date", date);
+        registry.register("setmainfont", set_font);
+        registry.register("setmonofont", set_font);
+        registry.register("language", language);
+        registry.register("hyphenation", hyphenation);
+        registry
+    }
+
+    /// Registers `handler` for `\name`, replacing any previous handler.
+    pub fn register(&mut self, name: impl Into<String>, handler: impl CommandHandler + 'static) {
+        self.handlers.insert(name.into(), Box::new(handler));
+    }
+
+    /// Removes the handler for `\name`, returning whether there was one.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.handlers.remove(name).is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn CommandHandler> {
+        self.handlers.get(name).map(Box::as_ref)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.handlers.contains_key(name)
+    }
+
+    /// Returns the names of the registered commands, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.handlers.keys().map(String::as_str)
+    }
+}
+
+impl std::fmt::Debug for CommandRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names: Vec<_> = self.handlers.keys().collect();
+        names.sort();
+        f.debug_struct("CommandRegistry")
+            .field("commands", &names)
+            .finish()
+    }
+}
+
+fn textbf(command: &Command, context: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    Ok(CommandOutput::Inline(Inline::Strong(
+        context.inline_arg(command, 0)?,
+    )))
+}
+
+fn emph(command: &Command, context: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    Ok(CommandOutput::Inline(Inline::Emph(
+        context.inline_arg(command, 0)?,
+    )))
+}
+
+fn texttt(command: &Command, _: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    Ok(CommandOutput::Inline(Inline::Code(unescape(
+        command.arg(0)?,
+    ))))
+}
+
+fn href(command: &Command, context: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    Ok(CommandOutput::Inline(Inline::Link {
+        url: command.arg(0)?.trim().to_string(),
+        content: context.inline_arg(command, 1)?,
+    }))
+}
+
+fn url(command: &Command, _: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    let url = command.arg(0)?.trim().to_string();
+    Ok(CommandOutput::Inline(Inline::Link {
+        content: vec![Inline::Text(url.clone())],
+        url,
+    }))
+}
+
+/// Attaches a label to the most recent heading, numbered environment or list item.
+fn label(command: &Command, context: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    let key = label_key(command)?;
+    context.define_label(key, &command.location)?;
+    Ok(CommandOutput::None)
+}
+
+/// `\ref{key}` or `\pageref{key}`. The label may be defined later in the input.
+fn reference(
+    command: &Command,
+    context: &mut CommandContext,
+) -> Result<CommandOutput, RustaTexError> {
+    let key = label_key(command)?;
+    context.add_reference(key.clone(), &command.location);
+    Ok(CommandOutput::Inline(if command.name == "pageref" {
+        Inline::PageRef(key)
+    } else {
+        Inline::Ref(key)
+    }))
+}
+
+fn label_key(command: &Command) -> Result<String, RustaTexError> {
+    let key = command.arg(0)?.trim();
+    if key.is_empty() {
+        error!(
+            "Empty label key in \\{} at {}",
+            command.name, command.location
+        );
+        return Err(RustaTexError::InvalidSyntax(format!(
+            "Empty label key in \\{} at {}",
+            command.name, command.location
+        )));
+    }
+    Ok(key.to_string())
+}
+
+fn cite(command: &Command, _: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    Ok(CommandOutput::Inline(Inline::Cite(
+        command
+            .arg(0)?
+            .split(',')
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty())
+            .collect(),
+    )))
+}
+
+fn footnote(
+    command: &Command,
+    context: &mut CommandContext,
+) -> Result<CommandOutput, RustaTexError> {
+    let content = context.inline_arg(command, 0)?;
+    Ok(CommandOutput::Inline(Inline::FootnoteMark {
+        number: context.next_footnote(),
+        content,
+    }))
+}
+
+/// Starts a heading; the parser nests the content that follows under it. The starred
+/// form, e.g. `\section*{...}`, is not numbered and left out of the table of contents
+/// unless given `[toc]`. Headings are in the PDF outline when they are in the table of
+/// contents, unless given `[bookmark=false]`.
+fn heading(
+    command: &Command,
+    context: &mut CommandContext,
+) -> Result<CommandOutput, RustaTexError> {
+    let level = SectionLevel::from_command(&command.name).unwrap_or(SectionLevel::Section);
+    let title = context.argument_text(command, 0)?;
+    let number = if command.starred {
+        None
+    } else {
+        context.number_heading(level)
+    };
+    let in_toc = command.options.get_bool("toc").unwrap_or(!command.starred);
+    let section = Section {
+        level,
+        number,
+        title,
+        elements: Vec::new(),
+        labels: Vec::new(),
+        in_toc,
+        in_bookmarks: command.options.get_bool("bookmark").unwrap_or(in_toc),
+    };
+    debug!("Added {}: {}", command.name, command.arg(0)?);
+    Ok(CommandOutput::Element(DocumentElement::Section(section)))
+}
+
+/// Starts a list item; the parser nests the content that follows under it.
+fn item(command: &Command, context: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    let number = context.number_item(&command.location)?;
+    Ok(CommandOutput::Element(DocumentElement::ListItem(
+        ListItem {
+            number,
+            labels: Vec::new(),
+            elements: Vec::new(),
+        },
+    )))
+}
+
+/// Places the table of contents, e.g. `\tableofcontents[depth=section, title=Outline]`.
+/// The depth is a heading command name or a LaTeX `tocdepth` number.
+fn table_of_contents(
+    command: &Command,
+    _: &mut CommandContext,
+) -> Result<CommandOutput, RustaTexError> {
+    let mut toc = TableOfContents::default();
+    if let Some(title) = command.options.get_text("title") {
+        toc.title = title;
+    }
+    if let Some(value) = command.options.get("depth") {
+        let depth = match value {
+            OptionValue::Number(depth) if depth.fract() == 0.0 => {
+                SectionLevel::from_depth(*depth as i64)
+            }
+            OptionValue::Text(name) => SectionLevel::from_command(name),
+            _ => None,
+        };
+        let Some(depth) = depth else {
+            error!(
+                "Invalid table of contents depth at {}: expected a heading such as 'subsection' or a number from -1 to 4",
+                command.location
+            );
+            return Err(RustaTexError::InvalidSyntax(format!(
+                "Invalid table of contents depth at {}: expected a heading such as 'subsection' or a number from -1 to 4",
+                command.location
+            )));
+        };
+        toc.depth = depth;
+    }
+    debug!("Added table of contents to depth {:?}", toc.depth);
+    Ok(CommandOutput::Element(DocumentElement::TableOfContents(
+        toc,
+    )))
+}
+
+/// Switches chapter or section numbering to letters for the rest of the document.
+fn appendix(_: &Command, context: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    context.start_appendix();
+    debug!("Started appendix.");
+    Ok(CommandOutput::None)
+}
+
+/// Starts the front, main or back matter, which changes heading numbering here and
+/// page numbering in the output.
+fn matter(command: &Command, context: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    let matter = match command.name.as_str() {
+        "frontmatter" => Matter::Front,
+        "backmatter" => Matter::Back,
+        _ => Matter::Main,
+    };
+    context.start_matter(matter);
+    debug!("Started {:?} matter.", matter);
+    Ok(CommandOutput::Element(DocumentElement::Matter(matter)))
+}
+
+fn title(command: &Command, context: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    let title = context.argument_text(command, 0)?;
+    context.document().set_title(title);
+    debug!("Set title: {}", command.arg(0)?);
+    Ok(CommandOutput::None)
+}
+
+fn author(command: &Command, context: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    let author = context.argument_text(command, 0)?;
+    context.document().set_author(author);
+    debug!("Set author: {}", command.arg(0)?);
+    Ok(CommandOutput::None)
+}
+
+fn date(command: &Command, context: &mut CommandContext) -> Result<CommandOutput, RustaTexError> {
+    let date = context.argument_text(command, 0)?;
+    context.document().set_date(date);
+    debug!("Set date: {}", command.arg(0)?);
+    Ok(CommandOutput::None)
+}
+
+/// Chooses the main or monospace font of the document, as `\setmainfont{name}` or
+/// `\setmainfont[Path=fonts/,BoldFont=name-Heavy]{name}`. Paths are relative to the
+/// file the command is in.
+fn set_font(
+    command: &Command,
+    context: &mut CommandContext,
+) -> Result<CommandOutput, RustaTexError> {
+    // Input given as a string resolves relative to the working directory.
+    let base = command
+        .location
+        .path
+        .as_deref()
+        .and_then(Path::parent)
+        .unwrap_or(Path::new(""));
+    let resolve = |name: &str| {
+        let path = base.join(name);
+        match path.is_file() {
+            true => path.to_string_lossy().into_owned(),
+            false => name.to_string(),
+        }
+    };
+    let face = |key: &str| command.options.get_text(key).map(|name| resolve(&name));
+    let font = FontChoice {
+        name: resolve(command.arg(0)?.trim()),
+        dirs: command
+            .options
+            .get_text("Path")
+            .map(|dir| base.join(dir))
+            .into_iter()
+            .collect(),
+        bold: face("BoldFont"),
+        italic: face("ItalicFont"),
+        bold_italic: face("BoldItalicFont"),
+    };
+    debug!("Set {}: {}", command.name, font.name);
+    match command.name.as_str() {
+        "setmonofont" => context.document().set_mono_font(font),
+        _ => context.document().set_main_font(font),
+    }
+    Ok(CommandOutput::None)
+}
+
+/// Sets the language of the document, as `\language{de}`, which chooses how words are
+/// hyphenated.
+fn language(
+    command: &Command,
+    context: &mut CommandContext,
+) -> Result<CommandOutput, RustaTexError> {
+    let language = command.arg(0)?.trim().to_string();
+    debug!("Set language: {}", language);
+    context.document().set_language(language);
+    Ok(CommandOutput::None)
+}
+
+/// Gives the hyphens of words the language's patterns get wrong, as
+/// `\hyphenation{ex-cep-tion data-base}`.
+fn hyphenation(
+    command: &Command,
+    context: &mut CommandContext,
+) -> Result<CommandOutput, RustaTexError> {
+    let words: Vec<String> = command
+        .arg(0)?
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    debug!("Added hyphenation exceptions: {}", words.join(" "));
+    context.document().add_hyphenation(words);
+    Ok(CommandOutput::None)
+}
@@ -1,51 +1,489 @@
+use crate::lexer::{find_group, tokenize, TokenKind};
+use crate::macros::Macro;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum DocumentElement {
     Section(Section),
-    Subsection(Subsection),
     Paragraph(Paragraph),
-    // Future elements: List, Table, Figure, Equation, CodeBlock, etc.
+    Environment(Environment),
+    /// An `\item` of an `itemize` or `enumerate` list and the content that follows it.
+    ListItem(ListItem),
+    /// Where `\tableofcontents` asks for the table of contents.
+    TableOfContents(TableOfContents),
+    /// Where `\frontmatter`, `\mainmatter` or `\backmatter` starts a part of the book.
+    Matter(Matter),
+    // Future elements: Table, Figure, Equation, CodeBlock, etc.
 }
 
+/// A heading and the content that follows it up to the next heading at the same or an
+/// outer level.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Section {
+    pub level: SectionLevel,
+    /// The heading number such as `2.1`, or `None` for unnumbered headings.
+    pub number: Option<String>,
     pub title: String,
     pub elements: Vec<DocumentElement>,
-    pub label: Option<String>,
+    /// The keys of the `\label`s attached to the heading.
+    pub labels: Vec<String>,
+    /// Whether the heading is listed in the table of contents.
+    pub in_toc: bool,
+    /// Whether the heading has an entry in the PDF outline.
+    pub in_bookmarks: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Subsection {
-    pub title: String,
-    pub elements: Vec<DocumentElement>,
-    pub label: Option<String>,
+impl Section {
+    /// Returns the heading as displayed: the title preceded by its number, if any.
+    pub fn heading_text(&self) -> String {
+        match (&self.number, self.level) {
+            (Some(number), SectionLevel::Part) => format!("Part {}: {}", number, self.title),
+            (Some(number), _) => format!("{} {}", number, self.title),
+            (None, _) => self.title.clone(),
+        }
+    }
+}
+
+/// Heading levels, from the outermost to the innermost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum SectionLevel {
+    Part,
+    Chapter,
+    Section,
+    Subsection,
+    Subsubsection,
+    Paragraph,
+}
+
+impl SectionLevel {
+    pub const ALL: [SectionLevel; 6] = [
+        SectionLevel::Part,
+        SectionLevel::Chapter,
+        SectionLevel::Section,
+        SectionLevel::Subsection,
+        SectionLevel::Subsubsection,
+        SectionLevel::Paragraph,
+    ];
+
+    /// Returns the level of a heading command such as `subsection`.
+    pub fn from_command(name: &str) -> Option<Self> {
+        SectionLevel::ALL
+            .into_iter()
+            .find(|level| level.command() == name)
+    }
+
+    /// Returns the name of the command that starts a heading at this level.
+    pub fn command(self) -> &'static str {
+        match self {
+            SectionLevel::Part => "part",
+            SectionLevel::Chapter => "chapter",
+            SectionLevel::Section => "section",
+            SectionLevel::Subsection => "subsection",
+            SectionLevel::Subsubsection => "subsubsection",
+            SectionLevel::Paragraph => "paragraph",
+        }
+    }
+
+    /// Returns the level of a LaTeX `tocdepth`-style depth, from -1 for parts to 4 for
+    /// paragraphs.
+    pub fn from_depth(depth: i64) -> Option<Self> {
+        usize::try_from(depth + 1)
+            .ok()
+            .and_then(|index| SectionLevel::ALL.get(index).copied())
+    }
+
+    /// Returns the position of this level in [`SectionLevel::ALL`], 0 for parts.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Returns the level a heading at this level must be nested in. Parts and chapters
+    /// are optional, and `\paragraph` may appear under any heading.
+    pub fn required_parent(self) -> Option<Self> {
+        match self {
+            SectionLevel::Subsection => Some(SectionLevel::Section),
+            SectionLevel::Subsubsection => Some(SectionLevel::Subsection),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Paragraph {
-    pub text: String,
+    pub content: Vec<Inline>,
     pub style: Option<Style>,
 }
 
+impl Paragraph {
+    /// Returns the paragraph content as plain text.
+    pub fn plain_text(&self) -> String {
+        inline_text(&self.content)
+    }
+}
+
+/// Inline content of a paragraph or of an inline command's argument.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Inline {
+    /// Text with whitespace normalised to single spaces.
+    Text(String),
+    /// `\emph{...}` or `\textit{...}`.
+    Emph(Vec<Inline>),
+    /// `\textbf{...}`.
+    Strong(Vec<Inline>),
+    /// `\texttt{...}`, kept verbatim.
+    Code(String),
+    /// `\href{url}{text}` or `\url{url}`.
+    Link { url: String, content: Vec<Inline> },
+    /// `\ref{key}`.
+    Ref(String),
+    /// `\pageref{key}`, resolved to a page number when rendering.
+    PageRef(String),
+    /// `\cite{key1,key2}`.
+    Cite(Vec<String>),
+    /// `$...$`, kept as source.
+    Math(String),
+    /// `\footnote{...}` with its number in the document.
+    FootnoteMark { number: u32, content: Vec<Inline> },
+    /// An explicit `\\` line break.
+    LineBreak,
+}
+
+/// Flattens inline content into plain text; line breaks become `\n`.
+pub fn inline_text(content: &[Inline]) -> String {
+    let mut text = String::new();
+    for inline in content {
+        match inline {
+            Inline::Text(value) | Inline::Code(value) | Inline::Math(value) => text.push_str(value),
+            Inline::Emph(inner) | Inline::Strong(inner) => text.push_str(&inline_text(inner)),
+            Inline::Link { content, .. } => text.push_str(&inline_text(content)),
+            Inline::Ref(key) | Inline::PageRef(key) => text.push_str(key),
+            Inline::Cite(keys) => text.push_str(&format!("[{}]", keys.join(", "))),
+            Inline::FootnoteMark { number, .. } => text.push_str(&number.to_string()),
+            Inline::LineBreak => text.push('\n'),
+        }
+    }
+    text
+}
+
+/// A `\begin{name}...\end{name}` block and its nested content.
 #[derive(Debug, Serialize, Deserialize)]
+pub struct Environment {
+    pub name: String,
+    pub options: Options,
+    pub args: Vec<String>,
+    /// The number of a numbered environment such as `figure`.
+    pub number: Option<String>,
+    /// The keys of the `\label`s attached to a numbered environment.
+    pub labels: Vec<String>,
+    pub elements: Vec<DocumentElement>,
+}
+
+/// An item of a list.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListItem {
+    /// The item number in an `enumerate` list, e.g. `2` or `b`, or `None` in `itemize`.
+    pub number: Option<String>,
+    /// The keys of the `\label`s attached to the item.
+    pub labels: Vec<String>,
+    pub elements: Vec<DocumentElement>,
+}
+
+impl ListItem {
+    /// Returns the marker drawn before the item.
+    pub fn marker(&self) -> String {
+        match &self.number {
+            Some(number) => format!("{}.", number),
+            None => "-".to_string(),
+        }
+    }
+}
+
+/// A generated table of contents.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TableOfContents {
+    pub title: String,
+    /// The deepest heading level listed.
+    pub depth: SectionLevel,
+}
+
+impl Default for TableOfContents {
+    fn default() -> Self {
+        TableOfContents {
+            title: "Contents".to_string(),
+            depth: SectionLevel::Subsection,
+        }
+    }
+}
+
+/// A division of a book with its own page numbering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Matter {
+    /// The title, abstract and table of contents, with unnumbered chapters and roman
+    /// page numbers.
+    Front,
+    /// The body, with numbered chapters and page numbers starting again at 1.
+    Main,
+    /// Appendices and indexes after the body, with unnumbered chapters and page
+    /// numbers continuing from the body.
+    Back,
+}
+
+/// What a `\label` is attached to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LabelTarget {
+    Heading(SectionLevel),
+    /// A numbered environment, by name.
+    Environment(String),
+    ListItem,
+}
+
+/// A label defined with `\label{key}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Label {
+    pub target: LabelTarget,
+    /// What `\ref` prints: the number of the target, if it has one.
+    pub number: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Style {
+    /// A font family, or `main` or `mono` for the document's families.
     pub font: Option<String>,
     pub font_size: Option<u32>,
     pub color: Option<String>,
     pub bold: bool,
     pub italic: bool,
+    /// How the lines of the paragraph are aligned, if not as the text around it.
+    pub align: Option<Alignment>,
     // Add more style attributes as needed
 }
 
+/// How the lines of a paragraph are placed between the margins.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Alignment {
+    /// Lines fill the width by stretching or shrinking the spaces, except the last.
+    #[default]
+    Justify,
+    Left,
+    Right,
+    Center,
+}
+
+impl Alignment {
+    /// Parses an alignment name: `justify`, `left`, `right` or `center`, also spelled
+    /// `centre`, or the LaTeX environment names `flushleft`, `flushright` and
+    /// `raggedright`.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "justify" | "justified" => Some(Alignment::Justify),
+            "left" | "flushleft" | "raggedright" => Some(Alignment::Left),
+            "right" | "flushright" | "raggedleft" => Some(Alignment::Right),
+            "center" | "centre" | "centering" => Some(Alignment::Center),
+            _ => None,
+        }
+    }
+}
+
+/// A typed value from an optional `[key=value,...]` argument.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum OptionValue {
+    /// A key given without a value, e.g. `[draft]`.
+    Flag,
+    Bool(bool),
+    Number(f64),
+    /// A length such as `5cm`, converted to millimetres.
+    Length(f64),
+    Text(String),
+}
+
+impl OptionValue {
+    /// Interprets a raw option value, trying booleans, numbers and lengths before text.
+    pub fn parse(raw: &str) -> Self {
+        let raw = raw.trim();
+        let tokens = tokenize(raw);
+        if let Some(group) = find_group(raw, &tokens, 0, TokenKind::BeginGroup) {
+            // Only a group spanning the whole value is unbraced, not `{a}x{b}`.
+            if group.next == tokens.len() {
+                return OptionValue::Text(raw[group.text].to_string());
+            }
+        }
+        match raw {
+            "true" => return OptionValue::Bool(true),
+            "false" => return OptionValue::Bool(false),
+            _ => (),
+        }
+        if let Ok(number) = raw.parse::<f64>() {
+            return OptionValue::Number(number);
+        }
+        if let Some(length) = parse_length(raw) {
+            return OptionValue::Length(length);
+        }
+        OptionValue::Text(raw.to_string())
+    }
+}
+
+/// Parses a length with a unit (`pt`, `mm`, `cm` or `in`) into millimetres.
+pub fn parse_length(raw: &str) -> Option<f64> {
+    let raw = raw.trim();
+    let split = raw.find(|c: char| c.is_ascii_alphabetic())?;
+    let (value, unit) = raw.split_at(split);
+    let value: f64 = value.trim().parse().ok()?;
+    match unit {
+        "pt" => Some(value * 25.4 / 72.0),
+        "mm" => Some(value),
+        "cm" => Some(value * 10.0),
+        "in" => Some(value * 25.4),
+        _ => None,
+    }
+}
+
+/// Key/value options parsed from the `[...]` groups of a command.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Options {
+    entries: BTreeMap<String, OptionValue>,
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Options::default()
+    }
+
+    /// Parses a comma-separated `key=value` list. Commas inside `{...}` do not split entries.
+    pub fn parse(text: &str) -> Self {
+        let mut options = Options::new();
+        for entry in split_top_level(text, ',') {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let key = split_top_level(entry, '=')[0];
+            if key.len() == entry.len() {
+                options.insert(key.trim(), OptionValue::Flag);
+            } else {
+                options.insert(key.trim(), OptionValue::parse(&entry[key.len() + 1..]));
+            }
+        }
+        options
+    }
+
+    pub fn insert(&mut self, key: &str, value: OptionValue) {
+        self.entries.insert(key.to_string(), value);
+    }
+
+    /// Adds all entries of `other`, overriding existing keys.
+    pub fn extend(&mut self, other: Options) {
+        self.entries.extend(other.entries);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&OptionValue> {
+        self.entries.get(key)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &OptionValue)> {
+        self.entries.iter()
+    }
+
+    pub fn get_text(&self, key: &str) -> Option<String> {
+        match self.get(key)? {
+            OptionValue::Text(text) => Some(text.clone()),
+            OptionValue::Number(number) => Some(number.to_string()),
+            OptionValue::Bool(value) => Some(value.to_string()),
+            OptionValue::Length(_) | OptionValue::Flag => None,
+        }
+    }
+
+    pub fn get_number(&self, key: &str) -> Option<f64> {
+        match self.get(key)? {
+            OptionValue::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    /// Returns a length in millimetres.
+    pub fn get_length(&self, key: &str) -> Option<f64> {
+        match self.get(key)? {
+            OptionValue::Length(length) => Some(*length),
+            _ => None,
+        }
+    }
+
+    /// Returns a boolean, treating a bare flag as `true`.
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get(key)? {
+            OptionValue::Bool(value) => Some(*value),
+            OptionValue::Flag => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// Splits `text` on `separator`, ignoring separators nested inside `{...}`.
+fn split_top_level(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            _ if c == separator && depth == 0 => {
+                parts.push(&text[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => (),
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// A font chosen in the document.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FontChoice {
+    /// A font family, the name of a font file or its path.
+    pub name: String,
+    /// Where to look for the font before the configured font directories.
+    pub dirs: Vec<PathBuf>,
+    /// The bold face, if not the one found next to the font.
+    pub bold: Option<String>,
+    /// The italic face, if not the one found next to the font.
+    pub italic: Option<String>,
+    /// The bold italic face, if not the one found next to the font.
+    pub bold_italic: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Document {
     pub title: Option<String>,
     pub author: Option<String>,
     pub date: Option<String>,
     pub elements: Vec<DocumentElement>,
-    pub macros: HashMap<String, String>,
+    pub macros: HashMap<String, Macro>,
+    /// The labels defined in the document, by key.
+    pub labels: BTreeMap<String, Label>,
+    /// The font chosen with `\setmainfont`, which replaces the configured font.
+    pub main_font: Option<FontChoice>,
+    /// The font chosen with `\setmonofont` for code.
+    pub mono_font: Option<FontChoice>,
+    /// The language chosen with `\language`, which replaces the configured language.
+    pub language: Option<String>,
+    /// The words given with `\hyphenation`, with the hyphens they may have, as
+    /// `ex-cep-tion`.
+    pub hyphenation: Vec<String>,
 }
 
 impl Document {
@@ -56,6 +494,11 @@ impl Document {
             date: None,
             elements: Vec::new(),
             macros: HashMap::new(),
+            labels: BTreeMap::new(),
+            main_font: None,
+            mono_font: None,
+            language: None,
+            hyphenation: Vec::new(),
         }
     }
 
@@ -75,11 +518,67 @@ impl Document {
         self.date = Some(date);
     }
 
-    pub fn define_macro(&mut self, name: String, value: String) {
-        self.macros.insert(name, value);
+    pub fn set_main_font(&mut self, font: FontChoice) {
+        self.main_font = Some(font);
+    }
+
+    pub fn set_mono_font(&mut self, font: FontChoice) {
+        self.mono_font = Some(font);
+    }
+
+    pub fn set_language(&mut self, language: String) {
+        self.language = Some(language);
+    }
+
+    /// Adds words with the hyphens they may have, replacing how the patterns of the
+    /// language hyphenate them.
+    pub fn add_hyphenation(&mut self, words: impl IntoIterator<Item = String>) {
+        self.hyphenation.extend(words);
+    }
+
+    pub fn define_macro(&mut self, name: String, definition: Macro) {
+        self.macros.insert(name, definition);
     }
 
-    pub fn get_macro(&self, name: &str) -> Option<&String> {
+    pub fn get_macro(&self, name: &str) -> Option<&Macro> {
         self.macros.get(name)
     }
+
+    /// Returns the text `\ref{key}` resolves to, or `??` for an unknown or unnumbered
+    /// target, as LaTeX prints it.
+    pub fn reference_text(&self, key: &str) -> String {
+        self.labels
+            .get(key)
+            .and_then(|label| label.number.clone())
+            .unwrap_or_else(|| "??".to_string())
+    }
+}
+
+impl Default for Document {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn option_value_unbraces_only_whole_groups() {
+        assert_eq!(
+            OptionValue::parse("{a, b}"),
+            OptionValue::Text("a, b".to_string())
+        );
+        assert_eq!(
+            OptionValue::parse("{a}x{b}"),
+            OptionValue::Text("{a}x{b}".to_string())
+        );
+        assert_eq!(
+            OptionValue::parse("{true}"),
+            OptionValue::Text("true".to_string())
+        );
+        assert_eq!(OptionValue::parse("true"), OptionValue::Bool(true));
+        assert_eq!(OptionValue::parse("1in"), OptionValue::Length(25.4));
+    }
 }
@@ -18,7 +18,19 @@ pub enum RustaTexError {
 
     #[error("Invalid syntax: {0}")]
     InvalidSyntax(String),
-  
+
+    #[error("Macro error: {0}")]
+    MacroError(String),
+
+    #[error("Include error: {0}")]
+    IncludeError(String),
+
+    #[error("Reference error: {0}")]
+    ReferenceError(String),
+
+    #[error("Font error: {0}")]
+    FontError(String),
+
     #[allow(unused)]
     #[error("Other error: {0}")]
     Other(String),
@@ -0,0 +1,343 @@
+use crate::errors::RustaTexError;
+use crate::metrics::{FontMetrics, TextMetrics};
+use crate::truetype::TrueTypeFont;
+use lazy_static::lazy_static;
+use log::{debug, error};
+use printpdf::types::plugins::graphics::two_dimensional::font as pdf_font;
+use printpdf::{BuiltinFont, FontData};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Mutex, PoisonError};
+
+/// Where fonts are looked for by name after the configured font directories.
+const SYSTEM_FONT_DIRS: [&str; 6] = [
+    "/usr/share/fonts",
+    "/usr/local/share/fonts",
+    "~/.local/share/fonts",
+    "~/.fonts",
+    "/Library/Fonts",
+    "C:\\Windows\\Fonts",
+];
+
+/// A typeface text is drawn in: one of the standard PDF fonts or a font file that is
+/// embedded in the output.
+#[derive(Debug, Clone)]
+pub enum FontFace {
+    Builtin(BuiltinFont),
+    TrueType(Rc<TrueTypeFont>),
+}
+
+impl FontFace {
+    /// Finds the font `name`: a standard font family such as Times, Helvetica (or
+    /// Arial) and Courier, the path of a TrueType file, or the name of such a file in
+    /// `dirs` or the system font directories, as in `DejaVuSans` or `dejavu sans` for
+    /// `DejaVuSans.ttf`.
+    pub fn load(name: &str, dirs: &[PathBuf]) -> Result<Self, RustaTexError> {
+        if let Some([regular, ..]) = builtin_family(name) {
+            return Ok(FontFace::Builtin(regular));
+        }
+        let path = Path::new(name);
+        if path.is_file() {
+            return FontFace::open(path);
+        }
+        let key = font_key(name);
+        match find_font(&[key.clone(), format!("{}regular", key)], dirs) {
+            Some(path) => FontFace::open(&path),
+            None => {
+                error!("Font not found: {}", name);
+                Err(RustaTexError::FontError(format!(
+                    "Font not found: {}",
+                    name
+                )))
+            }
+        }
+    }
+
+    /// Reads the font file at `path`.
+    fn open(path: &Path) -> Result<Self, RustaTexError> {
+        let font = TrueTypeFont::parse(fs::read(path)?).map_err(|e| {
+            error!("Cannot load font {}: {}", path.display(), e);
+            RustaTexError::FontError(format!("Cannot load font {}: {}", path.display(), e))
+        })?;
+        debug!("Loaded font {} from {}", font.name, path.display());
+        Ok(FontFace::TrueType(Rc::new(font)))
+    }
+
+    /// The PostScript name of the font.
+    pub fn name(&self) -> &str {
+        match self {
+            FontFace::Builtin(font) => &FontMetrics::builtin(*font).name,
+            FontFace::TrueType(font) => &font.name,
+        }
+    }
+
+    pub fn metrics(&self) -> &dyn TextMetrics {
+        match self {
+            FontFace::Builtin(font) => FontMetrics::builtin(*font),
+            FontFace::TrueType(font) => font.as_ref(),
+        }
+    }
+
+    /// Whether `self` and `other` are the same font, rather than copies of a font file.
+    pub fn same(&self, other: &FontFace) -> bool {
+        match (self, other) {
+            (FontFace::Builtin(font), FontFace::Builtin(other)) => font == other,
+            (FontFace::TrueType(font), FontFace::TrueType(other)) => Rc::ptr_eq(font, other),
+            _ => false,
+        }
+    }
+}
+
+/// Which face of a font family text is drawn in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Variant {
+    #[default]
+    Regular,
+    Bold,
+    Italic,
+    BoldItalic,
+}
+
+impl Variant {
+    pub fn new(bold: bool, italic: bool) -> Self {
+        match (bold, italic) {
+            (false, false) => Variant::Regular,
+            (true, false) => Variant::Bold,
+            (false, true) => Variant::Italic,
+            (true, true) => Variant::BoldItalic,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// The regular, bold, italic and bold italic faces of a typeface.
+#[derive(Debug, Clone)]
+pub struct FontFamily {
+    faces: [FontFace; 4],
+}
+
+impl FontFamily {
+    /// Finds the family `name` like [`FontFace::load`] finds a font, with the other
+    /// faces in files named after the regular one, as `DejaVuSans-Bold.ttf`,
+    /// `DejaVuSans-Oblique.ttf` and `DejaVuSans-BoldOblique.ttf` for `DejaVuSans.ttf`.
+    /// They are looked for next to a regular face given by path first. A face the
+    /// family lacks is drawn with the closest one it has.
+    pub fn load(name: &str, dirs: &[PathBuf]) -> Result<Self, RustaTexError> {
+        if let Some(faces) = builtin_family(name) {
+            return Ok(FontFamily {
+                faces: faces.map(FontFace::Builtin),
+            });
+        }
+        let regular = FontFace::load(name, dirs)?;
+        let path = Path::new(name);
+        let (key, dirs) = match path.file_stem() {
+            Some(stem) if path.is_file() => {
+                let key = normalize(&stem.to_string_lossy());
+                let key = key.strip_suffix("regular").unwrap_or(&key).to_string();
+                let parent = path.parent().unwrap_or(Path::new("")).to_path_buf();
+                (
+                    key,
+                    std::iter::once(parent)
+                        .chain(dirs.iter().cloned())
+                        .collect(),
+                )
+            }
+            _ => (font_key(name), dirs.to_vec()),
+        };
+        let find = |suffixes: &[&str]| -> Result<Option<FontFace>, RustaTexError> {
+            let names: Vec<String> = suffixes
+                .iter()
+                .map(|suffix| format!("{}{}", key, suffix))
+                .collect();
+            find_font(&names, &dirs)
+                .map(|path| FontFace::open(&path))
+                .transpose()
+        };
+        let bold = find(&["bold"])?;
+        let italic = find(&["italic", "oblique"])?;
+        let bold_italic = find(&["bolditalic", "boldoblique"])?;
+        if bold.is_none() || italic.is_none() || bold_italic.is_none() {
+            debug!(
+                "Font family {} lacks some faces; using the closest ones.",
+                name
+            );
+        }
+        Ok(FontFamily {
+            faces: [
+                regular.clone(),
+                bold.clone().unwrap_or_else(|| regular.clone()),
+                italic.clone().unwrap_or_else(|| regular.clone()),
+                bold_italic.or(bold).or(italic).unwrap_or(regular),
+            ],
+        })
+    }
+
+    pub fn face(&self, variant: Variant) -> &FontFace {
+        &self.faces[variant.index()]
+    }
+
+    /// Draws `variant` in `face` instead.
+    pub fn set_face(&mut self, variant: Variant, face: FontFace) {
+        self.faces[variant.index()] = face;
+    }
+}
+
+/// Returns the regular, bold, italic and bold italic faces of a standard font family.
+fn builtin_family(name: &str) -> Option<[BuiltinFont; 4]> {
+    use BuiltinFont::*;
+    match normalize(name).as_str() {
+        "times" | "timesroman" | "timesnewroman" | "serif" => {
+            Some([TimesRoman, TimesBold, TimesItalic, TimesBoldItalic])
+        }
+        "helvetica" | "arial" | "sans" | "sansserif" => Some([
+            Helvetica,
+            HelveticaBold,
+            HelveticaOblique,
+            HelveticaBoldOblique,
+        ]),
+        "courier" | "couriernew" | "mono" | "monospace" => {
+            Some([Courier, CourierBold, CourierOblique, CourierBoldOblique])
+        }
+        _ => None,
+    }
+}
+
+lazy_static! {
+    /// The font files in each directory fonts were looked for in, so that each is only
+    /// walked once however many faces are looked for.
+    static ref FONT_FILES: Mutex<HashMap<PathBuf, Vec<(String, PathBuf)>>> =
+        Mutex::default();
+}
+
+/// Looks through `dirs` and then the system font directories, including their
+/// subdirectories, for a `.ttf` file whose name is one of `names`, compared as by
+/// [`normalize`].
+fn find_font(names: &[String], dirs: &[PathBuf]) -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let system = SYSTEM_FONT_DIRS
+        .iter()
+        .filter_map(|dir| match dir.strip_prefix("~/") {
+            Some(rest) => Some(home.as_ref()?.join(rest)),
+            None => Some(PathBuf::from(dir)),
+        });
+    let mut index = FONT_FILES.lock().unwrap_or_else(PoisonError::into_inner);
+    dirs.iter().cloned().chain(system).find_map(|dir| {
+        let files = index.entry(dir).or_insert_with_key(|dir| font_files(dir));
+        files
+            .iter()
+            .find(|(name, _)| names.contains(name))
+            .map(|(_, path)| path.clone())
+    })
+}
+
+/// Returns the font files in `dir` and its subdirectories with their names normalized
+/// as by [`normalize`], in the order they are looked through: the files of a
+/// directory by name, then each of its subdirectories.
+fn font_files(dir: &Path) -> Vec<(String, PathBuf)> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut entries: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+        entries.sort();
+        for path in &entries {
+            if let Some(stem) = path.file_stem().filter(|_| is_font_file(path)) {
+                files.push((normalize(&stem.to_string_lossy()), path.clone()));
+            }
+        }
+        pending.extend(entries.into_iter().filter(|path| path.is_dir()).rev());
+    }
+    debug!("Found {} font files in {}.", files.len(), dir.display());
+    files
+}
+
+/// Returns what the files of the font `name` are named, compared as by [`normalize`],
+/// leaving out a font file extension.
+fn font_key(name: &str) -> String {
+    let path = Path::new(name);
+    match path.file_stem() {
+        Some(stem) if is_font_file(path) => normalize(&stem.to_string_lossy()),
+        _ => normalize(name),
+    }
+}
+
+/// Whether `path` is a TrueType font file. OpenType `.otf` files mostly have CFF
+/// outlines, which cannot be embedded, so they are not looked for.
+fn is_font_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("ttf"))
+}
+
+/// Lowercases `name` and leaves out everything but letters and digits, so that
+/// `Dejavu Sans`, `dejavu-sans` and `DejaVuSans` are the same font.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// The glyphs drawn in an embedded font, with the character each was drawn for.
+pub type UsedGlyphs = Rc<RefCell<BTreeMap<u16, char>>>;
+
+/// A TrueType font as printpdf embeds it. printpdf looks up the glyph of every
+/// character it draws here, which records the glyph as used, and writes the widths and
+/// character mapping of only the used glyphs.
+#[derive(Debug, Clone)]
+pub struct EmbeddedFont {
+    font: Rc<TrueTypeFont>,
+    used: UsedGlyphs,
+}
+
+impl EmbeddedFont {
+    pub fn new(font: Rc<TrueTypeFont>) -> Self {
+        EmbeddedFont {
+            font,
+            used: UsedGlyphs::default(),
+        }
+    }
+
+    /// The glyphs drawn so far, shared with every copy of this font.
+    pub fn used(&self) -> UsedGlyphs {
+        Rc::clone(&self.used)
+    }
+}
+
+impl FontData for EmbeddedFont {
+    fn font_metrics(&self) -> pdf_font::FontMetrics {
+        pdf_font::FontMetrics {
+            ascent: self.font.ascent,
+            descent: self.font.descent,
+            units_per_em: self.font.units_per_em,
+        }
+    }
+
+    fn glyph_id(&self, c: char) -> Option<u16> {
+        let glyph = self.font.glyph(c)?;
+        self.used.borrow_mut().insert(glyph, c);
+        Some(glyph)
+    }
+
+    fn glyph_ids(&self) -> HashMap<u16, char> {
+        self.used
+            .borrow()
+            .iter()
+            .map(|(&glyph, &c)| (glyph, c))
+            .collect()
+    }
+
+    fn glyph_metrics(&self, glyph_id: u16) -> Option<pdf_font::GlyphMetrics> {
+        Some(pdf_font::GlyphMetrics {
+            width: self.font.advance(glyph_id) as u32,
+            height: (self.font.ascent as i32 - self.font.descent as i32).max(0) as u32,
+        })
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+
+/// The hyphenation patterns bundled for each language, in the form of TeX's
+/// `\patterns` and `\hyphenation`.
+const BUNDLED_PATTERNS: [(&str, &str); 3] = [
+    ("en", include_str!("hyph/en.pat")),
+    ("de", include_str!("hyph/de.pat")),
+    ("fr", include_str!("hyph/fr.pat")),
+];
+
+/// Finds where words can be hyphenated with Frank Liang's patterns, as TeX does, and
+/// with exceptions that give the hyphens of whole words.
+#[derive(Debug, Clone)]
+pub struct Hyphenator {
+    /// The levels between the letters of each pattern, one more than its letters. A `.`
+    /// in a pattern matches the start or end of a word.
+    patterns: HashMap<String, Vec<u8>>,
+    /// The number of letters of the longest pattern.
+    longest: usize,
+    /// The positions of the hyphens of each exception, in characters from the start of
+    /// the lowercase word.
+    exceptions: HashMap<String, Vec<usize>>,
+    /// The fewest characters kept before a hyphen.
+    pub left_min: usize,
+    /// The fewest characters carried over after a hyphen.
+    pub right_min: usize,
+}
+
+impl Hyphenator {
+    /// Returns the hyphenator for a language with bundled patterns: English, German
+    /// or French, by code as `en`, `de-DE` or `fr_FR`, or by name as `english`,
+    /// `ngerman` or `french`.
+    pub fn for_language(language: &str) -> Option<Self> {
+        let language = language.trim().to_lowercase();
+        let code = match language.split(['-', '_']).next().unwrap_or_default() {
+            "en" | "english" | "american" | "usenglish" | "british" | "ukenglish" => "en",
+            "de" | "german" | "ngerman" | "deutsch" | "austrian" | "naustrian" => "de",
+            "fr" | "french" | "francais" | "français" => "fr",
+            _ => return None,
+        };
+        let (_, patterns) = BUNDLED_PATTERNS.iter().find(|(name, _)| *name == code)?;
+        Some(Hyphenator::parse(patterns))
+    }
+
+    /// Reads patterns and exceptions as TeX's `\patterns{...}` and
+    /// `\hyphenation{...}` give them, separated by whitespace, with `%` starting a
+    /// comment.
+    pub fn parse(text: &str) -> Self {
+        let mut hyphenator = Hyphenator {
+            patterns: HashMap::new(),
+            longest: 0,
+            exceptions: HashMap::new(),
+            left_min: 2,
+            right_min: 3,
+        };
+        let mut exceptions = false;
+        for line in text.lines() {
+            let line = line.split('%').next().unwrap_or_default();
+            for word in line.split_whitespace() {
+                match word {
+                    "\\patterns{" => exceptions = false,
+                    "\\hyphenation{" => exceptions = true,
+                    "}" => (),
+                    word if exceptions => hyphenator.add_exception(word),
+                    word => hyphenator.add_pattern(word),
+                }
+            }
+        }
+        hyphenator
+    }
+
+    /// Adds a pattern such as `hy3ph` or `.ach4`: letters with the level of a hyphen
+    /// between them where it is not 0. Odd levels allow a hyphen and even levels
+    /// forbid it, the highest level of all patterns that match deciding.
+    pub fn add_pattern(&mut self, pattern: &str) {
+        let mut letters = String::new();
+        let mut levels = vec![0];
+        for c in pattern.chars() {
+            match c.to_digit(10) {
+                Some(level) => *levels.last_mut().unwrap() = level as u8,
+                None => {
+                    letters.push(c);
+                    levels.push(0);
+                }
+            }
+        }
+        self.longest = self.longest.max(levels.len() - 1);
+        self.patterns.insert(letters, levels);
+    }
+
+    /// Adds an exception such as `ex-cep-tion`, the word with every hyphen it may have,
+    /// which replaces what the patterns find. A word without hyphens is never
+    /// hyphenated.
+    pub fn add_exception(&mut self, word: &str) {
+        let mut letters = String::new();
+        let mut hyphens = Vec::new();
+        let mut length = 0;
+        for c in word.chars() {
+            if c == '-' {
+                hyphens.push(length);
+            } else {
+                letters.push(c);
+                length += 1;
+            }
+        }
+        self.exceptions.insert(lowercase(&letters), hyphens);
+    }
+
+    /// Returns the byte offsets in `word` where it may be hyphenated, keeping at least
+    /// `left_min` characters before each hyphen and `right_min` after it.
+    pub fn hyphenate(&self, word: &str) -> Vec<usize> {
+        let chars: Vec<(usize, char)> = word.char_indices().collect();
+        let length = chars.len();
+        if length < self.left_min.max(1) + self.right_min.max(1) {
+            return Vec::new();
+        }
+        let lower = lowercase(word);
+        let hyphens = match self.exceptions.get(&lower) {
+            Some(hyphens) => hyphens.clone(),
+            None => {
+                // The levels between the characters of `.word.`, the highest of any
+                // pattern found at each position.
+                let dotted: Vec<char> = std::iter::once('.')
+                    .chain(lower.chars())
+                    .chain(std::iter::once('.'))
+                    .collect();
+                let mut levels = vec![0; dotted.len() + 1];
+                for start in 0..dotted.len() {
+                    let mut key = String::new();
+                    for &c in dotted[start..].iter().take(self.longest) {
+                        key.push(c);
+                        if let Some(pattern) = self.patterns.get(&key) {
+                            for (offset, &level) in pattern.iter().enumerate() {
+                                let level_at = &mut levels[start + offset];
+                                *level_at = (*level_at).max(level);
+                            }
+                        }
+                    }
+                }
+                // A hyphen before the character at `position` of the word is between
+                // characters `position` and `position + 1` of `.word.`.
+                (1..length)
+                    .filter(|&position| levels[position + 1] % 2 == 1)
+                    .collect()
+            }
+        };
+        hyphens
+            .into_iter()
+            .filter(|&position| position >= self.left_min && position + self.right_min <= length)
+            .filter_map(|position| chars.get(position).map(|&(offset, _)| offset))
+            .collect()
+    }
+}
+
+/// Lowercases `word` one character for one, leaving characters whose lowercase is
+/// longer as they are, so that positions in the result are positions in `word`.
+fn lowercase(word: &str) -> String {
+    word.chars()
+        .map(|c| {
+            let mut lower = c.to_lowercase();
+            match (lower.next(), lower.next()) {
+                (Some(lower), None) => lower,
+                _ => c,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hyphenates_with_the_bundled_patterns() {
+        let english = Hyphenator::for_language("en").unwrap();
+        assert_eq!(english.hyphenate("hyphenation"), [2, 6]);
+        assert_eq!(english.hyphenate("Hyphenation"), [2, 6]);
+        assert_eq!(english.hyphenate("computer"), [3]);
+        let german = Hyphenator::for_language("ngerman").unwrap();
+        assert_eq!(german.hyphenate("Silbentrennung"), [3, 6, 10]);
+        let french = Hyphenator::for_language("fr_FR").unwrap();
+        assert_eq!(french.hyphenate("hyphénation"), [2, 6, 8]);
+        assert!(Hyphenator::for_language("tlh").is_none());
+    }
+
+    #[test]
+    fn exceptions_replace_the_patterns() {
+        let mut english = Hyphenator::for_language("english").unwrap();
+        // Knuth's exceptions are bundled with the English patterns.
+        assert_eq!(english.hyphenate("associate"), [2, 4]);
+        assert!(english.hyphenate("project").is_empty());
+        english.add_exception("hyphe-nation");
+        assert_eq!(english.hyphenate("Hyphenation"), [5]);
+    }
+
+    #[test]
+    fn keeps_the_minimum_number_of_characters_around_hyphens() {
+        let mut english = Hyphenator::for_language("en").unwrap();
+        english.left_min = 3;
+        assert_eq!(english.hyphenate("hyphenation"), [6]);
+        english.right_min = 6;
+        assert!(english.hyphenate("hyphenation").is_empty());
+        assert!(english.hyphenate("a").is_empty());
+    }
+
+    #[test]
+    fn parses_patterns_and_exceptions() {
+        let mut hyphenator = Hyphenator::parse(
+            "% comment\n\\patterns{\na1b 2b. % odd levels allow a hyphen, even ones forbid it\n}\n\\hyphenation{\nab-ab\n}\n",
+        );
+        hyphenator.left_min = 1;
+        hyphenator.right_min = 1;
+        assert_eq!(hyphenator.hyphenate("cabc"), [2]);
+        assert!(hyphenator.hyphenate("cab").is_empty());
+        assert_eq!(hyphenator.hyphenate("ABAB"), [2]);
+    }
+}
@@ -0,0 +1,232 @@
+use std::ops::Range;
+
+/// The kind of a source token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// A backslash followed by a name, e.g. `\section`.
+    ControlWord,
+    /// A backslash followed by a single other character, e.g. `\%` or `\\`.
+    ControlSymbol,
+    /// `{`
+    BeginGroup,
+    /// `}`
+    EndGroup,
+    /// `[`
+    BeginOptional,
+    /// `]`
+    EndOptional,
+    /// A macro parameter `#1` to `#9`.
+    Parameter(u8),
+    /// A `%` comment up to (not including) the end of the line, or a whole
+    /// `\begin{comment}...\end{comment}` block.
+    Comment,
+    /// Any other run of characters.
+    Text,
+}
+
+/// A token and its byte range in the tokenized text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Token {
+    /// Returns the text of this token.
+    pub fn text<'t>(&self, source: &'t str) -> &'t str {
+        &source[self.start..self.end]
+    }
+
+    /// Returns the name of a control word or the character of a control symbol.
+    pub fn name<'t>(&self, source: &'t str) -> &'t str {
+        &source[self.start + 1..self.end]
+    }
+
+    /// Returns whether this is a text token made only of whitespace.
+    pub fn is_whitespace(&self, source: &str) -> bool {
+        self.kind == TokenKind::Text && self.text(source).trim().is_empty()
+    }
+}
+
+const COMMENT_BEGIN: &str = "\\begin{comment}";
+const COMMENT_END: &str = "\\end{comment}";
+
+/// Splits `source` into tokens in a single pass.
+pub fn tokenize(source: &str) -> Vec<Token> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::with_capacity(source.len() / 8);
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let start = index;
+        let kind = match bytes[index] {
+            b'\\' => {
+                if source[index..].starts_with(COMMENT_BEGIN) {
+                    index = source[index..]
+                        .find(COMMENT_END)
+                        .map_or(source.len(), |end| index + end + COMMENT_END.len());
+                    TokenKind::Comment
+                } else {
+                    let name_len = bytes[index + 1..]
+                        .iter()
+                        .take_while(|byte| byte.is_ascii_alphanumeric())
+                        .count();
+                    if name_len > 0 {
+                        index += 1 + name_len;
+                        TokenKind::ControlWord
+                    } else if let Some(next) = source[index + 1..].chars().next() {
+                        index += 1 + next.len_utf8();
+                        TokenKind::ControlSymbol
+                    } else {
+                        index += 1;
+                        TokenKind::Text
+                    }
+                }
+            }
+            b'%' => {
+                index = source[index..]
+                    .find('\n')
+                    .map_or(source.len(), |end| index + end);
+                TokenKind::Comment
+            }
+            b'{' => {
+                index += 1;
+                TokenKind::BeginGroup
+            }
+            b'}' => {
+                index += 1;
+                TokenKind::EndGroup
+            }
+            b'[' => {
+                index += 1;
+                TokenKind::BeginOptional
+            }
+            b']' => {
+                index += 1;
+                TokenKind::EndOptional
+            }
+            b'#' if matches!(bytes.get(index + 1), Some(b'1'..=b'9')) => {
+                index += 2;
+                TokenKind::Parameter(bytes[start + 1] - b'0')
+            }
+            _ => {
+                index += 1;
+                while index < bytes.len() {
+                    match bytes[index] {
+                        b'\\' | b'%' | b'{' | b'}' | b'[' | b']' => break,
+                        b'#' if matches!(bytes.get(index + 1), Some(b'1'..=b'9')) => break,
+                        // `##` stays text, so `##1` is not a parameter.
+                        b'#' if bytes.get(index + 1) == Some(&b'#') => index += 2,
+                        _ => index += 1,
+                    }
+                }
+                TokenKind::Text
+            }
+        };
+        tokens.push(Token {
+            kind,
+            start,
+            end: index,
+        });
+    }
+
+    tokens
+}
+
+/// A balanced group found by [`find_group`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    /// Indices of the tokens inside the group.
+    pub tokens: Range<usize>,
+    /// Byte range of the text inside the group.
+    pub text: Range<usize>,
+    /// Index of the token following the closing token.
+    pub next: usize,
+}
+
+/// Finds the group starting at `tokens[index]`, after any whitespace.
+///
+/// `open` must be `BeginGroup` or `BeginOptional`. Braces nest inside optional groups,
+/// so a `]` within `{...}` does not close `[...]`.
+pub fn find_group(
+    source: &str,
+    tokens: &[Token],
+    mut index: usize,
+    open: TokenKind,
+) -> Option<Group> {
+    while index < tokens.len() && tokens[index].is_whitespace(source) {
+        index += 1;
+    }
+    if tokens.get(index)?.kind != open {
+        return None;
+    }
+
+    let close = match open {
+        TokenKind::BeginOptional => TokenKind::EndOptional,
+        _ => TokenKind::EndGroup,
+    };
+    let mut depth = 0usize;
+    let mut braces = 0usize;
+    for (position, token) in tokens.iter().enumerate().skip(index) {
+        match token.kind {
+            TokenKind::BeginGroup if open != TokenKind::BeginGroup => braces += 1,
+            TokenKind::EndGroup if open != TokenKind::BeginGroup => {
+                braces = braces.saturating_sub(1)
+            }
+            _ if braces > 0 => (),
+            kind if kind == open => depth += 1,
+            kind if kind == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(Group {
+                        tokens: index + 1..position,
+                        text: tokens[index].end..token.start,
+                        next: position + 1,
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_nested_groups() {
+        let source = "{a {b {c}} d} rest";
+        let group = find_group(source, &tokenize(source), 0, TokenKind::BeginGroup).unwrap();
+        assert_eq!(&source[group.text], "a {b {c}} d");
+        let source = "{a {b}";
+        assert_eq!(
+            find_group(source, &tokenize(source), 0, TokenKind::BeginGroup),
+            None
+        );
+    }
+
+    #[test]
+    fn skips_escaped_braces() {
+        let source = r"{a \} b \{ c} rest";
+        let group = find_group(source, &tokenize(source), 0, TokenKind::BeginGroup).unwrap();
+        assert_eq!(&source[group.text], r"a \} b \{ c");
+        let source = r"{a \\} b}";
+        let group = find_group(source, &tokenize(source), 0, TokenKind::BeginGroup).unwrap();
+        assert_eq!(&source[group.text], r"a \\");
+    }
+
+    #[test]
+    fn finds_optional_groups_around_braces() {
+        let source = "  [key={a]b}, c] rest";
+        let group = find_group(source, &tokenize(source), 0, TokenKind::BeginOptional).unwrap();
+        assert_eq!(&source[group.text], "key={a]b}, c");
+        let source = "x{a}";
+        let tokens = tokenize(source);
+        assert_eq!(find_group(source, &tokens, 0, TokenKind::BeginGroup), None);
+        let group = find_group(source, &tokens, 1, TokenKind::BeginGroup).unwrap();
+        assert_eq!(group.next, tokens.len());
+    }
+}
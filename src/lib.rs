@@ -1,6 +1,16 @@
 pub mod cli;
+pub mod commands;
 pub mod document;
 pub mod errors;
+pub mod fonts;
+pub mod hyphenation;
+pub mod lexer;
+pub mod linebreak;
+pub mod macros;
+pub mod metrics;
+pub mod numbering;
 pub mod parser;
 pub mod renderer;
+pub mod source;
 pub mod styles;
+pub mod truetype;
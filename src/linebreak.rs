@@ -0,0 +1,437 @@
+/// A penalty this high forbids a break, and one this low forces it.
+pub const INFINITY: f64 = 10000.0;
+
+/// Part of a paragraph as the line breaker sees it, in the boxes, glue and penalties
+/// of Knuth and Plass. Widths are in any unit, as long as it is the same for all.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Item {
+    /// Material of a fixed width, such as a word.
+    Box { width: f64 },
+    /// Space that can stretch and shrink. A line can break at glue that follows a box,
+    /// and the glue then disappears.
+    Glue {
+        width: f64,
+        stretch: f64,
+        shrink: f64,
+    },
+    /// A place a line can break at a cost, adding `width` to the line if it does, as a
+    /// hyphen would. A penalty of [`INFINITY`] forbids the break and one of
+    /// -[`INFINITY`] forces it. Consecutive lines ending at flagged penalties cost
+    /// extra.
+    Penalty {
+        width: f64,
+        penalty: f64,
+        flagged: bool,
+    },
+}
+
+/// What makes a set of line breaks better than another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreakParams {
+    /// The highest badness a line may have, as in TeX: 100 for a line whose glue
+    /// stretches or shrinks by all it can.
+    pub tolerance: f64,
+    /// How many lines more (or, negative, fewer) than the best breaks to make if the
+    /// tolerance allows it.
+    pub looseness: i32,
+    /// The cost of each line, which favours fewer lines.
+    pub line_penalty: f64,
+    /// The cost of two consecutive lines ending at flagged penalties.
+    pub flagged_demerits: f64,
+    /// The cost of a line much tighter or looser than the one before it.
+    pub fitness_demerits: f64,
+}
+
+impl Default for BreakParams {
+    fn default() -> Self {
+        BreakParams {
+            tolerance: 200.0,
+            looseness: 0,
+            line_penalty: 10.0,
+            flagged_demerits: 3000.0,
+            fitness_demerits: 100.0,
+        }
+    }
+}
+
+/// Where a line ends, and how much its glue stretches (positive) or shrinks (negative)
+/// as a fraction of what it can.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Break {
+    /// The index of the glue or penalty the line breaks at.
+    pub position: usize,
+    pub ratio: f64,
+}
+
+/// Chooses the breaks of a paragraph into lines of `line_width` with the least total
+/// demerits, the total-fit method of Knuth and Plass. The paragraph must end with a
+/// forced break. If no breaks keep every line within the tolerance, lines may be as
+/// loose as needed and, where a box does not fit at all, overfull.
+pub fn break_lines(items: &[Item], line_width: f64, params: &BreakParams) -> Vec<Break> {
+    LineBreaker::new(items, line_width, params)
+        .run(false)
+        .or_else(|| LineBreaker::new(items, line_width, params).run(true))
+        .unwrap_or_default()
+}
+
+/// Sums of the items of a paragraph up to some position.
+#[derive(Debug, Clone, Copy, Default)]
+struct Totals {
+    width: f64,
+    stretch: f64,
+    shrink: f64,
+}
+
+/// A possible break, with the best way found to reach it.
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    position: usize,
+    /// The number of lines up to the break.
+    line: usize,
+    fitness: usize,
+    /// The sums of the items from the paragraph start to where the next line starts.
+    totals: Totals,
+    demerits: f64,
+    ratio: f64,
+    /// The node of the previous break in `nodes`.
+    previous: Option<usize>,
+}
+
+struct LineBreaker<'a> {
+    items: &'a [Item],
+    line_width: f64,
+    params: &'a BreakParams,
+    /// Every node created, which `active` and the `previous` links refer to.
+    nodes: Vec<Node>,
+    /// The nodes lines can still start at.
+    active: Vec<usize>,
+    /// The sums of the items before the current position.
+    totals: Totals,
+}
+
+impl<'a> LineBreaker<'a> {
+    fn new(items: &'a [Item], line_width: f64, params: &'a BreakParams) -> Self {
+        LineBreaker {
+            items,
+            line_width,
+            params,
+            nodes: vec![Node {
+                position: 0,
+                line: 0,
+                fitness: 1,
+                totals: Totals::default(),
+                demerits: 0.0,
+                ratio: 0.0,
+                previous: None,
+            }],
+            active: vec![0],
+            totals: Totals::default(),
+        }
+    }
+
+    /// Finds the breaks, or `None` if no breaks are within the tolerance. In an
+    /// emergency any badness is allowed, and a line that cannot fit is ended as soon
+    /// as the last way to reach the current position would be lost.
+    fn run(mut self, emergency: bool) -> Option<Vec<Break>> {
+        for (position, item) in self.items.iter().enumerate() {
+            match *item {
+                Item::Box { width } => self.totals.width += width,
+                Item::Glue {
+                    width,
+                    stretch,
+                    shrink,
+                } => {
+                    let after_box =
+                        position > 0 && matches!(self.items[position - 1], Item::Box { .. });
+                    if after_box {
+                        self.try_break(position, 0.0, emergency);
+                    }
+                    self.totals.width += width;
+                    self.totals.stretch += stretch;
+                    self.totals.shrink += shrink;
+                }
+                Item::Penalty { penalty, .. } if penalty < INFINITY => {
+                    self.try_break(position, penalty, emergency)
+                }
+                Item::Penalty { .. } => (),
+            }
+            if self.active.is_empty() {
+                return None;
+            }
+        }
+
+        let ends: Vec<&Node> = self
+            .active
+            .iter()
+            .map(|&index| &self.nodes[index])
+            .filter(|node| node.position + 1 == self.items.len())
+            .collect();
+        let best = ends
+            .iter()
+            .min_by(|a, b| a.demerits.total_cmp(&b.demerits))?;
+        // Of the breaks with the wanted number of lines, or the nearest the tolerance
+        // allows, take the best.
+        let wanted = best.line as i64 + self.params.looseness as i64;
+        let chosen = ends.iter().min_by(|a, b| {
+            let distance = |node: &Node| (node.line as i64 - wanted).abs();
+            distance(a)
+                .cmp(&distance(b))
+                .then(a.demerits.total_cmp(&b.demerits))
+        })?;
+
+        let mut breaks = Vec::new();
+        let mut node = **chosen;
+        while let Some(previous) = node.previous {
+            breaks.push(Break {
+                position: node.position,
+                ratio: node.ratio,
+            });
+            node = self.nodes[previous];
+        }
+        breaks.reverse();
+        Some(breaks)
+    }
+
+    /// Considers ending a line at `position` after each active node.
+    fn try_break(&mut self, position: usize, penalty: f64, emergency: bool) {
+        let (penalty_width, flagged) = match self.items[position] {
+            Item::Penalty { width, flagged, .. } => (width, flagged),
+            _ => (0.0, false),
+        };
+        let forced = penalty <= -INFINITY;
+        // The best way to end a line here in each fitness class, and with looseness
+        // for each number of lines.
+        let mut candidates: Vec<Node> = Vec::new();
+        let mut index = 0;
+        while index < self.active.len() {
+            let node_index = self.active[index];
+            let node = self.nodes[node_index];
+            let width = self.totals.width - node.totals.width + penalty_width;
+            let ratio = if width < self.line_width {
+                let stretch = self.totals.stretch - node.totals.stretch;
+                match stretch > 0.0 {
+                    true => (self.line_width - width) / stretch,
+                    false => INFINITY,
+                }
+            } else if width > self.line_width {
+                let shrink = self.totals.shrink - node.totals.shrink;
+                match shrink > 0.0 {
+                    true => (self.line_width - width) / shrink,
+                    false => -INFINITY,
+                }
+            } else {
+                0.0
+            };
+
+            let overfull = ratio < -1.0;
+            if overfull || forced {
+                self.active.remove(index);
+            } else {
+                index += 1;
+            }
+            // An overfull line is only taken in an emergency, when it is the last way
+            // to get here.
+            let last_resort = emergency && overfull && self.active.is_empty();
+            let badness = 100.0 * ratio.abs().powi(3);
+            let tolerable = !overfull && (emergency || badness <= self.params.tolerance);
+            if !tolerable && !last_resort {
+                continue;
+            }
+            let badness = badness.min(INFINITY);
+
+            let base = (self.params.line_penalty + badness).powi(2);
+            let mut demerits = if penalty >= 0.0 {
+                base + penalty * penalty
+            } else if !forced {
+                base - penalty * penalty
+            } else {
+                base
+            };
+            let previous_flagged = matches!(
+                self.items[node.position],
+                Item::Penalty { flagged: true, .. }
+            );
+            if flagged && previous_flagged && node.previous.is_some() {
+                demerits += self.params.flagged_demerits;
+            }
+            let fitness: usize = match ratio {
+                ratio if ratio < -0.5 => 0,
+                ratio if ratio <= 0.5 => 1,
+                ratio if ratio <= 1.0 => 2,
+                _ => 3,
+            };
+            if fitness.abs_diff(node.fitness) > 1 {
+                demerits += self.params.fitness_demerits;
+            }
+
+            let candidate = Node {
+                position,
+                line: node.line + 1,
+                fitness,
+                totals: Totals::default(),
+                demerits: node.demerits + demerits,
+                ratio: ratio.max(-1.0),
+                previous: Some(node_index),
+            };
+            let same_class = |other: &Node| {
+                other.fitness == candidate.fitness
+                    && (self.params.looseness == 0 || other.line == candidate.line)
+            };
+            match candidates.iter_mut().find(|other| same_class(other)) {
+                Some(other) if other.demerits <= candidate.demerits => (),
+                Some(other) => *other = candidate,
+                None => candidates.push(candidate),
+            }
+        }
+
+        if candidates.is_empty() {
+            return;
+        }
+        let totals = self.totals_after(position);
+        for candidate in candidates {
+            self.nodes.push(Node {
+                totals,
+                ..candidate
+            });
+            self.active.push(self.nodes.len() - 1);
+        }
+    }
+
+    /// Returns the sums up to where a line after a break at `position` starts: the
+    /// glue and penalties after the break disappear, up to the next box or forced
+    /// break.
+    fn totals_after(&self, position: usize) -> Totals {
+        let mut totals = self.totals;
+        for (index, item) in self.items.iter().enumerate().skip(position) {
+            match *item {
+                Item::Box { .. } => break,
+                Item::Glue {
+                    width,
+                    stretch,
+                    shrink,
+                } => {
+                    totals.width += width;
+                    totals.stretch += stretch;
+                    totals.shrink += shrink;
+                }
+                Item::Penalty { penalty, .. } if penalty <= -INFINITY && index > position => break,
+                Item::Penalty { .. } => (),
+            }
+        }
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a paragraph of words of the given widths, separated by spaces of width 1
+    /// that stretch by `stretch` and shrink by half, and ended by a forced break.
+    fn paragraph(words: &[f64], stretch: f64) -> Vec<Item> {
+        let mut items = Vec::new();
+        for (index, &width) in words.iter().enumerate() {
+            if index > 0 {
+                items.push(Item::Glue {
+                    width: 1.0,
+                    stretch,
+                    shrink: 0.5,
+                });
+            }
+            items.push(Item::Box { width });
+        }
+        items.extend([
+            Item::Penalty {
+                width: 0.0,
+                penalty: INFINITY,
+                flagged: false,
+            },
+            Item::Glue {
+                width: 0.0,
+                stretch: INFINITY,
+                shrink: 0.0,
+            },
+            Item::Penalty {
+                width: 0.0,
+                penalty: -INFINITY,
+                flagged: false,
+            },
+        ]);
+        items
+    }
+
+    #[test]
+    fn breaks_a_paragraph_into_justified_lines() {
+        let items = paragraph(&[4.0; 6], 1.0);
+        let breaks = break_lines(&items, 15.0, &BreakParams::default());
+        // Three words and two spaces are 14 wide, so each space stretches by a half.
+        assert_eq!(
+            breaks.iter().map(|line| line.position).collect::<Vec<_>>(),
+            [5, 13]
+        );
+        assert_eq!(breaks[0].ratio, 0.5);
+        // The last line is filled by its finishing glue.
+        assert!(breaks[1].ratio < 0.001);
+    }
+
+    #[test]
+    fn balances_lines_over_the_whole_paragraph() {
+        // Filling the first line with four words would leave only two on the second,
+        // stretched far beyond the tolerance; breaking earlier keeps both lines good.
+        let items = paragraph(&[2.0, 4.0, 2.0, 2.0, 3.0, 4.0, 5.0], 1.0);
+        let breaks = break_lines(&items, 12.0, &BreakParams::default());
+        assert_eq!(
+            breaks.iter().map(|line| line.position).collect::<Vec<_>>(),
+            [5, 11, 15]
+        );
+        assert_eq!(breaks[0].ratio, 1.0);
+        assert_eq!(breaks[1].ratio, 0.5);
+    }
+
+    #[test]
+    fn honours_forced_breaks_and_looseness() {
+        let mut items = paragraph(&[4.0; 4], 1.0);
+        items[3] = Item::Penalty {
+            width: 0.0,
+            penalty: -INFINITY,
+            flagged: false,
+        };
+        let breaks = break_lines(&items, 100.0, &BreakParams::default());
+        assert_eq!(
+            breaks.iter().map(|line| line.position).collect::<Vec<_>>(),
+            [3, 9]
+        );
+
+        let items = paragraph(&[4.0; 6], 3.0);
+        assert_eq!(break_lines(&items, 15.0, &BreakParams::default()).len(), 2);
+        let params = BreakParams {
+            looseness: 1,
+            tolerance: 1000.0,
+            ..BreakParams::default()
+        };
+        // One line more, with the loose line first and the rest as good as before.
+        let breaks = break_lines(&items, 15.0, &params);
+        assert_eq!(
+            breaks.iter().map(|line| line.position).collect::<Vec<_>>(),
+            [3, 9, 13]
+        );
+    }
+
+    #[test]
+    fn allows_loose_and_overfull_lines_in_an_emergency() {
+        // Two words stretch far beyond the tolerance, but no other breaks exist.
+        let items = paragraph(&[4.0, 4.0, 4.0], 0.1);
+        let breaks = break_lines(&items, 10.0, &BreakParams::default());
+        assert_eq!(breaks.len(), 2);
+        assert!(breaks[0].ratio > 1.0);
+
+        // A word wider than the line is set on a line of its own, overfull.
+        let items = paragraph(&[4.0, 20.0, 4.0], 1.0);
+        let breaks = break_lines(&items, 10.0, &BreakParams::default());
+        assert_eq!(
+            breaks.iter().map(|line| line.position).collect::<Vec<_>>(),
+            [1, 3, 7]
+        );
+        assert_eq!(breaks[1].ratio, -1.0);
+    }
+}
@@ -0,0 +1,945 @@
+use crate::commands::CommandRegistry;
+use crate::errors::RustaTexError;
+use crate::lexer::{find_group, tokenize, Token, TokenKind};
+use crate::parser::ParserConfig;
+use crate::source::{FileId, SourceFiles, SourceMap};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// A user-defined macro, created by `\macro{name=value}` or `\newcommand`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Macro {
+    /// Number of parameters (`#1` to `#9`) the body refers to.
+    pub params: usize,
+    /// Default value of the first parameter, which is then passed as an optional `[...]` argument.
+    pub default: Option<String>,
+    pub body: String,
+}
+
+impl Macro {
+    /// Creates a macro without parameters that expands to `body`.
+    pub fn text(body: impl Into<String>) -> Self {
+        Macro {
+            params: 0,
+            default: None,
+            body: body.into(),
+        }
+    }
+}
+
+/// The result of expanding a whole source.
+#[derive(Debug)]
+pub struct Expansion {
+    /// The source with all macro definitions removed and all invocations expanded.
+    pub text: String,
+    /// Maps offsets in `text` back to offsets in the source files.
+    pub source_map: SourceMap,
+    /// The main file and every file it included.
+    pub files: SourceFiles,
+    /// All macros defined once expansion has finished.
+    pub macros: HashMap<String, Macro>,
+}
+
+/// Expands every macro in `source`, starting from the macros predefined in `config`.
+///
+/// `\macro`, `\newcommand` and `\renewcommand` definitions are processed in order and
+/// removed from the output. `\input{file}` and `\include{file}` splice in another file,
+/// searched for next to the including file (or `path`'s directory for the main source)
+/// and then in `config.include_paths`. Of each `\ifdef{flag}...\else...\fi` block only
+/// the branch selected by `config.flags` is kept. Comments are copied without being
+/// expanded.
+///
+/// Each file is tokenized once and every token is visited once; macro bodies are
+/// tokenized when they are defined and arguments are expanded once per invocation, so
+/// the work done is linear in the size of the source plus the size of the output.
+///
+/// Macros may not take the name of a built-in command unless they replace it with
+/// `\renewcommand`.
+pub fn expand_source(
+    source: &str,
+    path: Option<&Path>,
+    config: &ParserConfig,
+) -> Result<Expansion, RustaTexError> {
+    expand_source_with_commands(source, path, config, &CommandRegistry::with_builtins())
+}
+
+/// Expands `source` like [`expand_source`] for a parser that knows `commands`, which
+/// only `\renewcommand` may replace with a macro.
+pub fn expand_source_with_commands(
+    source: &str,
+    path: Option<&Path>,
+    config: &ParserConfig,
+    commands: &CommandRegistry,
+) -> Result<Expansion, RustaTexError> {
+    let mut files = SourceFiles::new();
+    let main = files.add(path.map(Path::to_path_buf), source, None);
+    let mut expander = MacroExpander {
+        files,
+        macros: config
+            .macros
+            .iter()
+            .map(|(name, definition)| {
+                (
+                    name.clone(),
+                    Rc::new(CompiledMacro::new(definition.clone())),
+                )
+            })
+            .collect(),
+        max_depth: config.max_macro_depth,
+        include_paths: config.include_paths.clone(),
+        include_stack: path.map(|path| (canonical(path), 0)).into_iter().collect(),
+        flags: config.flags.clone(),
+        conditionals: Vec::new(),
+        commands: commands.names().map(String::from).collect(),
+    };
+    let tokens = tokenize(source);
+    let mut output = Output {
+        text: String::with_capacity(source.len()),
+        source_map: SourceMap::new(),
+    };
+    let input = Input {
+        text: source,
+        tokens: &tokens,
+        params: None,
+        file: main,
+        verbatim: true,
+        origin: 0,
+    };
+    expander.expand(input, &mut Vec::new(), &mut output)?;
+    if let Some(open) = expander.conditionals.last() {
+        error!(
+            "\\{}{{{}}} at {} is never closed by \\fi",
+            open.command,
+            open.flag,
+            expander.position(open.at)
+        );
+        return Err(RustaTexError::InvalidSyntax(format!(
+            "\\{}{{{}}} at {} is never closed by \\fi",
+            open.command,
+            open.flag,
+            expander.position(open.at)
+        )));
+    }
+    Ok(Expansion {
+        text: output.text,
+        source_map: output.source_map,
+        files: expander.files,
+        macros: expander
+            .macros
+            .into_iter()
+            .map(|(name, compiled)| {
+                let definition = Rc::try_unwrap(compiled)
+                    .map_or_else(|shared| shared.definition.clone(), |owned| owned.definition);
+                (name, definition)
+            })
+            .collect(),
+    })
+}
+
+#[derive(Default)]
+struct Output {
+    text: String,
+    source_map: SourceMap,
+}
+
+/// A macro together with the tokens of its body.
+struct CompiledMacro {
+    definition: Macro,
+    tokens: Vec<Token>,
+}
+
+impl CompiledMacro {
+    fn new(definition: Macro) -> Self {
+        let tokens = tokenize(&definition.body);
+        CompiledMacro { definition, tokens }
+    }
+}
+
+/// A token sequence being expanded.
+#[derive(Clone, Copy)]
+struct Input<'a> {
+    /// The text the tokens index into.
+    text: &'a str,
+    tokens: &'a [Token],
+    /// The expanded arguments `#1` to `#9` refer to, when expanding a macro body.
+    params: Option<&'a [String]>,
+    file: FileId,
+    /// When set, `text` is the content of `file`; otherwise it was generated by the
+    /// macro at `origin` in `file`.
+    verbatim: bool,
+    origin: usize,
+}
+
+impl Input<'_> {
+    /// Returns the file and offset a token comes from.
+    fn position(&self, token: &Token) -> (FileId, usize) {
+        if self.verbatim {
+            (self.file, self.origin + token.start)
+        } else {
+            (self.file, self.origin)
+        }
+    }
+
+    /// Returns the tokens in `range` as an input of their own.
+    fn slice(&self, range: Range<usize>) -> Input<'_> {
+        Input {
+            tokens: &self.tokens[range],
+            ..*self
+        }
+    }
+
+    /// Returns the text of the tokens in `range` with parameters substituted.
+    fn substitute(&self, range: Range<usize>) -> String {
+        let mut result = String::new();
+        for token in &self.tokens[range] {
+            push_token(&mut result, self.text, token, self.params);
+        }
+        result
+    }
+}
+
+/// Expands macro invocations recursively, with a depth limit and cycle detection.
+struct MacroExpander {
+    files: SourceFiles,
+    macros: HashMap<String, Rc<CompiledMacro>>,
+    max_depth: usize,
+    include_paths: Vec<PathBuf>,
+    /// Canonical paths and ids of the files currently being expanded, outermost first.
+    include_stack: Vec<(PathBuf, FileId)>,
+    flags: HashSet<String>,
+    /// The `\ifdef` blocks currently open, outermost first.
+    conditionals: Vec<Conditional>,
+    /// The commands of the parser, which a macro replaces only with `\renewcommand`.
+    commands: HashSet<String>,
+}
+
+/// An open `\ifdef{flag}` or `\ifndef{flag}` block.
+struct Conditional {
+    command: &'static str,
+    flag: String,
+    at: (FileId, usize),
+    /// Whether the enclosing text is kept.
+    enclosing_active: bool,
+    /// Whether the first branch is taken.
+    condition: bool,
+    in_else: bool,
+}
+
+impl Conditional {
+    /// Returns whether the current branch is kept.
+    fn active(&self) -> bool {
+        self.enclosing_active && self.condition != self.in_else
+    }
+}
+
+impl MacroExpander {
+    /// Formats a position for diagnostics.
+    fn position(&self, (file, offset): (FileId, usize)) -> String {
+        self.files.location(file, offset).to_string()
+    }
+
+    /// Expands the tokens of `input` into `output`. `chain` holds the macros currently
+    /// being expanded.
+    fn expand(
+        &mut self,
+        input: Input,
+        chain: &mut Vec<String>,
+        output: &mut Output,
+    ) -> Result<(), RustaTexError> {
+        let mut index = 0;
+        while index < input.tokens.len() {
+            let token = input.tokens[index];
+            let at = input.position(&token);
+            index += 1;
+
+            if token.kind == TokenKind::ControlWord
+                && matches!(token.name(input.text), "ifdef" | "ifndef" | "else" | "fi")
+            {
+                index = self.conditional(token.name(input.text), input, index, at)?;
+                continue;
+            }
+            // Tokens in a branch that is not taken are dropped unexpanded. Groups are
+            // dropped whole, like the arguments of a definition, so that a `\fi` or
+            // `\else` in a macro body does not end the branch.
+            if self.conditionals.last().is_some_and(|last| !last.active()) {
+                if token.kind == TokenKind::BeginGroup {
+                    if let Some(group) =
+                        find_group(input.text, input.tokens, index - 1, TokenKind::BeginGroup)
+                    {
+                        index = group.next;
+                    }
+                }
+                continue;
+            }
+
+            if token.kind != TokenKind::ControlWord {
+                if input.verbatim {
+                    output
+                        .source_map
+                        .push_verbatim(output.text.len(), at.0, at.1);
+                }
+                push_token(&mut output.text, input.text, &token, input.params);
+                continue;
+            }
+
+            let name = token.name(input.text);
+            if matches!(name, "macro" | "newcommand" | "renewcommand") {
+                index = self.define(name, input, index, at)?;
+                continue;
+            }
+            if matches!(name, "input" | "include") {
+                index = self.include(name, input, index, at, chain, output)?;
+                continue;
+            }
+
+            let compiled = match self.macros.get(name) {
+                Some(compiled) => Rc::clone(compiled),
+                None => {
+                    if input.verbatim {
+                        output
+                            .source_map
+                            .push_verbatim(output.text.len(), at.0, at.1);
+                    }
+                    output.text.push_str(token.text(input.text));
+                    continue;
+                }
+            };
+            // A cycle or too deep an expansion is reported at the invocation, before
+            // its arguments are taken.
+            if chain.iter().any(|active| active == name) {
+                chain.push(name.to_string());
+                return Err(RustaTexError::MacroError(format!(
+                    "Macro expansion cycle {} at {}",
+                    format_chain(chain),
+                    self.position(at)
+                )));
+            }
+            if chain.len() >= self.max_depth {
+                chain.push(name.to_string());
+                return Err(RustaTexError::MacroError(format!(
+                    "Macro expansion exceeded the depth limit of {} at {}: {}",
+                    self.max_depth,
+                    self.position(at),
+                    format_chain(chain)
+                )));
+            }
+
+            let definition = &compiled.definition;
+            let args = self.take_args(name, definition, input, &mut index, at)?;
+
+            // Arguments are expanded before substitution so that a macro may appear in
+            // its own arguments without being mistaken for a cycle. The body refers to
+            // the expanded text, which is never scanned again.
+            let mut expanded_args = Vec::with_capacity(args.len());
+            for arg in args {
+                let mut expanded = Output::default();
+                match arg {
+                    Argument::Tokens(range) => {
+                        let arg_input = Input {
+                            verbatim: false,
+                            origin: at.1,
+                            ..input.slice(range)
+                        };
+                        self.expand(arg_input, chain, &mut expanded)?;
+                    }
+                    Argument::Default(default) => {
+                        let tokens = tokenize(&default);
+                        let arg_input = Input {
+                            text: &default,
+                            tokens: &tokens,
+                            params: None,
+                            file: at.0,
+                            verbatim: false,
+                            origin: at.1,
+                        };
+                        self.expand(arg_input, chain, &mut expanded)?;
+                    }
+                }
+                expanded_args.push(expanded.text);
+            }
+
+            if input.verbatim {
+                output
+                    .source_map
+                    .push_generated(output.text.len(), at.0, at.1);
+            }
+            chain.push(name.to_string());
+            let body = Input {
+                text: &definition.body,
+                tokens: &compiled.tokens,
+                params: Some(&expanded_args),
+                file: at.0,
+                verbatim: false,
+                origin: at.1,
+            };
+            self.expand(body, chain, output)?;
+            chain.pop();
+        }
+        Ok(())
+    }
+
+    /// Takes the arguments of an invocation of `definition` from the tokens of `input`
+    /// starting at `index`, advancing `index` past them.
+    fn take_args(
+        &self,
+        name: &str,
+        definition: &Macro,
+        input: Input,
+        index: &mut usize,
+        at: (FileId, usize),
+    ) -> Result<Vec<Argument>, RustaTexError> {
+        let mut args = Vec::with_capacity(definition.params);
+        if let Some(default) = &definition.default {
+            match find_group(input.text, input.tokens, *index, TokenKind::BeginOptional) {
+                Some(group) => {
+                    args.push(Argument::Tokens(group.tokens));
+                    *index = group.next;
+                }
+                None => args.push(Argument::Default(default.clone())),
+            }
+        }
+        while args.len() < definition.params {
+            match find_group(input.text, input.tokens, *index, TokenKind::BeginGroup) {
+                Some(group) => {
+                    args.push(Argument::Tokens(group.tokens));
+                    *index = group.next;
+                }
+                None => {
+                    return Err(RustaTexError::MacroError(format!(
+                        "Macro '\\{}' at {} expects {} argument(s) but got {}",
+                        name,
+                        self.position(at),
+                        definition.params,
+                        args.len()
+                    )))
+                }
+            }
+        }
+        Ok(args)
+    }
+
+    /// Processes a `\macro`, `\newcommand` or `\renewcommand` definition whose arguments
+    /// start at `tokens[index]`, returning the index of the token that follows it.
+    fn define(
+        &mut self,
+        command: &str,
+        input: Input,
+        mut index: usize,
+        at: (FileId, usize),
+    ) -> Result<usize, RustaTexError> {
+        let invalid = |message: String| {
+            error!(
+                "Invalid \\{} at {}: {}",
+                command,
+                self.position(at),
+                message
+            );
+            RustaTexError::InvalidSyntax(format!(
+                "Invalid \\{} at {}: {}",
+                command,
+                self.position(at),
+                message
+            ))
+        };
+        let group = |index: usize, open: TokenKind| {
+            find_group(input.text, input.tokens, index, open)
+                .map(|group| (input.substitute(group.tokens), group.next))
+        };
+
+        if command == "macro" {
+            let (argument, next) = group(index, TokenKind::BeginGroup)
+                .ok_or_else(|| invalid("expected {name=value}".to_string()))?;
+            // Everything after the first '=' is the value
+            match argument.split_once('=') {
+                Some((name, value)) if !name.trim().is_empty() => {
+                    let name = name.trim().to_string();
+                    let value = value.trim().to_string();
+                    self.check_name(&name, command, at)?;
+                    debug!("Defined macro: {} = {}", name, value);
+                    self.macros
+                        .insert(name, Rc::new(CompiledMacro::new(Macro::text(value))));
+                    return Ok(next);
+                }
+                _ => return Err(invalid(argument)),
+            }
+        }
+
+        let mut args = Vec::new();
+        let mut optional_args = Vec::new();
+        if let Some((name, next)) = group(index, TokenKind::BeginGroup) {
+            args.push(name);
+            index = next;
+        }
+        while optional_args.len() < 2 {
+            match group(index, TokenKind::BeginOptional) {
+                Some((optional, next)) => {
+                    optional_args.push(optional);
+                    index = next;
+                }
+                None => break,
+            }
+        }
+        if let Some((body, next)) = group(index, TokenKind::BeginGroup) {
+            args.push(body);
+            index = next;
+        }
+
+        let (name, definition) = parse_newcommand(&args, &optional_args).map_err(invalid)?;
+        self.check_name(&name, command, at)?;
+        let defined = self.macros.contains_key(&name) || self.commands.contains(&name);
+        if defined != (command == "renewcommand") {
+            let problem = if defined {
+                "is already defined; use \\renewcommand"
+            } else {
+                "is not defined; use \\newcommand"
+            };
+            error!("Macro '\\{}' at {} {}", name, self.position(at), problem);
+            return Err(RustaTexError::MacroError(format!(
+                "Macro '\\{}' at {} {}",
+                name,
+                self.position(at),
+                problem
+            )));
+        }
+        debug!(
+            "Defined macro: {} with {} parameter(s)",
+            name, definition.params
+        );
+        self.macros
+            .insert(name, Rc::new(CompiledMacro::new(definition)));
+        Ok(index)
+    }
+
+    /// Fails if a `\macro` or `\newcommand` definition of `name` would hide a command,
+    /// or if `name` is one of the commands handled during expansion, which no macro
+    /// can replace.
+    fn check_name(
+        &self,
+        name: &str,
+        command: &str,
+        at: (FileId, usize),
+    ) -> Result<(), RustaTexError> {
+        let problem = if EXPANSION_COMMANDS.contains(&name) {
+            "cannot be redefined"
+        } else if command != "renewcommand" && self.commands.contains(name) {
+            "is a built-in command; use \\renewcommand to replace it"
+        } else {
+            return Ok(());
+        };
+        error!("Macro '\\{}' at {} {}", name, self.position(at), problem);
+        Err(RustaTexError::MacroError(format!(
+            "Macro '\\{}' at {} {}",
+            name,
+            self.position(at),
+            problem
+        )))
+    }
+
+    /// Processes `\ifdef{flag}`, `\ifndef{flag}`, `\else` or `\fi`, whose arguments start
+    /// at `tokens[index]`, returning the index of the token that follows it.
+    fn conditional(
+        &mut self,
+        command: &str,
+        input: Input,
+        index: usize,
+        at: (FileId, usize),
+    ) -> Result<usize, RustaTexError> {
+        let position = self.position(at);
+        let unmatched = |message: &str| {
+            error!("{} at {}", message, position);
+            RustaTexError::InvalidSyntax(format!("{} at {}", message, position))
+        };
+
+        match command {
+            "else" => match self.conditionals.last_mut() {
+                Some(open) if !open.in_else => open.in_else = true,
+                Some(_) => return Err(unmatched("Second \\else in the same \\ifdef")),
+                None => return Err(unmatched("\\else without a matching \\ifdef")),
+            },
+            "fi" => {
+                if self.conditionals.pop().is_none() {
+                    return Err(unmatched("\\fi without a matching \\ifdef"));
+                }
+            }
+            _ => {
+                let group = find_group(input.text, input.tokens, index, TokenKind::BeginGroup)
+                    .ok_or_else(|| {
+                        unmatched(&format!("Invalid \\{}: expected {{flag}}", command))
+                    })?;
+                let flag = input.substitute(group.tokens).trim().to_string();
+                let enclosing_active = self.conditionals.last().is_none_or(Conditional::active);
+                let condition = self.flags.contains(&flag) == (command == "ifdef");
+                debug!("\\{}{{{}}} is {}", command, flag, condition);
+                self.conditionals.push(Conditional {
+                    command: if command == "ifdef" {
+                        "ifdef"
+                    } else {
+                        "ifndef"
+                    },
+                    flag,
+                    at,
+                    enclosing_active,
+                    condition,
+                    in_else: false,
+                });
+                return Ok(group.next);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Processes an `\input` or `\include` whose argument starts at `tokens[index]`,
+    /// expanding the named file into `output` and returning the index of the token
+    /// that follows the argument.
+    fn include(
+        &mut self,
+        command: &str,
+        input: Input,
+        index: usize,
+        at: (FileId, usize),
+        chain: &mut Vec<String>,
+        output: &mut Output,
+    ) -> Result<usize, RustaTexError> {
+        let group = match find_group(input.text, input.tokens, index, TokenKind::BeginGroup) {
+            Some(group) => group,
+            None => {
+                error!(
+                    "Invalid \\{} at {}: expected {{file}}",
+                    command,
+                    self.position(at)
+                );
+                return Err(RustaTexError::InvalidSyntax(format!(
+                    "Invalid \\{} at {}: expected {{file}}",
+                    command,
+                    self.position(at)
+                )));
+            }
+        };
+        // The file name may itself use macros.
+        let mut name = Output::default();
+        let name_input = Input {
+            verbatim: false,
+            origin: at.1,
+            ..input.slice(group.tokens)
+        };
+        self.expand(name_input, chain, &mut name)?;
+        let name = name.text.trim();
+
+        let path = self.resolve(name, at.0).ok_or_else(|| {
+            error!(
+                "Cannot find file '{}' included at {}",
+                name,
+                self.position(at)
+            );
+            RustaTexError::IncludeError(format!(
+                "Cannot find file '{}' included at {}",
+                name,
+                self.position(at)
+            ))
+        })?;
+
+        let canonical_path = canonical(&path);
+        if let Some(start) = self
+            .include_stack
+            .iter()
+            .position(|(active, _)| *active == canonical_path)
+        {
+            let cycle = self.include_stack[start..]
+                .iter()
+                .filter_map(|&(_, file)| self.files.get(file).path.as_deref())
+                .chain(std::iter::once(path.as_path()))
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            error!("Include cycle {} at {}", cycle, self.position(at));
+            return Err(RustaTexError::IncludeError(format!(
+                "Include cycle {} at {}",
+                cycle,
+                self.position(at)
+            )));
+        }
+
+        let text: Rc<str> = std::fs::read_to_string(&path)
+            .map_err(|err| {
+                error!(
+                    "Cannot read '{}' included at {}: {}",
+                    path.display(),
+                    self.position(at),
+                    err
+                );
+                RustaTexError::IncludeError(format!(
+                    "Cannot read '{}' included at {}: {}",
+                    path.display(),
+                    self.position(at),
+                    err
+                ))
+            })?
+            .into();
+        debug!("Including {} at {}", path.display(), self.position(at));
+
+        let file = self.files.add(Some(path), Rc::clone(&text), Some(at));
+        let tokens = tokenize(&text);
+        let file_input = Input {
+            text: &text,
+            tokens: &tokens,
+            params: None,
+            file,
+            verbatim: true,
+            origin: 0,
+        };
+        self.include_stack.push((canonical_path, file));
+        self.expand(file_input, chain, output)?;
+        self.include_stack.pop();
+        Ok(group.next)
+    }
+
+    /// Finds the file `name` included from `file`: first next to that file, then in each
+    /// include path. A name without an extension also matches a `.rt` file.
+    fn resolve(&self, name: &str, file: FileId) -> Option<PathBuf> {
+        let name = Path::new(name);
+        // Input given as a string resolves relative to the working directory.
+        let base = self
+            .files
+            .get(file)
+            .path
+            .as_deref()
+            .and_then(Path::parent)
+            .unwrap_or(Path::new(""));
+        let directories =
+            std::iter::once(base).chain(self.include_paths.iter().map(PathBuf::as_path));
+
+        let mut candidates = vec![name.to_path_buf()];
+        if name.extension().is_none() {
+            candidates.push(name.with_extension("rt"));
+        }
+        for directory in directories {
+            for candidate in &candidates {
+                let path = directory.join(candidate);
+                if path.is_file() {
+                    return Some(path);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The commands the expander handles itself.
+const EXPANSION_COMMANDS: [&str; 9] = [
+    "macro",
+    "newcommand",
+    "renewcommand",
+    "input",
+    "include",
+    "ifdef",
+    "ifndef",
+    "else",
+    "fi",
+];
+
+/// An argument of a macro invocation.
+enum Argument {
+    /// The tokens of an argument given at the invocation.
+    Tokens(Range<usize>),
+    /// The default value of an omitted optional argument.
+    Default(String),
+}
+
+/// Appends the text of `token` to `output`. Inside a macro body, parameters are replaced
+/// by the corresponding argument and `##` produces a literal `#`.
+fn push_token(output: &mut String, text: &str, token: &Token, params: Option<&[String]>) {
+    match (token.kind, params) {
+        (TokenKind::Parameter(number), Some(params)) => {
+            if let Some(arg) = params.get(number as usize - 1) {
+                output.push_str(arg);
+            }
+        }
+        (TokenKind::Text, Some(_)) if token.text(text).contains("##") => {
+            output.push_str(&token.text(text).replace("##", "#"))
+        }
+        _ => output.push_str(token.text(text)),
+    }
+}
+
+/// Returns the canonical form of `path` for detecting include cycles, or the path itself
+/// if it cannot be resolved.
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Formats a macro chain as `\a -> \b -> \c`.
+fn format_chain(chain: &[String]) -> String {
+    chain
+        .iter()
+        .map(|name| format!("\\{}", name))
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+/// Parses a `\newcommand{\name}[n][default]{body}` definition from a command's raw
+/// arguments, returning the macro name and its definition.
+pub fn parse_newcommand(
+    args: &[String],
+    optional_args: &[String],
+) -> Result<(String, Macro), String> {
+    let (name, body) = match args {
+        [name, body] => (name.trim(), body),
+        _ => return Err(format!("expected 2 arguments, got {}", args.len())),
+    };
+    let name = name.strip_prefix('\\').unwrap_or(name);
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("invalid macro name '{}'", name));
+    }
+
+    let params = match optional_args.first() {
+        Some(count) => match count.trim().parse::<usize>() {
+            Ok(count) if count <= 9 => count,
+            _ => return Err(format!("invalid number of parameters '{}'", count)),
+        },
+        None => 0,
+    };
+    let default = optional_args.get(1).cloned();
+    if default.is_some() && params == 0 {
+        return Err("a default value requires at least one parameter".to_string());
+    }
+
+    Ok((
+        name.to_string(),
+        Macro {
+            params,
+            default,
+            body: body.clone(),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substitutes_numbered_arguments() {
+        let config = ParserConfig::default();
+        let source = "\\newcommand{\\pair}[2]{(#1, #2)}\\pair{a}{b}";
+        assert_eq!(expand_source(source, None, &config).unwrap().text, "(a, b)");
+        let source = "\\newcommand{\\twice}[1]{#1#1}\\twice{{x}y}";
+        assert_eq!(
+            expand_source(source, None, &config).unwrap().text,
+            "{x}y{x}y"
+        );
+    }
+
+    #[test]
+    fn uses_the_default_of_a_missing_optional_argument() {
+        let source = "\\newcommand{\\note}[2][Note]{#1: #2}\\note{x} \\note[Tip]{y}";
+        let expansion = expand_source(source, None, &ParserConfig::default()).unwrap();
+        assert_eq!(expansion.text, "Note: x Tip: y");
+    }
+
+    #[test]
+    fn replaces_commands_only_with_renewcommand() {
+        let config = ParserConfig::default();
+        match expand_source("\\newcommand{\\section}{S}", None, &config).unwrap_err() {
+            RustaTexError::MacroError(message) => {
+                assert!(message.contains("use \\renewcommand"), "{}", message)
+            }
+            error => panic!("unexpected error: {:?}", error),
+        }
+        let source = "\\renewcommand{\\section}[1]{#1!}\\section{S}";
+        assert_eq!(expand_source(source, None, &config).unwrap().text, "S!");
+        assert!(matches!(
+            expand_source("\\renewcommand{\\fi}{}", None, &config),
+            Err(RustaTexError::MacroError(message)) if message.contains("cannot be redefined")
+        ));
+    }
+
+    #[test]
+    fn reports_expansion_cycles() {
+        let config = ParserConfig::default();
+        let source = "\\newcommand{\\a}{\\b}\\newcommand{\\b}{\\a}\\a";
+        match expand_source(source, None, &config).unwrap_err() {
+            RustaTexError::MacroError(message) => {
+                assert!(message.contains("\\a -> \\b -> \\a"), "{}", message)
+            }
+            error => panic!("unexpected error: {:?}", error),
+        }
+        // The cycle is found before the arguments the inner invocation lacks.
+        let source = "\\newcommand{\\loop}[1]{\\loop}\\loop{x}";
+        match expand_source(source, None, &config).unwrap_err() {
+            RustaTexError::MacroError(message) => {
+                assert!(message.starts_with("Macro expansion cycle"), "{}", message)
+            }
+            error => panic!("unexpected error: {:?}", error),
+        }
+    }
+
+    #[test]
+    fn reports_expansions_deeper_than_the_limit() {
+        let config = ParserConfig {
+            max_macro_depth: 3,
+            ..ParserConfig::default()
+        };
+        let source = "\\newcommand{\\d}{x}\\newcommand{\\c}{\\d}\\newcommand{\\b}{\\c}\\newcommand{\\a}{\\b}";
+        let shallow = format!("{}\\b", source);
+        assert_eq!(expand_source(&shallow, None, &config).unwrap().text, "x");
+        let deep = format!("{}\\a", source);
+        match expand_source(&deep, None, &config).unwrap_err() {
+            RustaTexError::MacroError(message) => {
+                assert!(message.contains("depth limit of 3"), "{}", message)
+            }
+            error => panic!("unexpected error: {:?}", error),
+        }
+    }
+
+    #[test]
+    fn includes_files_and_reports_include_cycles() {
+        let main = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/include/main.rt");
+        let config = ParserConfig::default();
+        let source = "\\input{leaf.rt} \\include{leaf}";
+        let expansion = expand_source(source, Some(&main), &config).unwrap();
+        assert_eq!(expansion.text, "leaf\n leaf\n");
+        match expand_source("\\input{a.rt}", Some(&main), &config).unwrap_err() {
+            RustaTexError::IncludeError(message) => {
+                assert!(message.starts_with("Include cycle"), "{}", message);
+                assert!(message.contains("a.rt -> "), "{}", message);
+            }
+            error => panic!("unexpected error: {:?}", error),
+        }
+    }
+
+    #[test]
+    fn keeps_the_branch_selected_by_the_flags() {
+        let draft = ParserConfig {
+            flags: HashSet::from(["draft".to_string()]),
+            ..ParserConfig::default()
+        };
+        let none = ParserConfig::default();
+        let source = "\\ifdef{draft}D\\else\\emph{F}\\fi";
+        assert_eq!(expand_source(source, None, &draft).unwrap().text, "D");
+        assert_eq!(
+            expand_source(source, None, &none).unwrap().text,
+            "\\emph{F}"
+        );
+        let source = "\\ifndef{draft}N\\fi.";
+        assert_eq!(expand_source(source, None, &draft).unwrap().text, ".");
+        assert_eq!(expand_source(source, None, &none).unwrap().text, "N.");
+        // Conditionals nest, and macros are only defined in the kept branch.
+        let source = "\\ifdef{draft}\\ifdef{final}\\newcommand{\\v}{final}\\else\\newcommand{\\v}{draft}\\fi\\fi\\v";
+        assert_eq!(expand_source(source, None, &draft).unwrap().text, "draft");
+        // Conditionals in the arguments of a dropped definition are not seen.
+        let source = "\\ifdef{final}\\newcommand{\\close}{\\fi}\\newcommand{\\flip}{\\else}\\fi.";
+        assert_eq!(expand_source(source, None, &draft).unwrap().text, ".");
+    }
+
+    #[test]
+    fn reports_unclosed_conditionals() {
+        assert!(matches!(
+            expand_source("\\ifdef{draft}D", None, &ParserConfig::default()),
+            Err(RustaTexError::InvalidSyntax(_))
+        ));
+    }
+}
@@ -1,20 +1,17 @@
-mod cli;
-mod document;
-mod errors;
-mod parser;
-mod renderer;
-mod styles;
 use clap::Parser;
-use cli::Cli;
-use env_logger;
+use rustatex::cli::Cli;
 
 use log::{debug, error, info};
-use parser::parse_input;
 use printpdf::Mm;
 use regex::Regex;
-use renderer::{render_pdf, RenderConfig};
+use rustatex::document::{parse_length, Alignment};
+use rustatex::macros::Macro;
+use rustatex::numbering::NumberFormat;
+use rustatex::parser::{parse_source, ParserConfig};
+use rustatex::renderer::{render_pdf, PageSelection, PageTemplate, RenderConfig, Watermark};
 
-use std::{path::Path, process};
+use std::path::{Path, PathBuf};
+use std::process;
 
 fn main() {
     // Initialize the logger
@@ -37,8 +34,38 @@ fn main() {
         }
     };
 
+    // Handle macros from CLI; they must be known before parsing starts
+    let mut parser_config = ParserConfig {
+        include_paths: cli.include_path.iter().map(PathBuf::from).collect(),
+        ..ParserConfig::default()
+    };
+
+    // Flags for conditionals: user flags plus those derived from the build options
+    parser_config.flags.extend(cli.flag.iter().cloned());
+    if cli.draft {
+        parser_config.flags.insert("draft".to_string());
+    }
+    let output_format = if cli.html.is_some() { "html" } else { "pdf" };
+    parser_config.flags.insert(output_format.to_string());
+    debug!("Conditional flags: {:?}", parser_config.flags);
+    for macro_def in &cli.macro_def {
+        match macro_def.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => {
+                let name = name.trim().to_string();
+                let value = value.trim().to_string();
+                debug!("Defined macro from CLI: {} = {}", name, value);
+                parser_config.macros.insert(name, Macro::text(value));
+            }
+            _ => {
+                error!("Invalid macro definition: {}", macro_def);
+                eprintln!("Invalid macro definition: {}", macro_def);
+                process::exit(1);
+            }
+        }
+    }
+
     // Parse input
-    let mut document = match parse_input(&input_content) {
+    let document = match parse_source(&input_content, Some(Path::new(&cli.input)), &parser_config) {
         Ok(doc) => doc,
         Err(err) => {
             error!("Error parsing input file: {}", err);
@@ -47,48 +74,113 @@ fn main() {
         }
     };
 
-    // Handle macros from CLI
-    for macro_def in &cli.macro_def {
-        let parts: Vec<&str> = macro_def.split('=').collect();
-        if parts.len() == 2 {
-            let name = parts[0].trim().to_string();
-            let value = parts[1].trim().to_string();
-            document.define_macro(name.clone(), value.clone());
-            debug!("Defined macro from CLI: {} = {}", name, value);
-        } else {
-            error!("Invalid macro definition: {}", macro_def);
-            eprintln!("Invalid macro definition: {}", macro_def);
+    let page_numbering = match NumberFormat::parse(&cli.page_numbering) {
+        Some(format) => format,
+        None => {
+            error!("Invalid page numbering style: {}", cli.page_numbering);
+            eprintln!(
+                "Invalid page numbering style: {} (expected arabic, roman, Roman, alph or Alph)",
+                cli.page_numbering
+            );
             process::exit(1);
         }
-    }
+    };
+
+    let alignment = match Alignment::parse(&cli.align) {
+        Some(alignment) => alignment,
+        None => {
+            error!("Invalid alignment: {}", cli.align);
+            eprintln!(
+                "Invalid alignment: {} (expected justify, left, right or center)",
+                cli.align
+            );
+            process::exit(1);
+        }
+    };
+
+    let watermark = cli.watermark.as_ref().map(|text| {
+        let color = match Watermark::parse_color(&cli.watermark_color) {
+            Some(color) => color,
+            None => {
+                error!("Invalid watermark colour: {}", cli.watermark_color);
+                eprintln!(
+                    "Invalid watermark colour: {} (expected #rrggbb, #rgb, black, grey, red, green or blue)",
+                    cli.watermark_color
+                );
+                process::exit(1);
+            }
+        };
+        let pages = cli.watermark_pages.as_ref().map(|spec| match PageSelection::parse(spec) {
+            Some(pages) => pages,
+            None => {
+                error!("Invalid watermark pages: {}", spec);
+                eprintln!(
+                    "Invalid watermark pages: {} (expected page numbers and ranges such as 1,3-5,10-)",
+                    spec
+                );
+                process::exit(1);
+            }
+        });
+        Watermark {
+            font_size: cli.watermark_size,
+            angle: cli.watermark_angle,
+            opacity: cli.watermark_opacity.clamp(0.0, 1.0),
+            color,
+            pages,
+            ..Watermark::new(text.as_str())
+        }
+    });
+
+    let page_width = match cli.paper_size.to_uppercase().as_str() {
+        "A4" => Mm(210.0),
+        "LETTER" => Mm(215.9),
+        _ => Mm(210.0), // Default to A4
+    };
+    let margin = parse_margin(&cli.margins).unwrap_or(25.4); // 1in = 25.4mm
 
     // Set up rendering configuration based on CLI
     let render_config = RenderConfig {
-        page_width: match cli.paper_size.to_uppercase().as_str() {
-            "A4" => Mm(210.0),
-            "LETTER" => Mm(215.9),
-            _ => Mm(210.0), // Default to A4
-        },
+        page_width,
         page_height: match cli.paper_size.to_uppercase().as_str() {
             "A4" => Mm(297.0),
             "LETTER" => Mm(279.4),
             _ => Mm(297.0), // Default to A4
         },
-        margin_left: parse_margin(&cli.margins).unwrap_or(25.4), // 1in = 25.4mm
-        margin_right: parse_margin(&cli.margins).unwrap_or(25.4),
-        margin_top: parse_margin(&cli.margins).unwrap_or(25.4),
-        margin_bottom: parse_margin(&cli.margins).unwrap_or(25.4),
+        margin_left: margin,
+        margin_right: margin,
+        margin_top: margin,
+        margin_bottom: margin,
         start_y: 280.0,      // Starting Y position for rendering text
         bottom_margin: 20.0, // Minimum Y position before adding a new page
         font_size: cli.font_size as f64,
         title_font_size: 20.0,
         section_font_size: 16.0,
         subsection_font_size: 14.0,
-        line_width: 190.0,
+        // Lines fill the page between the side margins.
+        line_width: page_width.0 - 2.0 * margin,
         line_spacing: 4.0,
         paragraph_spacing: 10.0,
         section_spacing: 15.0,
         subsection_spacing: 10.0,
+        table_of_contents: cli.toc,
+        bookmarks: cli.bookmarks,
+        header: cli.header.as_deref().map(PageTemplate::parse),
+        footer: cli.footer.as_deref().map(PageTemplate::parse),
+        header_font_size: 10.0,
+        header_offset: parse_offset(cli.header_offset.as_deref(), "header"),
+        footer_offset: parse_offset(cli.footer_offset.as_deref(), "footer"),
+        page_numbering,
+        watermark,
+        font: cli.font.clone(),
+        mono_font: cli.mono_font.clone(),
+        font_dirs: cli.font_dir.iter().map(PathBuf::from).collect(),
+        alignment,
+        tolerance: cli.tolerance,
+        looseness: cli.looseness,
+        hyphenation: cli.hyphenation,
+        language: cli.language.clone(),
+        left_hyphen_min: cli.left_hyphen_min,
+        right_hyphen_min: cli.right_hyphen_min,
     };
 
     // Determine output path
@@ -129,6 +221,23 @@ fn main() {
     }
 }
 
+/// Parses the `--header-offset` or `--footer-offset` length into mm, exiting if it is
+/// invalid.
+fn parse_offset(offset: Option<&str>, name: &str) -> Option<f64> {
+    let offset = offset?;
+    match parse_length(offset) {
+        Some(length) => Some(length),
+        None => {
+            error!("Invalid {} offset: {}", name, offset);
+            eprintln!(
+                "Invalid {} offset: {} (expected a length such as 10mm or 0.5in)",
+                name, offset
+            );
+            process::exit(1);
+        }
+    }
+}
+
 /// Parses margin string (e.g., "1in") and returns value in mm.
 /// Supports "in" for inches and "cm" for centimeters.
 fn parse_margin(margin_str: &str) -> Option<f64> {
@@ -0,0 +1,336 @@
+use crate::errors::RustaTexError;
+use lazy_static::lazy_static;
+use printpdf::BuiltinFont;
+use std::collections::HashMap;
+
+/// The glyph names of WinAnsiEncoding, which printpdf draws the builtin fonts in, from
+/// code 32 on. Codes without a glyph are `.`.
+const WIN_ANSI_NAMES: &str = "\
+    space exclam quotedbl numbersign dollar percent ampersand quotesingle parenleft \
+    parenright asterisk plus comma hyphen period slash zero one two three four five \
+    six seven eight nine colon semicolon less equal greater question at A B C D E F \
+    G H I J K L M N O P Q R S T U V W X Y Z bracketleft backslash bracketright \
+    asciicircum underscore grave a b c d e f g h i j k l m n o p q r s t u v w x y \
+    z braceleft bar braceright asciitilde . Euro . quotesinglbase florin \
+    quotedblbase ellipsis dagger daggerdbl circumflex perthousand Scaron \
+    guilsinglleft OE . Zcaron . . quoteleft quoteright quotedblleft quotedblright \
+    bullet endash emdash tilde trademark scaron guilsinglright oe . zcaron \
+    Ydieresis space exclamdown cent sterling currency yen brokenbar section \
+    dieresis copyright ordfeminine guillemotleft logicalnot hyphen registered \
+    macron degree plusminus twosuperior threesuperior acute mu paragraph \
+    periodcentered cedilla onesuperior ordmasculine guillemotright onequarter \
+    onehalf threequarters questiondown Agrave Aacute Acircumflex Atilde Adieresis \
+    Aring AE Ccedilla Egrave Eacute Ecircumflex Edieresis Igrave Iacute Icircumflex \
+    Idieresis Eth Ntilde Ograve Oacute Ocircumflex Otilde Odieresis multiply Oslash \
+    Ugrave Uacute Ucircumflex Udieresis Yacute Thorn germandbls agrave aacute \
+    acircumflex atilde adieresis aring ae ccedilla egrave eacute ecircumflex \
+    edieresis igrave iacute icircumflex idieresis eth ntilde ograve oacute \
+    ocircumflex otilde odieresis divide oslash ugrave uacute ucircumflex udieresis \
+    yacute thorn ydieresis";
+
+/// The Adobe Font Metrics of the 14 standard fonts, in the order of [`BuiltinFont`].
+const BUILTIN_AFM: [&str; 14] = [
+    include_str!("afm/Times-Roman.afm"),
+    include_str!("afm/Times-Bold.afm"),
+    include_str!("afm/Times-Italic.afm"),
+    include_str!("afm/Times-BoldItalic.afm"),
+    include_str!("afm/Helvetica.afm"),
+    include_str!("afm/Helvetica-Bold.afm"),
+    include_str!("afm/Helvetica-Oblique.afm"),
+    include_str!("afm/Helvetica-BoldOblique.afm"),
+    include_str!("afm/Courier.afm"),
+    include_str!("afm/Courier-Oblique.afm"),
+    include_str!("afm/Courier-Bold.afm"),
+    include_str!("afm/Courier-BoldOblique.afm"),
+    include_str!("afm/Symbol.afm"),
+    include_str!("afm/ZapfDingbats.afm"),
+];
+
+lazy_static! {
+    /// The WinAnsiEncoding codes of each glyph name.
+    static ref WIN_ANSI_CODES: HashMap<&'static str, Vec<u8>> = {
+        let mut codes: HashMap<&'static str, Vec<u8>> = HashMap::new();
+        for (code, glyph) in (32..=255).zip(WIN_ANSI_NAMES.split_whitespace()) {
+            codes.entry(glyph).or_default().push(code);
+        }
+        codes
+    };
+    static ref BUILTIN: Vec<FontMetrics> = BUILTIN_AFM
+        .iter()
+        .map(|afm| FontMetrics::parse(afm).expect("bundled AFM files are valid"))
+        .collect();
+}
+
+/// How wide text is in a font, in thousandths of the font size.
+pub trait TextMetrics {
+    /// Whether `c` can be drawn in this font. Other characters are left out.
+    fn encodes(&self, c: char) -> bool;
+
+    /// Returns the advance width of `c`, which is 0 if it cannot be drawn.
+    fn char_width(&self, c: char) -> f64;
+
+    /// Returns the kerning between `left` and `right` when they are drawn next to each
+    /// other.
+    fn kerning(&self, left: char, right: char) -> f64;
+
+    /// Returns the width of `text` at `font_size` in points, including kerning.
+    fn text_width(&self, text: &str, font_size: f64) -> f64 {
+        let mut units = 0.0;
+        let mut previous = None;
+        for c in text.chars().filter(|&c| self.encodes(c)) {
+            units += self.char_width(c);
+            if let Some(previous) = previous {
+                units += self.kerning(previous, c);
+            }
+            previous = Some(c);
+        }
+        units * font_size / 1000.0
+    }
+}
+
+/// Glyph widths and kerning pairs of a font drawn in WinAnsiEncoding, in thousandths
+/// of the font size.
+#[derive(Debug, Clone)]
+pub struct FontMetrics {
+    pub name: String,
+    /// The advance width of each code.
+    widths: Vec<u16>,
+    /// The change to the advance of the first code of each kerned pair.
+    kerning: HashMap<(u8, u8), i16>,
+}
+
+impl FontMetrics {
+    /// Returns the metrics of one of the 14 standard fonts.
+    pub fn builtin(font: BuiltinFont) -> &'static FontMetrics {
+        let index = match font {
+            BuiltinFont::TimesRoman => 0,
+            BuiltinFont::TimesBold => 1,
+            BuiltinFont::TimesItalic => 2,
+            BuiltinFont::TimesBoldItalic => 3,
+            BuiltinFont::Helvetica => 4,
+            BuiltinFont::HelveticaBold => 5,
+            BuiltinFont::HelveticaOblique => 6,
+            BuiltinFont::HelveticaBoldOblique => 7,
+            BuiltinFont::Courier => 8,
+            BuiltinFont::CourierOblique => 9,
+            BuiltinFont::CourierBold => 10,
+            BuiltinFont::CourierBoldOblique => 11,
+            BuiltinFont::Symbol => 12,
+            BuiltinFont::ZapfDingbats => 13,
+        };
+        &BUILTIN[index]
+    }
+
+    /// Reads the widths and kerning pairs of an AFM file. Glyphs are looked up by name
+    /// in WinAnsiEncoding, except in fonts with a `FontSpecific` encoding such as
+    /// Symbol, whose glyphs are drawn by their own codes.
+    pub fn parse(afm: &str) -> Result<Self, RustaTexError> {
+        let mut name = None;
+        let mut font_specific = false;
+        let mut glyphs: HashMap<&str, (Option<u8>, u16)> = HashMap::new();
+        let mut pairs = Vec::new();
+
+        for (number, line) in afm.lines().enumerate() {
+            let invalid =
+                || RustaTexError::FontError(format!("Invalid AFM line {}: {}", number + 1, line));
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("FontName") => name = words.next(),
+                Some("EncodingScheme") => font_specific = words.next() == Some("FontSpecific"),
+                Some("C") => {
+                    let mut code = None;
+                    let mut width = None;
+                    let mut glyph = None;
+                    for field in line.split(';') {
+                        let mut words = field.split_whitespace();
+                        match (words.next(), words.next()) {
+                            (Some("C"), Some(value)) => {
+                                code =
+                                    u8::try_from(value.parse::<i32>().map_err(|_| invalid())?).ok()
+                            }
+                            (Some("WX"), Some(value)) => {
+                                width = Some(value.parse::<f64>().map_err(|_| invalid())?)
+                            }
+                            (Some("N"), Some(value)) => glyph = Some(value),
+                            _ => (),
+                        }
+                    }
+                    let (Some(width), Some(glyph)) = (width, glyph) else {
+                        return Err(invalid());
+                    };
+                    glyphs.insert(glyph, (code, width.round() as u16));
+                }
+                Some("KPX") => {
+                    let (Some(left), Some(right), Some(value)) =
+                        (words.next(), words.next(), words.next())
+                    else {
+                        return Err(invalid());
+                    };
+                    let value = value.parse::<f64>().map_err(|_| invalid())?;
+                    pairs.push((left, right, value.round() as i16));
+                }
+                _ => (),
+            }
+        }
+        let Some(name) = name else {
+            return Err(RustaTexError::FontError(
+                "AFM file has no FontName".to_string(),
+            ));
+        };
+
+        // The codes each glyph is drawn with.
+        let codes = |glyph: &str| -> Vec<u8> {
+            if font_specific {
+                return glyphs
+                    .get(glyph)
+                    .and_then(|&(code, _)| code)
+                    .into_iter()
+                    .collect();
+            }
+            WIN_ANSI_CODES.get(glyph).cloned().unwrap_or_default()
+        };
+        let mut widths = vec![0; 256];
+        for (glyph, &(_, width)) in &glyphs {
+            for code in codes(glyph) {
+                widths[code as usize] = width;
+            }
+        }
+        let mut kerning = HashMap::new();
+        for (left, right, value) in pairs {
+            for left in codes(left) {
+                for right in codes(right) {
+                    kerning.insert((left, right), value);
+                }
+            }
+        }
+        Ok(FontMetrics {
+            name: name.to_string(),
+            widths,
+            kerning,
+        })
+    }
+}
+
+impl TextMetrics for FontMetrics {
+    fn encodes(&self, c: char) -> bool {
+        win_ansi(c).is_some()
+    }
+
+    fn char_width(&self, c: char) -> f64 {
+        win_ansi(c).map_or(0.0, |code| self.widths[code as usize] as f64)
+    }
+
+    fn kerning(&self, left: char, right: char) -> f64 {
+        match (win_ansi(left), win_ansi(right)) {
+            (Some(left), Some(right)) => self
+                .kerning
+                .get(&(left, right))
+                .map_or(0.0, |&value| value as f64),
+            _ => 0.0,
+        }
+    }
+}
+
+/// Returns the WinAnsiEncoding code of `c`, as lopdf encodes text for builtin fonts.
+pub(crate) fn win_ansi(c: char) -> Option<u8> {
+    let code = match c {
+        ' '..='~' | '\u{A0}'..='\u{FF}' => c as u32 as u8,
+        '€' => 0x80,
+        '‚' => 0x82,
+        'ƒ' => 0x83,
+        '„' => 0x84,
+        '…' => 0x85,
+        '†' => 0x86,
+        '‡' => 0x87,
+        'ˆ' => 0x88,
+        '‰' => 0x89,
+        'Š' => 0x8A,
+        '‹' => 0x8B,
+        'Œ' => 0x8C,
+        'Ž' => 0x8E,
+        '‘' => 0x91,
+        '’' => 0x92,
+        '“' => 0x93,
+        '”' => 0x94,
+        '•' => 0x95,
+        '–' => 0x96,
+        '—' => 0x97,
+        '˜' => 0x98,
+        '™' => 0x99,
+        'š' => 0x9A,
+        '›' => 0x9B,
+        'œ' => 0x9C,
+        'ž' => 0x9E,
+        'Ÿ' => 0x9F,
+        _ => return None,
+    };
+    Some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AFM: &str = "StartFontMetrics 4.1
+FontName Test-Regular
+EncodingScheme AdobeStandardEncoding
+StartCharMetrics 5
+C 32 ; WX 250 ; N space ; B 0 0 0 0 ;
+C 65 ; WX 722 ; N A ; B 15 0 706 674 ;
+C 86 ; WX 700.4 ; N V ; B 16 -11 697 662 ;
+C 39 ; WX 333 ; N quoteright ; B 79 433 218 676 ;
+C -1 ; WX 556 ; N eacute ; B 25 -14 531 692 ;
+EndCharMetrics
+StartKernData
+StartKernPairs 2
+KPX A V -135
+KPX V A -129.6
+EndKernPairs
+EndKernData
+EndFontMetrics
+";
+
+    #[test]
+    fn parses_widths_by_glyph_name() {
+        let metrics = FontMetrics::parse(AFM).unwrap();
+        assert_eq!(metrics.name, "Test-Regular");
+        assert_eq!(metrics.char_width('A'), 722.0);
+        assert_eq!(metrics.char_width('V'), 700.0);
+        // Glyphs are placed at their WinAnsiEncoding codes, whatever their AFM code.
+        assert_eq!(metrics.char_width('\u{2019}'), 333.0);
+        assert_eq!(metrics.char_width('\''), 0.0);
+        assert_eq!(metrics.char_width('é'), 556.0);
+        assert!(!metrics.encodes('\u{4e00}'));
+    }
+
+    #[test]
+    fn parses_kerning_pairs() {
+        let metrics = FontMetrics::parse(AFM).unwrap();
+        assert_eq!(metrics.kerning('A', 'V'), -135.0);
+        assert_eq!(metrics.kerning('V', 'A'), -130.0);
+        assert_eq!(metrics.kerning('A', 'A'), 0.0);
+        assert_eq!(
+            metrics.text_width("AVA", 10.0),
+            (722.0 * 2.0 + 700.0 - 265.0) / 100.0
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        assert!(FontMetrics::parse("StartFontMetrics 4.1\n").is_err());
+        let invalid = AFM.replace("WX 722", "WX wide");
+        assert!(matches!(
+            FontMetrics::parse(&invalid),
+            Err(RustaTexError::FontError(_))
+        ));
+    }
+
+    #[test]
+    fn loads_the_bundled_fonts() {
+        let times = FontMetrics::builtin(BuiltinFont::TimesRoman);
+        assert_eq!(times.name, "Times-Roman");
+        assert_eq!(times.text_width("AV", 1000.0), 722.0 + 722.0 - 135.0);
+        let courier = FontMetrics::builtin(BuiltinFont::Courier);
+        assert_eq!(courier.text_width("AV", 1000.0), 1200.0);
+    }
+}
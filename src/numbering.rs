@@ -0,0 +1,301 @@
+use crate::document::SectionLevel;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How a counter value is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NumberFormat {
+    /// 1, 2, 3
+    Arabic,
+    /// i, ii, iii
+    LowerRoman,
+    /// I, II, III
+    UpperRoman,
+    /// a, b, ..., z, aa, ab
+    LowerAlpha,
+    /// A, B, ..., Z, AA, AB
+    UpperAlpha,
+}
+
+impl NumberFormat {
+    /// Parses a format name: `arabic`, `roman`, `Roman`, `alph` or `Alph`, as in LaTeX,
+    /// or the spelled-out `lower-roman`, `upper-roman`, `lower-alpha` and `upper-alpha`.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "arabic" => Some(NumberFormat::Arabic),
+            "roman" | "lower-roman" => Some(NumberFormat::LowerRoman),
+            "Roman" | "upper-roman" => Some(NumberFormat::UpperRoman),
+            "alph" | "alpha" | "lower-alpha" => Some(NumberFormat::LowerAlpha),
+            "Alph" | "Alpha" | "upper-alpha" => Some(NumberFormat::UpperAlpha),
+            _ => None,
+        }
+    }
+
+    /// Formats `value`. Roman and alphabetic formats fall back to arabic for 0.
+    pub fn format(self, value: u32) -> String {
+        if value == 0 {
+            return "0".to_string();
+        }
+        match self {
+            NumberFormat::Arabic => value.to_string(),
+            NumberFormat::LowerRoman => roman(value).to_lowercase(),
+            NumberFormat::UpperRoman => roman(value),
+            NumberFormat::LowerAlpha => alpha(value).to_lowercase(),
+            NumberFormat::UpperAlpha => alpha(value),
+        }
+    }
+}
+
+fn roman(mut value: u32) -> String {
+    const NUMERALS: [(u32, &str); 13] = [
+        (1000, "M"),
+        (900, "CM"),
+        (500, "D"),
+        (400, "CD"),
+        (100, "C"),
+        (90, "XC"),
+        (50, "L"),
+        (40, "XL"),
+        (10, "X"),
+        (9, "IX"),
+        (5, "V"),
+        (4, "IV"),
+        (1, "I"),
+    ];
+    let mut result = String::new();
+    for (step, numeral) in NUMERALS {
+        while value >= step {
+            result.push_str(numeral);
+            value -= step;
+        }
+    }
+    result
+}
+
+/// Bijective base-26: A..Z, then AA, AB, ...
+fn alpha(mut value: u32) -> String {
+    let mut letters = Vec::new();
+    while value > 0 {
+        value -= 1;
+        letters.push((b'A' + (value % 26) as u8) as char);
+        value /= 26;
+    }
+    letters.iter().rev().collect()
+}
+
+/// Options controlling how headings, environments and list items are numbered.
+#[derive(Debug, Clone)]
+pub struct NumberingConfig {
+    /// The format of each level's own counter.
+    pub formats: BTreeMap<SectionLevel, NumberFormat>,
+    /// The format of the outermost numbered level after `\appendix`.
+    pub appendix_format: NumberFormat,
+    /// The deepest level that is numbered.
+    pub depth: SectionLevel,
+    /// Environments that are numbered, each with its own counter.
+    pub environments: Vec<String>,
+    /// The item format of `enumerate` lists by nesting depth; deeper lists reuse the
+    /// last one.
+    pub list_formats: Vec<NumberFormat>,
+}
+
+impl Default for NumberingConfig {
+    fn default() -> Self {
+        let formats = SectionLevel::ALL
+            .into_iter()
+            .map(|level| match level {
+                SectionLevel::Part => (level, NumberFormat::UpperRoman),
+                _ => (level, NumberFormat::Arabic),
+            })
+            .collect();
+        NumberingConfig {
+            formats,
+            appendix_format: NumberFormat::UpperAlpha,
+            depth: SectionLevel::Subsubsection,
+            environments: ["figure", "table", "equation"].map(String::from).to_vec(),
+            list_formats: vec![
+                NumberFormat::Arabic,
+                NumberFormat::LowerAlpha,
+                NumberFormat::LowerRoman,
+                NumberFormat::UpperAlpha,
+            ],
+        }
+    }
+}
+
+/// The heading and environment counters of a document while it is parsed.
+#[derive(Debug, Clone, Default)]
+pub struct Counters {
+    values: BTreeMap<SectionLevel, u32>,
+    /// The level numbered with letters once `\appendix` has been seen.
+    appendix: Option<SectionLevel>,
+    /// Counters of numbered environments, by name.
+    environments: BTreeMap<String, u32>,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Counters::default()
+    }
+
+    /// Steps the counter of `level`, resets the counters below it and returns the
+    /// heading's full number, e.g. `2.1.3`, or `None` if `level` is not numbered.
+    ///
+    /// Parts are numbered on their own and do not reset chapters. Other numbers start
+    /// at the outermost level that has been used, so sections are `2.1` in a document
+    /// with chapters and `1` in one without.
+    pub fn step(&mut self, level: SectionLevel, config: &NumberingConfig) -> Option<String> {
+        if level > config.depth {
+            return None;
+        }
+        if let Some(appendix) = self.appendix {
+            // Headings before the first lettered one belong to appendix A, so that
+            // their numbers do not clash with those before `\appendix`.
+            if level > appendix && self.value(appendix) == 0 {
+                self.values.insert(appendix, 1);
+            }
+        }
+        *self.values.entry(level).or_insert(0) += 1;
+        if level != SectionLevel::Part {
+            self.values.retain(|&other, _| other <= level);
+        }
+        if level == SectionLevel::Chapter {
+            self.environments.clear();
+        }
+
+        if level == SectionLevel::Part {
+            return Some(self.format(level, config));
+        }
+        let parts: Vec<String> = SectionLevel::ALL
+            .into_iter()
+            .filter(|&other| other > SectionLevel::Part && other <= level)
+            .skip_while(|&other| self.value(other) == 0)
+            .map(|level| self.format(level, config))
+            .collect();
+        Some(parts.join("."))
+    }
+
+    /// Steps the counter of the numbered environment `name` and returns its number,
+    /// e.g. `3`, or `2.3` for the third one in chapter 2. Chapters restart the count.
+    pub fn step_environment(&mut self, name: &str, config: &NumberingConfig) -> String {
+        let value = self.environments.entry(name.to_string()).or_insert(0);
+        *value += 1;
+        let value = *value;
+        if self.value(SectionLevel::Chapter) > 0 {
+            format!("{}.{}", self.format(SectionLevel::Chapter, config), value)
+        } else {
+            value.to_string()
+        }
+    }
+
+    /// Switches the outermost level in use, chapters if there are any and sections
+    /// otherwise, to appendix numbering, restarts it and returns it.
+    pub fn start_appendix(&mut self) -> SectionLevel {
+        let level = if self.value(SectionLevel::Chapter) > 0 {
+            SectionLevel::Chapter
+        } else {
+            SectionLevel::Section
+        };
+        self.values.retain(|&other, _| other < level);
+        self.appendix = Some(level);
+        if level == SectionLevel::Chapter {
+            self.environments.clear();
+        }
+        level
+    }
+
+    /// Formats the current value of `level`'s own counter.
+    fn format(&self, level: SectionLevel, config: &NumberingConfig) -> String {
+        let format = if self.appendix == Some(level) {
+            config.appendix_format
+        } else {
+            config
+                .formats
+                .get(&level)
+                .copied()
+                .unwrap_or(NumberFormat::Arabic)
+        };
+        format.format(self.value(level))
+    }
+
+    fn value(&self, level: SectionLevel) -> u32 {
+        self.values.get(&level).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_and_resets_nested_levels() {
+        use SectionLevel::*;
+        let config = NumberingConfig::default();
+        let mut counters = Counters::new();
+        for (level, number) in [
+            (Section, "1"),
+            (Subsection, "1.1"),
+            (Subsection, "1.2"),
+            (Subsubsection, "1.2.1"),
+            (Section, "2"),
+            (Subsection, "2.1"),
+        ] {
+            assert_eq!(counters.step(level, &config).as_deref(), Some(number));
+        }
+        // Levels deeper than the numbering depth have no number.
+        assert_eq!(counters.step(Paragraph, &config), None);
+    }
+
+    #[test]
+    fn numbers_from_chapters_and_keeps_parts_apart() {
+        use SectionLevel::*;
+        let config = NumberingConfig::default();
+        let mut counters = Counters::new();
+        for (level, number) in [
+            (Part, "I"),
+            (Chapter, "1"),
+            (Section, "1.1"),
+            (Part, "II"),
+            (Chapter, "2"),
+            (Section, "2.1"),
+        ] {
+            assert_eq!(counters.step(level, &config).as_deref(), Some(number));
+        }
+        assert_eq!(counters.step_environment("figure", &config), "2.1");
+    }
+
+    #[test]
+    fn letters_the_outermost_level_after_the_appendix() {
+        use SectionLevel::*;
+        let config = NumberingConfig::default();
+        let mut counters = Counters::new();
+        counters.step(Section, &config);
+        counters.step(Section, &config);
+        assert_eq!(counters.start_appendix(), Section);
+        for (level, number) in [(Section, "A"), (Subsection, "A.1"), (Section, "B")] {
+            assert_eq!(counters.step(level, &config).as_deref(), Some(number));
+        }
+
+        let mut counters = Counters::new();
+        counters.step(Chapter, &config);
+        counters.step(Section, &config);
+        assert_eq!(counters.start_appendix(), Chapter);
+        for (level, number) in [(Chapter, "A"), (Section, "A.1")] {
+            assert_eq!(counters.step(level, &config).as_deref(), Some(number));
+        }
+
+        // Sections before the first appendix chapter are in appendix A.
+        let mut counters = Counters::new();
+        counters.step(Chapter, &config);
+        counters.step(Section, &config);
+        counters.start_appendix();
+        for (level, number) in [
+            (Section, "A.1"),
+            (Section, "A.2"),
+            (Chapter, "B"),
+            (Section, "B.1"),
+        ] {
+            assert_eq!(counters.step(level, &config).as_deref(), Some(number));
+        }
+    }
+}
@@ -1,200 +1,1221 @@
+use crate::commands::{CommandHandler, CommandOutput, CommandRegistry};
 use crate::document::*;
 use crate::errors::RustaTexError;
+use crate::lexer::{tokenize, TokenKind};
+use crate::macros::{expand_source_with_commands, Macro};
+use crate::numbering::{Counters, NumberFormat, NumberingConfig};
+use crate::source::{Location, SourceFiles, SourceMap};
 use log::{debug, error};
-use pest::iterators::Pair;
+use pest::error::{Error as PestError, ErrorVariant, InputLocation};
+use pest::iterators::{Pair, Pairs};
 use pest::Parser as PestParser;
-use regex::Regex;
+use pest::Position;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
 #[derive(pest_derive::Parser)]
 #[grammar = "grammar.pest"]
 pub struct RustaTexParser;
 
+/// Options controlling how input is parsed.
+#[derive(Debug, Clone)]
+pub struct ParserConfig {
+    /// Maximum nesting depth of macro expansions before parsing fails.
+    pub max_macro_depth: usize,
+    /// Macros defined before parsing starts, e.g. from the command line.
+    pub macros: HashMap<String, Macro>,
+    /// Directories searched for `\input` and `\include` files that are not found next
+    /// to the including file.
+    pub include_paths: Vec<PathBuf>,
+    /// Flags that are set for `\ifdef{flag}` conditionals.
+    pub flags: HashSet<String>,
+    pub numbering: NumberingConfig,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        ParserConfig {
+            max_macro_depth: 32,
+            macros: HashMap::new(),
+            include_paths: Vec::new(),
+            flags: HashSet::new(),
+            numbering: NumberingConfig::default(),
+        }
+    }
+}
+
+/// A parser with its configuration and the commands it knows.
+#[derive(Debug)]
+pub struct Parser {
+    pub config: ParserConfig,
+    pub commands: CommandRegistry,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Parser::new(ParserConfig::default())
+    }
+}
+
+impl Parser {
+    /// Creates a parser that knows the built-in commands.
+    pub fn new(config: ParserConfig) -> Self {
+        Parser {
+            config,
+            commands: CommandRegistry::with_builtins(),
+        }
+    }
+
+    /// Registers `handler` for `\name`, replacing any previous handler, including a
+    /// built-in one.
+    pub fn register(&mut self, name: impl Into<String>, handler: impl CommandHandler + 'static) {
+        self.commands.register(name, handler);
+    }
+
+    /// Parses the input RustaTex content into a Document structure.
+    pub fn parse(&self, input: &str) -> Result<Document, RustaTexError> {
+        self.parse_source(input, None)
+    }
+
+    /// Parses the content of the file at `path`. Files it includes are resolved relative
+    /// to `path`, and diagnostics name the file they occur in.
+    pub fn parse_source(
+        &self,
+        input: &str,
+        path: Option<&Path>,
+    ) -> Result<Document, RustaTexError> {
+        debug!("Expanding macros.");
+        let expansion = expand_source_with_commands(input, path, &self.config, &self.commands)?;
+
+        debug!("Starting parsing of the document.");
+        let parsed = RustaTexParser::parse(Rule::document, &expansion.text)
+            .map_err(|err| remap_error(err, &expansion.files, &expansion.source_map))?;
+        let mut state = ParserState::new(
+            &expansion.text,
+            expansion.source_map,
+            expansion.files,
+            &self.commands,
+            &self.config.numbering,
+        );
+        state.document.macros = expansion.macros;
+
+        for pair in parsed {
+            match pair.as_rule() {
+                Rule::document => {
+                    let elements = state.parse_blocks(pair.into_inner(), 0, Vec::new())?;
+                    state.document.elements.extend(elements);
+                }
+                _ => {
+                    error!("Unexpected top-level rule: {:?}", pair.as_rule());
+                    return Err(RustaTexError::InvalidSyntax(format!(
+                        "Unexpected top-level rule: {:?}",
+                        pair.as_rule()
+                    )));
+                }
+            }
+        }
+
+        state.check_references()?;
+        Ok(state.document)
+    }
+}
+
 /// Parses the input RustaTex content into a Document structure.
 pub fn parse_input(input: &str) -> Result<Document, RustaTexError> {
-    debug!("Starting parsing of the document.");
-    let parsed = RustaTexParser::parse(Rule::document, input)?;
-    let mut document = Document::new();
-
-    for pair in parsed {
-        match pair.as_rule() {
-            Rule::document => {
-                for inner_pair in pair.into_inner() {
-                    match inner_pair.as_rule() {
-                        Rule::command => {
-                            debug!("Parsing command: {:?}", inner_pair.as_str());
-                            parse_command(inner_pair, &mut document)?;
-                        }
-                        Rule::paragraph => {
-                            debug!("Parsing paragraph.");
-                            let paragraph = parse_paragraph(inner_pair)?;
-                            document.add_element(DocumentElement::Paragraph(paragraph));
-                        }
-                        Rule::EOI => (),
-                        _ => {
-                            error!("Unexpected rule in document: {:?}", inner_pair.as_rule());
-                            return Err(RustaTexError::InvalidSyntax(format!(
-                                "Unexpected rule in document: {:?}",
-                                inner_pair.as_rule()
-                            )));
-                        }
-                    }
+    Parser::default().parse(input)
+}
+
+/// Parses the input RustaTex content using the given configuration.
+pub fn parse_input_with_config(
+    input: &str,
+    config: &ParserConfig,
+) -> Result<Document, RustaTexError> {
+    Parser::new(config.clone()).parse(input)
+}
+
+/// Parses the content of the file at `path` using the given configuration.
+pub fn parse_source(
+    input: &str,
+    path: Option<&Path>,
+    config: &ParserConfig,
+) -> Result<Document, RustaTexError> {
+    Parser::new(config.clone()).parse_source(input, path)
+}
+
+/// Moves a pest error on the expanded text to the matching position in the source file,
+/// naming the files that included it.
+fn remap_error(err: PestError<Rule>, files: &SourceFiles, source_map: &SourceMap) -> RustaTexError {
+    let offset = match err.location {
+        InputLocation::Pos(offset) => offset,
+        InputLocation::Span((start, _)) => start,
+    };
+    let (file, offset) = source_map.source_position(offset);
+    let source = files.get(file);
+    let Some(position) = Position::new(&source.text, offset) else {
+        return err.into();
+    };
+
+    let variant = match files.location(file, offset).included_from {
+        Some(parent) => ErrorVariant::CustomError {
+            message: format!(
+                "{}, in a file included from {}",
+                err.variant.message(),
+                parent
+            ),
+        },
+        None => err.variant,
+    };
+    let err = PestError::new_from_pos(variant, position);
+    match &source.path {
+        Some(path) => err.with_path(&path.display().to_string()).into(),
+        None => err.into(),
+    }
+}
+
+/// A command invocation such as `\cmd[key=value]{arg1}{arg2}`.
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub name: String,
+    /// Options merged from all `[...]` groups.
+    pub options: Options,
+    /// Raw text of each `[...]` group, in source order.
+    pub optional_args: Vec<String>,
+    /// Raw text of each `{...}` group, in source order.
+    pub args: Vec<String>,
+    /// Whether the name is followed by `*`, as in `\section*`.
+    pub starred: bool,
+    /// Where the command appears in the source.
+    pub location: Location,
+    /// Byte offset of each mandatory argument in the input.
+    arg_offsets: Vec<usize>,
+}
+
+impl Command {
+    /// Returns the mandatory argument at `index`.
+    pub fn arg(&self, index: usize) -> Result<&str, RustaTexError> {
+        match self.args.get(index) {
+            Some(arg) => Ok(arg),
+            None => {
+                error!(
+                    "Missing argument {} for command '{}' at {}",
+                    index + 1,
+                    self.name,
+                    self.location
+                );
+                Err(RustaTexError::InvalidSyntax(format!(
+                    "Missing argument {} for command '{}' at {}",
+                    index + 1,
+                    self.name,
+                    self.location
+                )))
+            }
+        }
+    }
+}
+
+/// State shared while parsing a single input.
+struct ParserState<'i> {
+    /// The source after macro expansion, which is what gets parsed.
+    input: &'i str,
+    source_map: SourceMap,
+    /// The source files, used for positions in diagnostics.
+    files: SourceFiles,
+    commands: &'i CommandRegistry,
+    numbering: &'i NumberingConfig,
+    counters: Counters,
+    /// Headings are only numbered in the main matter.
+    matter: Matter,
+    document: Document,
+    footnotes: u32,
+    /// The containers being parsed, outermost first.
+    trees: Vec<SectionTree>,
+    /// The numbered environments being parsed, outermost first.
+    environments: Vec<NumberedEnvironment>,
+    /// The lists being parsed, outermost first.
+    lists: Vec<List>,
+    /// What a `\label` found now is attached to.
+    label_scope: Option<LabelScope>,
+    /// Where each label is defined.
+    label_locations: HashMap<String, Location>,
+    /// Each `\ref` and `\pageref`, checked once the whole input is parsed.
+    references: Vec<(String, Location)>,
+}
+
+/// The most recent labelable element, as in LaTeX: the last heading or list item, or
+/// the numbered environment around it. Environments restore the previous one at
+/// their end.
+#[derive(Debug, Clone, Copy)]
+enum LabelScope {
+    /// The innermost open heading of `trees[index]`.
+    Heading(usize),
+    /// The open item of `trees[index]`.
+    Item(usize),
+    /// `environments[index]`.
+    Environment(usize),
+}
+
+/// A numbered environment such as `figure` that is being parsed.
+struct NumberedEnvironment {
+    name: String,
+    number: String,
+    labels: Vec<String>,
+}
+
+/// An `itemize` or `enumerate` list that is being parsed.
+struct List {
+    ordered: bool,
+    items: u32,
+}
+
+impl<'i> ParserState<'i> {
+    fn new(
+        input: &'i str,
+        source_map: SourceMap,
+        files: SourceFiles,
+        commands: &'i CommandRegistry,
+        numbering: &'i NumberingConfig,
+    ) -> Self {
+        ParserState {
+            input,
+            source_map,
+            files,
+            commands,
+            numbering,
+            counters: Counters::new(),
+            matter: Matter::Main,
+            document: Document::new(),
+            footnotes: 0,
+            trees: Vec::new(),
+            environments: Vec::new(),
+            lists: Vec::new(),
+            label_scope: None,
+            label_locations: HashMap::new(),
+            references: Vec::new(),
+        }
+    }
+
+    /// Returns the tree of the innermost container being parsed.
+    fn tree(&mut self) -> &mut SectionTree {
+        self.trees
+            .last_mut()
+            .expect("blocks are only parsed inside a container")
+    }
+
+    /// Converts a byte offset in the expanded input into a location in the source files.
+    fn location(&self, offset: usize) -> Location {
+        let (file, offset) = self
+            .source_map
+            .source_position(offset.min(self.input.len()));
+        self.files.location(file, offset)
+    }
+
+    /// Parses a command argument as inline content and flattens it to plain text.
+    /// Unlike in paragraphs, `\\` stands for a literal backslash here.
+    fn argument_text(&mut self, command: &Command, index: usize) -> Result<String, RustaTexError> {
+        let content = self.inline_arg(command, index)?;
+        let text: String = content
+            .iter()
+            .map(|inline| match inline {
+                Inline::LineBreak => "\\".to_string(),
+                inline => inline_text(std::slice::from_ref(inline)),
+            })
+            .collect();
+        Ok(text.trim().to_string())
+    }
+
+    /// Parses a sequence of blocks and returns the elements they produce, nested under
+    /// their headings. `base` is the offset of the parsed text within the input and
+    /// `outer` holds the levels of the headings open around it.
+    fn parse_blocks(
+        &mut self,
+        pairs: Pairs<Rule>,
+        base: usize,
+        outer: Vec<SectionLevel>,
+    ) -> Result<Vec<DocumentElement>, RustaTexError> {
+        self.trees.push(SectionTree::new(outer));
+
+        for pair in pairs {
+            match pair.as_rule() {
+                Rule::environment => {
+                    debug!("Parsing environment.");
+                    let levels = self.tree().levels();
+                    let environment = self.parse_environment(pair, base, levels)?;
+                    self.tree().push(DocumentElement::Environment(environment));
+                }
+                Rule::paragraph => {
+                    debug!("Parsing paragraph.");
+                    self.parse_paragraph(pair, base)?;
                 }
+                Rule::EOI => (),
+                _ => {
+                    error!("Unexpected rule in document: {:?}", pair.as_rule());
+                    return Err(RustaTexError::InvalidSyntax(format!(
+                        "Unexpected rule in document: {:?}",
+                        pair.as_rule()
+                    )));
+                }
+            }
+        }
+
+        let tree = self.trees.pop();
+        Ok(tree.map(SectionTree::finish).unwrap_or_default())
+    }
+
+    /// Parses a `\begin{name}...\end{name}` block, checking that both names match.
+    fn parse_environment(
+        &mut self,
+        pair: Pair<Rule>,
+        base: usize,
+        outer: Vec<SectionLevel>,
+    ) -> Result<Environment, RustaTexError> {
+        let mut inner = pair.into_inner();
+        let begin = match inner.next() {
+            Some(begin) => self.command(begin, base)?,
+            None => {
+                return Err(RustaTexError::InvalidSyntax(
+                    "Environment without \\begin".to_string(),
+                ))
             }
-            _ => {
-                error!("Unexpected top-level rule: {:?}", pair.as_rule());
+        };
+        let end = match inner.next_back() {
+            Some(end) => end,
+            None => {
                 return Err(RustaTexError::InvalidSyntax(format!(
-                    "Unexpected top-level rule: {:?}",
-                    pair.as_rule()
-                )));
+                    "Environment '{}' at {} is never closed",
+                    begin.name, begin.location
+                )))
             }
+        };
+
+        let end_location = self.location(base + end.as_span().start());
+        let end_name = end.into_inner().as_str();
+        if end_name != begin.name {
+            error!(
+                "Environment '{}' opened at {} is closed by '{}' at {}",
+                begin.name, begin.location, end_name, end_location
+            );
+            return Err(RustaTexError::InvalidSyntax(format!(
+                "Environment '{}' opened at {} is closed by '{}' at {}",
+                begin.name, begin.location, end_name, end_location
+            )));
+        }
+
+        let scope = self.label_scope;
+        let number = if self.numbering.environments.contains(&begin.name) {
+            let number = self.counters.step_environment(&begin.name, self.numbering);
+            self.environments.push(NumberedEnvironment {
+                name: begin.name.clone(),
+                number: number.clone(),
+                labels: Vec::new(),
+            });
+            self.label_scope = Some(LabelScope::Environment(self.environments.len() - 1));
+            Some(number)
+        } else {
+            None
+        };
+        let list = matches!(begin.name.as_str(), "itemize" | "enumerate");
+        if list {
+            self.lists.push(List {
+                ordered: begin.name == "enumerate",
+                items: 0,
+            });
         }
+
+        let elements = self.parse_blocks(inner, base, outer)?;
+
+        if list {
+            self.lists.pop();
+        }
+        let labels = match number {
+            Some(_) => self
+                .environments
+                .pop()
+                .map(|numbered| numbered.labels)
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+        self.label_scope = scope;
+        debug!("Added environment: {}", begin.name);
+        Ok(Environment {
+            name: begin.name,
+            options: begin.options,
+            args: begin.args,
+            number,
+            labels,
+            elements,
+        })
     }
 
-    Ok(document)
-}
+    /// Builds a Command from a `command` or `begin_environment` pair.
+    fn command(&self, pair: Pair<Rule>, base: usize) -> Result<Command, RustaTexError> {
+        let location = self.location(base + pair.as_span().start());
+        let mut command = Command {
+            name: String::new(),
+            options: Options::new(),
+            optional_args: Vec::new(),
+            args: Vec::new(),
+            starred: false,
+            location,
+            arg_offsets: Vec::new(),
+        };
+
+        for inner in pair.into_inner() {
+            match inner.as_rule() {
+                Rule::backslash => (),
+                Rule::star => command.starred = true,
+                Rule::identifier | Rule::environment_name => {
+                    command.name = inner.as_str().to_string()
+                }
+                Rule::optional_group => {
+                    let text = inner.into_inner().as_str();
+                    command.options.extend(Options::parse(text));
+                    command.optional_args.push(text.to_string());
+                }
+                Rule::mandatory_group => {
+                    // The argument starts right after the opening brace.
+                    command.arg_offsets.push(base + inner.as_span().start() + 1);
+                    command.args.push(inner.into_inner().as_str().to_string());
+                }
+                rule => {
+                    return Err(RustaTexError::InvalidSyntax(format!(
+                        "Unexpected rule {:?} in command at {}",
+                        rule, command.location
+                    )))
+                }
+            }
+        }
+
+        if command.name.is_empty() {
+            error!("Missing command identifier at {}", command.location);
+            return Err(RustaTexError::InvalidSyntax(format!(
+                "Missing command identifier at {}",
+                command.location
+            )));
+        }
+
+        Ok(command)
+    }
+
+    /// Parses a paragraph into the current container. Block commands inside the
+    /// paragraph end the current paragraph and add their own element; comments are
+    /// dropped.
+    fn parse_paragraph(&mut self, pair: Pair<Rule>, base: usize) -> Result<(), RustaTexError> {
+        let mut content = Vec::new();
 
-/// Parses a single command and updates the Document accordingly.
-fn parse_command(pair: Pair<Rule>, document: &mut Document) -> Result<(), RustaTexError> {
-    let span = pair.as_span();
-    let position = span.start_pos();
+        for inner in pair.into_inner() {
+            match inner.as_rule() {
+                Rule::paragraph_text => push_text(&mut content, inner.as_str()),
+                Rule::inline_math => {
+                    content.push(Inline::Math(inner.into_inner().as_str().to_string()))
+                }
+                Rule::command => {
+                    let command = self.command(inner, base)?;
+                    match self.run_command(&command)? {
+                        CommandOutput::Inline(inline) => content.push(inline),
+                        CommandOutput::Element(element) => {
+                            push_paragraph(self.tree(), std::mem::take(&mut content));
+                            self.push_element(element, &command.location)?;
+                        }
+                        CommandOutput::None => (),
+                    }
+                }
+                rule => {
+                    return Err(RustaTexError::InvalidSyntax(format!(
+                        "Unexpected rule in paragraph: {:?}",
+                        rule
+                    )))
+                }
+            }
+        }
 
-    let mut inner = pair.into_inner();
+        push_paragraph(self.tree(), content);
+        Ok(())
+    }
 
-    // Skip the backslash
-    let _backslash = inner.next();
+    /// Adds an element produced by a command to the current container. Headings and
+    /// list items collect the content that follows them and take the next `\label`.
+    fn push_element(
+        &mut self,
+        element: DocumentElement,
+        location: &Location,
+    ) -> Result<(), RustaTexError> {
+        let index = self.trees.len() - 1;
+        match element {
+            DocumentElement::Section(section) => {
+                self.trees[index].push_heading(section, location)?;
+                self.label_scope = Some(LabelScope::Heading(index));
+            }
+            DocumentElement::ListItem(item) => {
+                self.trees[index].push_item(item);
+                self.label_scope = Some(LabelScope::Item(index));
+            }
+            DocumentElement::Matter(matter) => {
+                // A new division ends every heading, like a new part.
+                self.trees[index].close_item();
+                self.trees[index].close(SectionLevel::Part);
+                self.trees[index].push(DocumentElement::Matter(matter));
+                self.label_scope = None;
+            }
+            element => self.trees[index].push(element),
+        }
+        Ok(())
+    }
 
-    // Now get the identifier (command) and argument
-    let command = match inner.next() {
-        Some(cmd) => cmd.as_str(),
-        None => {
+    /// Attaches the label `key` to the most recent labelable element.
+    fn define_label(&mut self, key: String, location: &Location) -> Result<(), RustaTexError> {
+        if let Some(previous) = self.label_locations.get(&key) {
             error!(
-                "Missing command identifier at line {}, column {}",
-                position.line_col().0,
-                position.line_col().1
+                "Label '{}' at {} is already defined at {}",
+                key, location, previous
             );
-            return Err(RustaTexError::InvalidSyntax(format!(
-                "Missing command identifier at line {}, column {}",
-                position.line_col().0,
-                position.line_col().1
+            return Err(RustaTexError::ReferenceError(format!(
+                "Label '{}' at {} is already defined at {}",
+                key, location, previous
             )));
         }
-    };
 
-    let argument = match inner.next() {
-        Some(arg) => arg.as_str(),
-        None => {
+        let label = match self.label_scope {
+            Some(LabelScope::Heading(index)) => self
+                .trees
+                .get_mut(index)
+                .and_then(|tree| tree.open.last_mut())
+                .map(|section| {
+                    section.labels.push(key.clone());
+                    Label {
+                        target: LabelTarget::Heading(section.level),
+                        number: section.number.clone(),
+                    }
+                }),
+            Some(LabelScope::Item(index)) => self
+                .trees
+                .get_mut(index)
+                .and_then(|tree| tree.item.as_mut())
+                .map(|item| {
+                    item.labels.push(key.clone());
+                    Label {
+                        target: LabelTarget::ListItem,
+                        number: item.number.clone(),
+                    }
+                }),
+            Some(LabelScope::Environment(index)) => {
+                self.environments.get_mut(index).map(|environment| {
+                    environment.labels.push(key.clone());
+                    Label {
+                        target: LabelTarget::Environment(environment.name.clone()),
+                        number: Some(environment.number.clone()),
+                    }
+                })
+            }
+            None => None,
+        };
+        let Some(label) = label else {
             error!(
-                "Missing argument for command '{}' at line {}, column {}",
-                command,
-                position.line_col().0,
-                position.line_col().1
+                "Label '{}' at {} does not follow a heading, numbered environment or list item",
+                key, location
             );
-            return Err(RustaTexError::InvalidSyntax(format!(
-                "Missing argument for command '{}' at line {}, column {}",
-                command,
-                position.line_col().0,
-                position.line_col().1
+            return Err(RustaTexError::ReferenceError(format!(
+                "Label '{}' at {} does not follow a heading, numbered environment or list item",
+                key, location
             )));
+        };
+
+        debug!("Defined label '{}': {:?}", key, label);
+        self.label_locations.insert(key.clone(), location.clone());
+        self.document.labels.insert(key, label);
+        Ok(())
+    }
+
+    /// Fails with every `\ref` and `\pageref` whose label is never defined.
+    fn check_references(&self) -> Result<(), RustaTexError> {
+        let undefined: Vec<String> = self
+            .references
+            .iter()
+            .filter(|(key, _)| !self.document.labels.contains_key(key))
+            .map(|(key, location)| format!("Undefined reference '{}' at {}", key, location))
+            .collect();
+        if undefined.is_empty() {
+            return Ok(());
         }
-    };
+        for message in &undefined {
+            error!("{}", message);
+        }
+        Err(RustaTexError::ReferenceError(undefined.join("; ")))
+    }
 
-    match command {
-        "section" => {
-            let section = Section {
-                title: substitute_macros(argument, document)?,
-                elements: Vec::new(),
-                label: None,
+    /// Parses text (typically a command argument starting at `base`) as inline content.
+    fn parse_inline(&mut self, text: &str, base: usize) -> Result<Vec<Inline>, RustaTexError> {
+        let pairs = RustaTexParser::parse(Rule::inline_content, text).map_err(|err| {
+            let offset = match err.location {
+                InputLocation::Pos(offset) => offset,
+                InputLocation::Span((start, _)) => start,
             };
-            document.add_element(DocumentElement::Section(section));
-            debug!("Added section: {}", argument);
-        }
-        "subsection" => {
-            let subsection = Subsection {
-                title: substitute_macros(argument, document)?,
-                elements: Vec::new(),
-                label: None,
-            };
-            document.add_element(DocumentElement::Subsection(subsection));
-            debug!("Added subsection: {}", argument);
+            RustaTexError::InvalidSyntax(format!(
+                "Invalid inline content at {}: {}",
+                self.location(base + offset.min(text.len())),
+                err.variant.message()
+            ))
+        })?;
+
+        let mut content = Vec::new();
+        for pair in pairs.flat_map(|pair| pair.into_inner()) {
+            match pair.as_rule() {
+                Rule::inline_text => push_text(&mut content, pair.as_str()),
+                Rule::inline_math => {
+                    content.push(Inline::Math(pair.into_inner().as_str().to_string()))
+                }
+                Rule::command => {
+                    let command = self.command(pair, base)?;
+                    match self.run_command(&command)? {
+                        CommandOutput::Inline(inline) => content.push(inline),
+                        CommandOutput::None => (),
+                        CommandOutput::Element(_) => {
+                            error!(
+                                "Command '{}' cannot be used inline at {}",
+                                command.name, command.location
+                            );
+                            return Err(RustaTexError::InvalidSyntax(format!(
+                                "Command '{}' cannot be used inline at {}",
+                                command.name, command.location
+                            )));
+                        }
+                    }
+                }
+                _ => (),
+            }
         }
-        "paragraph" => {
-            let paragraph = Paragraph {
-                text: substitute_macros(argument, document)?,
-                style: None,
-            };
-            document.add_element(DocumentElement::Paragraph(paragraph));
-            debug!("Added paragraph.");
-        }
-        "macro" => {
-            // Define a new macro
-            let parts: Vec<&str> = argument.split('=').collect();
-            if parts.len() == 2 {
-                let name = parts[0].trim().to_string();
-                let value = parts[1].trim().to_string();
-                document.define_macro(name.clone(), value.clone());
-                debug!("Defined macro: {} = {}", name, value);
-            } else {
+        Ok(content)
+    }
+
+    /// Parses the mandatory argument at `index` of an inline command as inline content.
+    fn inline_arg(
+        &mut self,
+        command: &Command,
+        index: usize,
+    ) -> Result<Vec<Inline>, RustaTexError> {
+        let argument = command.arg(index)?;
+        self.parse_inline(argument, command.arg_offsets[index])
+    }
+
+    /// Runs the handler registered for a command.
+    fn run_command(&mut self, command: &Command) -> Result<CommandOutput, RustaTexError> {
+        let commands = self.commands;
+        let Some(handler) = commands.get(&command.name) else {
+            error!("Unknown command '{}' at {}", command.name, command.location);
+            return Err(RustaTexError::UnknownCommand(format!(
+                "{} at {}",
+                command.name, command.location
+            )));
+        };
+        handler.handle(command, &mut CommandContext { state: self })
+    }
+}
+
+/// Gives command handlers access to the document being built and to the parsing of
+/// their arguments.
+pub struct CommandContext<'s, 'i> {
+    state: &'s mut ParserState<'i>,
+}
+
+impl CommandContext<'_, '_> {
+    /// Returns the document being built, e.g. to set its metadata.
+    pub fn document(&mut self) -> &mut Document {
+        &mut self.state.document
+    }
+
+    /// Parses the mandatory argument at `index` of `command` as inline content.
+    pub fn inline_arg(
+        &mut self,
+        command: &Command,
+        index: usize,
+    ) -> Result<Vec<Inline>, RustaTexError> {
+        self.state.inline_arg(command, index)
+    }
+
+    /// Parses the mandatory argument at `index` of `command` as inline content and
+    /// flattens it to plain text.
+    pub fn argument_text(
+        &mut self,
+        command: &Command,
+        index: usize,
+    ) -> Result<String, RustaTexError> {
+        self.state.argument_text(command, index)
+    }
+
+    /// Steps the heading counters for a new numbered heading at `level` and returns
+    /// its number, or `None` if headings at `level` are not numbered or the heading is
+    /// outside the main matter.
+    pub fn number_heading(&mut self, level: SectionLevel) -> Option<String> {
+        if self.state.matter != Matter::Main {
+            return None;
+        }
+        self.state.counters.step(level, self.state.numbering)
+    }
+
+    /// Starts the front, main or back matter.
+    pub fn start_matter(&mut self, matter: Matter) {
+        self.state.matter = matter;
+    }
+
+    /// Switches heading numbering to the appendix format and closes the open headings
+    /// at the lettered level, so that the appendix does not nest under them.
+    pub fn start_appendix(&mut self) {
+        let level = self.state.counters.start_appendix();
+        let tree = self.state.tree();
+        tree.close_item();
+        tree.close(level);
+        self.state.label_scope = None;
+    }
+
+    /// Returns the number of a new item in the innermost list, or `None` in an
+    /// `itemize` list. Fails outside lists.
+    pub fn number_item(&mut self, location: &Location) -> Result<Option<String>, RustaTexError> {
+        let depth = self.state.lists.iter().filter(|list| list.ordered).count();
+        let Some(list) = self.state.lists.last_mut() else {
+            error!("\\item at {} is not inside a list", location);
+            return Err(RustaTexError::InvalidSyntax(format!(
+                "\\item at {} is not inside a list",
+                location
+            )));
+        };
+        list.items += 1;
+        if !list.ordered {
+            return Ok(None);
+        }
+        let formats = &self.state.numbering.list_formats;
+        let format = formats
+            .get(depth - 1)
+            .or(formats.last())
+            .copied()
+            .unwrap_or(NumberFormat::Arabic);
+        Ok(Some(format.format(list.items)))
+    }
+
+    /// Attaches the label `key` to the most recent heading, numbered environment or
+    /// list item. Fails if `key` is already defined.
+    pub fn define_label(&mut self, key: String, location: &Location) -> Result<(), RustaTexError> {
+        self.state.define_label(key, location)
+    }
+
+    /// Records a reference to `key` at `location`; it must be defined by the end of the
+    /// input.
+    pub fn add_reference(&mut self, key: String, location: &Location) {
+        self.state.references.push((key, location.clone()));
+    }
+
+    /// Returns the number of the next footnote.
+    pub fn next_footnote(&mut self) -> u32 {
+        self.state.footnotes += 1;
+        self.state.footnotes
+    }
+}
+
+/// Nests the elements of one container, the document or an environment, under the
+/// headings that precede them.
+struct SectionTree {
+    elements: Vec<DocumentElement>,
+    /// Headings still collecting content, outermost first.
+    open: Vec<Section>,
+    /// The list item collecting content, inside the innermost open heading.
+    item: Option<ListItem>,
+    /// Levels of the headings open around the container.
+    outer: Vec<SectionLevel>,
+}
+
+impl SectionTree {
+    fn new(outer: Vec<SectionLevel>) -> Self {
+        SectionTree {
+            elements: Vec::new(),
+            open: Vec::new(),
+            item: None,
+            outer,
+        }
+    }
+
+    /// Returns the levels of all open headings, including those around the container.
+    fn levels(&self) -> Vec<SectionLevel> {
+        let open = self.open.iter().map(|section| section.level);
+        self.outer.iter().copied().chain(open).collect()
+    }
+
+    /// Adds an element to the open list item or the innermost open heading.
+    fn push(&mut self, element: DocumentElement) {
+        if let Some(item) = &mut self.item {
+            item.elements.push(element);
+            return;
+        }
+        match self.open.last_mut() {
+            Some(section) => section.elements.push(element),
+            None => self.elements.push(element),
+        }
+    }
+
+    /// Closes the headings at the level of `section` or deeper and opens `section`.
+    fn push_heading(&mut self, section: Section, location: &Location) -> Result<(), RustaTexError> {
+        self.close_item();
+        self.close(section.level);
+        if let Some(parent) = section.level.required_parent() {
+            if !self.levels().contains(&parent) {
                 error!(
-                    "Invalid macro definition at line {}, column {}: {}",
-                    position.line_col().0,
-                    position.line_col().1,
-                    argument
+                    "\\{} '{}' at {} is not inside a \\{}",
+                    section.level.command(),
+                    section.title,
+                    location,
+                    parent.command()
                 );
                 return Err(RustaTexError::InvalidSyntax(format!(
-                    "Invalid macro definition at line {}, column {}: {}",
-                    position.line_col().0,
-                    position.line_col().1,
-                    argument
+                    "\\{} '{}' at {} is not inside a \\{}",
+                    section.level.command(),
+                    section.title,
+                    location,
+                    parent.command()
                 )));
             }
         }
-        "title" => {
-            document.set_title(substitute_macros(argument, document)?);
-            debug!("Set title: {}", argument);
+        self.open.push(section);
+        Ok(())
+    }
+
+    /// Closes the open list item, if any, and opens `item`.
+    fn push_item(&mut self, item: ListItem) {
+        self.close_item();
+        self.item = Some(item);
+    }
+
+    fn close_item(&mut self) {
+        if let Some(item) = self.item.take() {
+            self.push(DocumentElement::ListItem(item));
+        }
+    }
+
+    /// Closes every open heading at `level` or deeper.
+    fn close(&mut self, level: SectionLevel) {
+        while self
+            .open
+            .last()
+            .is_some_and(|section| section.level >= level)
+        {
+            if let Some(section) = self.open.pop() {
+                self.push(DocumentElement::Section(section));
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<DocumentElement> {
+        self.close_item();
+        while let Some(section) = self.open.pop() {
+            self.push(DocumentElement::Section(section));
         }
-        "author" => {
-            document.set_author(substitute_macros(argument, document)?);
-            debug!("Set author: {}", argument);
+        self.elements
+    }
+}
+
+/// Adds a paragraph built from `content` unless it is empty.
+fn push_paragraph(tree: &mut SectionTree, mut content: Vec<Inline>) {
+    trim_inlines(&mut content);
+    if !content.is_empty() {
+        tree.push(DocumentElement::Paragraph(Paragraph {
+            content,
+            style: None,
+        }));
+    }
+}
+
+/// Appends source text to inline content. Whitespace (including single newlines) is folded
+/// into single spaces, escapes are resolved and `\\` becomes a line break.
+fn push_text(content: &mut Vec<Inline>, text: &str) {
+    for (index, line) in split_hard_breaks(text).into_iter().enumerate() {
+        if index > 0 {
+            content.push(Inline::LineBreak);
         }
-        "date" => {
-            document.set_date(substitute_macros(argument, document)?);
-            debug!("Set date: {}", argument);
+        let line = collapse_whitespace(&unescape(line));
+        if line.is_empty() {
+            continue;
         }
-        // Add more commands as needed
-        _ => {
-            error!(
-                "Unknown command '{}' at line {}, column {}",
-                command,
-                position.line_col().0,
-                position.line_col().1
-            );
-            return Err(RustaTexError::UnknownCommand(format!(
-                "{} at line {}, column {}",
-                command,
-                position.line_col().0,
-                position.line_col().1
-            )));
+        match content.last_mut() {
+            Some(Inline::Text(previous)) => {
+                if previous.ends_with(' ') && line.starts_with(' ') {
+                    previous.push_str(&line[1..]);
+                } else {
+                    previous.push_str(&line);
+                }
+            }
+            _ => content.push(Inline::Text(line)),
+        }
+    }
+}
+
+/// Replaces every run of whitespace with a single space.
+fn collapse_whitespace(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut in_whitespace = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !in_whitespace {
+                result.push(' ');
+            }
+            in_whitespace = true;
+        } else {
+            result.push(c);
+            in_whitespace = false;
+        }
+    }
+    result
+}
+
+/// Removes whitespace at the start and end of a paragraph and around line breaks,
+/// and drops trailing line breaks.
+fn trim_inlines(content: &mut Vec<Inline>) {
+    for index in 0..content.len() {
+        let after_break = index == 0 || content[index - 1] == Inline::LineBreak;
+        let before_break = index + 1 == content.len() || content[index + 1] == Inline::LineBreak;
+        if let Inline::Text(text) = &mut content[index] {
+            if after_break {
+                *text = text.trim_start().to_string();
+            }
+            if before_break {
+                *text = text.trim_end().to_string();
+            }
         }
     }
+    content.retain(|inline| !matches!(inline, Inline::Text(text) if text.is_empty()));
+    while content.last() == Some(&Inline::LineBreak) {
+        content.pop();
+    }
+}
 
-    Ok(())
+/// Splits text at each `\\`, skipping over other escaped characters.
+fn split_hard_breaks(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for token in tokenize(text) {
+        if token.kind == TokenKind::ControlSymbol && token.name(text) == "\\" {
+            lines.push(&text[start..token.start]);
+            start = token.end;
+        }
+    }
+    lines.push(&text[start..]);
+    lines
 }
 
-/// Parses a paragraph and returns a Paragraph struct.
-fn parse_paragraph(pair: Pair<Rule>) -> Result<Paragraph, RustaTexError> {
-    let text = pair.as_str().trim().to_string();
-    Ok(Paragraph { text, style: None })
+/// Resolves escaped characters (`\{`, `\}`, `\\`, `\%`, `\&`, `\$`) into their
+/// literal form, drops the braces of nested groups and strips `%` comments.
+pub(crate) fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for token in tokenize(text) {
+        match token.kind {
+            TokenKind::ControlSymbol
+                if matches!(token.name(text), "{" | "}" | "\\" | "%" | "&" | "$") =>
+            {
+                result.push_str(token.name(text))
+            }
+            TokenKind::BeginGroup | TokenKind::EndGroup | TokenKind::Comment => (),
+            _ => result.push_str(token.text(text)),
+        }
+    }
+    result
 }
 
-/// Substitutes macros within the given text using the Document's macro definitions.
-fn substitute_macros(text: &str, document: &Document) -> Result<String, RustaTexError> {
-    let re = Regex::new(r"\\(\w+)").unwrap();
-    let result = re.replace_all(text, |caps: &regex::Captures| {
-        let macro_name = &caps[1];
-        match document.get_macro(macro_name) {
-            Some(val) => val.clone(),
-            None => caps[0].to_string(), 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_nested_braces_and_escapes_in_arguments() {
+        let document = parse_input("\\textbf{a {b} \\} c} and 50\\% \\& \\$5").unwrap();
+        let DocumentElement::Paragraph(paragraph) = &document.elements[0] else {
+            panic!("expected a paragraph: {:?}", document.elements);
+        };
+        assert_eq!(
+            paragraph.content,
+            vec![
+                Inline::Strong(vec![Inline::Text("a b } c".to_string())]),
+                Inline::Text(" and 50% & $5".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn collects_optional_and_mandatory_argument_groups() {
+        let mut parser = Parser::default();
+        parser.register("show", |command: &Command, _: &mut CommandContext| {
+            Ok(CommandOutput::Inline(Inline::Text(format!(
+                "{:?} {:?} {:?}",
+                command.optional_args,
+                command.args,
+                command.options.get("width")
+            ))))
+        });
+        let document = parser
+            .parse("\\show[width=2cm]{a}[x, {y, z}]{b{c}}")
+            .unwrap();
+        let DocumentElement::Paragraph(paragraph) = &document.elements[0] else {
+            panic!("expected a paragraph: {:?}", document.elements);
+        };
+        assert_eq!(
+            paragraph.plain_text(),
+            "[\"width=2cm\", \"x, {y, z}\"] [\"a\", \"b{c}\"] Some(Length(20.0))"
+        );
+    }
+
+    #[test]
+    fn parses_environments_and_checks_their_names() {
+        let document =
+            parse_input("\\begin{quote}[style=plain]{x}\nSome text.\n\\end{quote}").unwrap();
+        let DocumentElement::Environment(environment) = &document.elements[0] else {
+            panic!("expected an environment: {:?}", document.elements);
+        };
+        assert_eq!(environment.name, "quote");
+        assert_eq!(environment.args, vec!["x".to_string()]);
+        assert_eq!(
+            environment.options.get("style"),
+            Some(&OptionValue::Text("plain".to_string()))
+        );
+        assert_eq!(environment.elements.len(), 1);
+
+        match parse_input("\\begin{quote}x\\end{center}") {
+            Err(RustaTexError::InvalidSyntax(message)) => {
+                assert!(message.contains("is closed by 'center'"), "{}", message)
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn drops_comments() {
+        let document = parse_input(
+            "one % a comment\ntwo \\begin{comment}\nhidden\n\\end{comment} three 100\\%",
+        )
+        .unwrap();
+        let DocumentElement::Paragraph(paragraph) = &document.elements[0] else {
+            panic!("expected a paragraph: {:?}", document.elements);
+        };
+        assert_eq!(paragraph.plain_text(), "one two three 100%");
+    }
+
+    #[test]
+    fn splits_paragraphs_and_normalises_whitespace() {
+        let document =
+            parse_input("  First   line\n  continues. \\\\  Next\tline.\n\n \n\nSecond.").unwrap();
+        let paragraphs: Vec<&Vec<Inline>> = document
+            .elements
+            .iter()
+            .map(|element| match element {
+                DocumentElement::Paragraph(paragraph) => &paragraph.content,
+                element => panic!("expected a paragraph: {:?}", element),
+            })
+            .collect();
+        assert_eq!(
+            paragraphs,
+            vec![
+                &vec![
+                    Inline::Text("First line continues.".to_string()),
+                    Inline::LineBreak,
+                    Inline::Text("Next line.".to_string()),
+                ],
+                &vec![Inline::Text("Second.".to_string())],
+            ]
+        );
+    }
+
+    #[test]
+    fn normalises_text_helpers() {
+        assert_eq!(collapse_whitespace(" a \n\t b  "), " a b ");
+        assert_eq!(
+            split_hard_breaks("a \\\\ b \\{ c\\\\"),
+            vec!["a ", " b \\{ c", ""]
+        );
+        assert_eq!(unescape("\\{x\\} {y} 5\\% \\\\ % gone"), "{x} y 5% \\ ");
+    }
+
+    #[test]
+    fn reports_unknown_commands() {
+        assert!(matches!(
+            parse_input("\\nosuchcommand{x}"),
+            Err(RustaTexError::UnknownCommand(message)) if message.starts_with("nosuchcommand")
+        ));
+    }
+
+    #[test]
+    fn nests_content_under_headings() {
+        let document = parse_input(
+            "\\chapter{One}\nIntro.\n\n\\section{A}\nText.\n\n\\subsection{A1}\n\n\\section{B}\n\n\\chapter{Two}",
+        )
+        .unwrap();
+        let titles = |elements: &[DocumentElement]| -> Vec<String> {
+            elements
+                .iter()
+                .filter_map(|element| match element {
+                    DocumentElement::Section(section) => Some(section.title.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(titles(&document.elements), ["One", "Two"]);
+        let DocumentElement::Section(one) = &document.elements[0] else {
+            unreachable!();
+        };
+        assert_eq!(one.elements.len(), 3);
+        assert_eq!(titles(&one.elements), ["A", "B"]);
+        let DocumentElement::Section(a) = &one.elements[1] else {
+            panic!("expected section A: {:?}", one.elements[1]);
+        };
+        assert_eq!(titles(&a.elements), ["A1"]);
+
+        assert!(matches!(
+            parse_input("\\chapter{One}\n\n\\subsection{Orphan}"),
+            Err(RustaTexError::InvalidSyntax(message)) if message.contains("is not inside a \\section")
+        ));
+    }
+
+    #[test]
+    fn closes_the_last_chapter_at_the_appendix() {
+        let document = parse_input(
+            "\\chapter{One}\n\n\\section{S}\n\n\\appendix\n\n\\section{Extra}\n\n\\chapter{More}",
+        )
+        .unwrap();
+        let headings: Vec<(Option<String>, usize)> = document
+            .elements
+            .iter()
+            .map(|element| match element {
+                DocumentElement::Section(section) => {
+                    (section.number.clone(), section.elements.len())
+                }
+                element => panic!("expected a heading: {:?}", element),
+            })
+            .collect();
+        assert_eq!(
+            headings,
+            vec![
+                (Some("1".to_string()), 1),
+                (Some("A.1".to_string()), 0),
+                (Some("B".to_string()), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_labels_and_reports_undefined_references() {
+        let document = parse_input(
+            "\\section{A}\\label{a}\n\n\\section*{Aside}\\label{aside}\n\n\\begin{enumerate}\n\\item x\n\\item y\\label{y}\n\\end{enumerate}\n\n\\ref{a}, \\pageref{y}.",
+        )
+        .unwrap();
+        assert_eq!(document.reference_text("a"), "1");
+        assert_eq!(document.reference_text("y"), "2");
+        assert_eq!(document.reference_text("aside"), "??");
+        assert!(matches!(
+            &document.labels["y"].target,
+            LabelTarget::ListItem
+        ));
+
+        for source in ["\\ref{nope}", "\\pageref{nope}"] {
+            assert!(matches!(
+                parse_input(source),
+                Err(RustaTexError::ReferenceError(message)) if message.contains("'nope'")
+            ));
         }
-    });
-    Ok(result.to_string())
+        assert!(matches!(
+            parse_input("\\section{A}\\label{a}\n\n\\section{B}\\label{a}"),
+            Err(RustaTexError::ReferenceError(message)) if message.contains("already defined")
+        ));
+    }
 }
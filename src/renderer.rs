@@ -1,160 +1,1978 @@
 use crate::document::*;
 use crate::errors::RustaTexError;
-use log::debug;
+use crate::fonts::{EmbeddedFont, FontFace, FontFamily, UsedGlyphs, Variant};
+use crate::hyphenation::Hyphenator;
+use crate::linebreak::{break_lines, BreakParams, Item, INFINITY};
+use crate::metrics::{win_ansi, TextMetrics};
+use crate::numbering::NumberFormat;
+use crate::truetype::TrueTypeFont;
+use log::{debug, error, warn};
+use lopdf::{Dictionary, Object, ObjectId, StringFormat};
+use printpdf::indices::PdfPageIndex;
 use printpdf::*;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::File;
-use std::io::BufWriter;
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// The most layout passes made to resolve page numbers in `\pageref` and the table
+/// of contents.
+const MAX_PASSES: usize = 4;
+
+/// The heading of the footnotes, which are drawn at the end of the document.
+const NOTES_TITLE: &str = "Notes";
+
+/// Horizontal space between a list item's marker and its content, in mm.
+const LIST_INDENT: f64 = 8.0;
+
+/// Indentation of each table of contents level below the first, in mm.
+const TOC_INDENT: f64 = 5.0;
+
+/// How much a space in justified text can stretch and shrink, as fractions of its
+/// width.
+const SPACE_STRETCH: f64 = 1.0 / 2.0;
+const SPACE_SHRINK: f64 = 1.0 / 3.0;
+
+/// How much the end of a line of ragged text can stretch, in spaces.
+const RAGGED_STRETCH: f64 = 3.0;
+
+/// The cost of ending a line with a hyphen, as TeX's `\hyphenpenalty`.
+const HYPHEN_PENALTY: f64 = 50.0;
+
+/// The index of the main font family in [`FontFamilies`].
+const MAIN_FAMILY: usize = 0;
+
+/// The index of the monospace font family, which code is drawn in.
+const MONO_FAMILY: usize = 1;
 
 pub fn render_pdf(
     document: &Document,
     output_path: &str,
     config: &RenderConfig,
 ) -> Result<(), RustaTexError> {
-    debug!("Initializing PDF document.");
-    let (doc, page1, layer1) = PdfDocument::new(
-        "RustaTex Document",
-        config.page_width,
-        config.page_height,
-        "Layer 1",
-    );
-    let mut current_layer = doc.get_page(page1).get_layer(layer1);
-
-    let font = doc.add_builtin_font(BuiltinFont::TimesRoman).unwrap();
-
-    let mut y_position = config.start_y;
-
-    // Render metadata
-    if let Some(title) = &document.title {
-        current_layer.use_text(
-            title,
-            config.title_font_size,
-            Mm(config.margin_left),
-            Mm(y_position),
-            &font,
+    let pdf = render(document, config)?;
+    debug!("Saving PDF to {}", output_path);
+    File::create(output_path)?.write_all(&pdf)?;
+    Ok(())
+}
+
+/// Lays out `document` and returns the PDF file.
+fn render(document: &Document, config: &RenderConfig) -> Result<Vec<u8>, RustaTexError> {
+    let families = FontFamilies::load(document, config)?;
+    let hyphenator = match config.hyphenation {
+        true => Some(load_hyphenator(document, config)?),
+        false => None,
+    };
+
+    // Page numbers are only known once the pages are laid out, and filling them in
+    // can move text to other pages, so lay out again until the pages settle.
+    let mut pages = PageNumbers::default();
+    let mut pass = 1;
+    let state = loop {
+        debug!("Layout pass {}.", pass);
+        let state = RenderState::render(document, config, &pages, &families, hyphenator.as_ref())?;
+        let found = state.page_numbers();
+        if !state.needs_pages || found == pages || pass == MAX_PASSES {
+            break state;
+        }
+        pages = found;
+        pass += 1;
+    };
+    for what in &state.unresolved {
+        warn!("Could not resolve {}; it is printed as ??", what);
+    }
+
+    // Links within the document are left out if their target was not drawn.
+    let links: Vec<(LinkArea, Option<Anchor>)> = state
+        .links
+        .iter()
+        .filter_map(|link| match &link.destination {
+            Destination::Uri(_) => Some((link.clone(), None)),
+            destination => Some((link.clone(), Some(state.anchor(destination)?))),
+        })
+        .collect();
+    let mut outline = Vec::new();
+    if config.bookmarks {
+        outline_entries(&document.elements, &state.headings, &mut 0, &mut outline);
+    }
+    let mut pdf = Vec::new();
+    state
+        .doc
+        .save(&mut BufWriter::new(&mut pdf))
+        .map_err(|e| RustaTexError::RenderError(e.to_string()))?;
+    let labels = state.numbering.needs_labels();
+    let mut subsets = Vec::new();
+    for font in &state.fonts {
+        subsets.extend(font.subset()?);
+    }
+    let watermark = config
+        .watermark
+        .as_ref()
+        .filter(|_| !state.watermarked.is_empty())
+        .map(|watermark| (watermark.opacity, state.watermarked.as_slice()));
+    let numbering = if labels { Some(&state.numbering) } else { None };
+    finish_pdf(&pdf, &links, &outline, numbering, &subsets, watermark)
+        .map_err(|e| RustaTexError::RenderError(e.to_string()))
+}
+
+/// Adds what printpdf cannot write itself, link annotations, a nested outline, page
+/// labels and the watermark opacity, to the saved `pdf`, and replaces embedded fonts
+/// with their subsets. `watermark` gives the opacity and the indices of the pages the
+/// watermark is drawn on.
+fn finish_pdf(
+    pdf: &[u8],
+    links: &[(LinkArea, Option<Anchor>)],
+    outline: &[OutlineEntry],
+    numbering: Option<&PageNumbering>,
+    subsets: &[FontSubset],
+    watermark: Option<(f64, &[usize])>,
+) -> Result<Vec<u8>, lopdf::Error> {
+    let mut pdf = lopdf::Document::load_mem(pdf)?;
+    narrow_builtin_codes(&mut pdf)?;
+    add_font_subsets(&mut pdf, subsets)?;
+    if let Some((opacity, pages)) = watermark {
+        set_watermark_opacity(&mut pdf, opacity, pages)?;
+    }
+    add_links(&mut pdf, links)?;
+    if !outline.is_empty() {
+        add_outline(&mut pdf, outline)?;
+    }
+    if let Some(numbering) = numbering {
+        add_page_labels(&mut pdf, numbering)?;
+    }
+    let mut output = Vec::new();
+    pdf.save_to(&mut output)?;
+    Ok(output)
+}
+
+/// The glyphs of an embedded font that were drawn, for replacing the font file
+/// printpdf embeds whole.
+struct FontSubset {
+    font: Rc<TrueTypeFont>,
+    /// The subset font file.
+    data: Vec<u8>,
+    /// The PostScript name of the subset, with a tag that tells it from other subsets
+    /// of the same font.
+    name: String,
+}
+
+/// Where a link target is drawn: a page index and the top of the target in mm.
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    page: usize,
+    y: f64,
+}
+
+/// What a link jumps to.
+#[derive(Debug, Clone)]
+enum Destination {
+    /// The target of a label.
+    Label(String),
+    /// A heading, by its position among all headings in document order.
+    Heading(usize),
+    /// A web address, opened outside the document.
+    Uri(String),
+    /// A footnote, by its position among all footnotes in document order.
+    Footnote(usize),
+}
+
+/// An entry of the PDF outline, with the entries nested under it.
+#[derive(Debug)]
+struct OutlineEntry {
+    title: String,
+    anchor: Anchor,
+    children: Vec<OutlineEntry>,
+}
+
+/// A clickable area of a page.
+#[derive(Debug, Clone)]
+struct LinkArea {
+    page: usize,
+    /// Left, bottom, right and top edges in mm.
+    rect: [f64; 4],
+    destination: Destination,
+}
+
+/// The page indices found by a layout pass.
+#[derive(Debug, Default, PartialEq)]
+struct PageNumbers {
+    labels: HashMap<String, usize>,
+    /// The page of each heading in document order.
+    headings: Vec<usize>,
+    /// How the pages are numbered.
+    numbering: PageNumbering,
+}
+
+/// How pages are numbered: from the first page of each range on, in the range's
+/// format and counting from its first number. Without ranges pages are numbered 1, 2, 3.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct PageNumbering {
+    ranges: Vec<PageRange>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PageRange {
+    /// The index of the first page.
+    start: usize,
+    format: NumberFormat,
+    /// The number of the first page.
+    first: u32,
+}
+
+impl PageNumbering {
+    fn new(format: NumberFormat) -> Self {
+        PageNumbering {
+            ranges: vec![PageRange {
+                start: 0,
+                format,
+                first: 1,
+            }],
+        }
+    }
+
+    /// Numbers the pages from `page` on in `format`, starting at `first`, or
+    /// continuing the count if it is `None`.
+    fn start(&mut self, page: usize, format: NumberFormat, first: Option<u32>) {
+        let first = first.unwrap_or_else(|| self.number(page));
+        self.ranges.retain(|range| range.start < page);
+        self.ranges.push(PageRange {
+            start: page,
+            format,
+            first,
+        });
+    }
+
+    fn range(&self, page: usize) -> Option<&PageRange> {
+        self.ranges.iter().rev().find(|range| range.start <= page)
+    }
+
+    fn number(&self, page: usize) -> u32 {
+        match self.range(page) {
+            Some(range) => range.first + (page - range.start) as u32,
+            None => page as u32 + 1,
+        }
+    }
+
+    /// Returns the number printed for the page at `page`.
+    fn label(&self, page: usize) -> String {
+        let format = self
+            .range(page)
+            .map_or(NumberFormat::Arabic, |range| range.format);
+        format.format(self.number(page))
+    }
+
+    /// Whether viewers would show other numbers than the page indices without page
+    /// labels.
+    fn needs_labels(&self) -> bool {
+        match self.ranges.as_slice() {
+            [] => false,
+            [range] => range.format != NumberFormat::Arabic || range.first != 1,
+            _ => true,
+        }
+    }
+}
+
+/// The layout position and what has been drawn during one rendering pass.
+struct RenderState<'a> {
+    document: &'a Document,
+    config: &'a RenderConfig,
+    /// The pages found by the previous pass, for `\pageref` and the table of contents.
+    pages: &'a PageNumbers,
+    /// How the pages drawn so far are numbered.
+    numbering: PageNumbering,
+    families: &'a FontFamilies,
+    doc: PdfDocumentReference,
+    layer: PdfLayerReference,
+    /// The fonts added to the PDF, the regular face of the main family first.
+    fonts: Vec<PdfFont>,
+    /// The index of the current page.
+    page: usize,
+    y_position: f64,
+    /// Where content starts on the current page, below the title on the first page.
+    content_top: f64,
+    /// Where the target of each label is drawn.
+    anchors: HashMap<String, Anchor>,
+    /// Where each heading is drawn, in document order.
+    headings: Vec<Anchor>,
+    links: Vec<LinkArea>,
+    /// Whether any page number from the previous pass was drawn, which requires
+    /// another pass.
+    needs_pages: bool,
+    /// The number and text of each footnote met so far, in document order.
+    footnotes: Vec<(u32, Vec<Inline>)>,
+    /// Where the text of each footnote is drawn.
+    notes: Vec<Anchor>,
+    /// What was drawn as `??` because it could not be resolved.
+    unresolved: BTreeSet<String>,
+    /// The layer of every page, for drawing headers and footers once all pages exist.
+    layers: Vec<PdfLayerReference>,
+    /// Every page, for adding the watermark layer once all pages exist.
+    page_indices: Vec<PdfPageIndex>,
+    /// The pages the watermark is drawn on, whose `WATERMARK_STATE` gets its opacity.
+    watermarked: Vec<usize>,
+    /// The chapter and section headings with the page each starts on, for `{section}`.
+    sections: Vec<(usize, String)>,
+    /// How paragraphs are aligned where they do not choose for themselves.
+    alignment: Alignment,
+    /// Where words may be hyphenated, if they may.
+    hyphenator: Option<&'a Hyphenator>,
+}
+
+impl<'a> RenderState<'a> {
+    /// Lays out and draws the whole document into a new PDF.
+    fn render(
+        document: &'a Document,
+        config: &'a RenderConfig,
+        pages: &'a PageNumbers,
+        families: &'a FontFamilies,
+        hyphenator: Option<&'a Hyphenator>,
+    ) -> Result<Self, RustaTexError> {
+        debug!("Initializing PDF document.");
+        let (doc, page1, layer1) = PdfDocument::new(
+            "RustaTex Document",
+            config.page_width,
+            config.page_height,
+            "Layer 1",
         );
-        debug!("Rendered title: {}", title);
-        y_position -= config.title_font_size + 10.0;
-    }
-    if let Some(author) = &document.author {
-        current_layer.use_text(
-            format!("Author: {}", author),
-            config.font_size,
-            Mm(config.margin_left),
-            Mm(y_position),
-            &font,
+        let layer = doc.get_page(page1).get_layer(layer1);
+        let font = PdfFont::new(&doc, families.main().face(Variant::Regular))?;
+        let mut state = RenderState {
+            document,
+            config,
+            pages,
+            numbering: PageNumbering::new(config.page_numbering),
+            families,
+            doc,
+            layer,
+            fonts: vec![font],
+            page: 0,
+            y_position: config.start_y,
+            content_top: config.start_y,
+            anchors: HashMap::new(),
+            headings: Vec::new(),
+            links: Vec::new(),
+            needs_pages: false,
+            footnotes: Vec::new(),
+            notes: Vec::new(),
+            unresolved: BTreeSet::new(),
+            layers: Vec::new(),
+            page_indices: vec![page1],
+            watermarked: Vec::new(),
+            sections: Vec::new(),
+            alignment: config.alignment,
+            hyphenator,
+        };
+        state.layers.push(state.layer.clone());
+        state.render_metadata();
+        state.content_top = state.y_position;
+        // The generated table of contents belongs to the front matter if the document
+        // starts with it.
+        let leading = match document.elements.first() {
+            Some(DocumentElement::Matter(_)) => 1,
+            _ => 0,
+        };
+        let (leading, rest) = document.elements.split_at(leading);
+        state.render_elements(leading, 0.0)?;
+        if config.table_of_contents && !contains_table_of_contents(&document.elements) {
+            state.render_table_of_contents(&TableOfContents::default(), 0.0);
+        }
+        state.render_elements(rest, 0.0)?;
+        state.render_notes()?;
+        state.render_headers_and_footers();
+        state.render_watermark();
+        Ok(state)
+    }
+
+    fn page_numbers(&self) -> PageNumbers {
+        PageNumbers {
+            labels: self
+                .anchors
+                .iter()
+                .map(|(key, anchor)| (key.clone(), anchor.page))
+                .collect(),
+            headings: self.headings.iter().map(|anchor| anchor.page).collect(),
+            numbering: self.numbering.clone(),
+        }
+    }
+
+    fn anchor(&self, destination: &Destination) -> Option<Anchor> {
+        match destination {
+            Destination::Label(key) => self.anchors.get(key).copied(),
+            Destination::Heading(index) => self.headings.get(*index).copied(),
+            Destination::Footnote(index) => self.notes.get(*index).copied(),
+            Destination::Uri(_) => None,
+        }
+    }
+
+    fn render_metadata(&mut self) {
+        let config = self.config;
+        if let Some(title) = &self.document.title {
+            self.font().draw(
+                &self.layer,
+                title,
+                config.title_font_size,
+                config.margin_left,
+                self.y_position,
+            );
+            debug!("Rendered title: {}", title);
+            self.y_position -= pt_to_mm(config.title_font_size) + 10.0;
+        }
+        if let Some(author) = &self.document.author {
+            self.font().draw(
+                &self.layer,
+                &format!("Author: {}", author),
+                config.font_size,
+                config.margin_left,
+                self.y_position,
+            );
+            debug!("Rendered author: {}", author);
+            self.y_position -= pt_to_mm(config.font_size) + 5.0;
+        }
+        if let Some(date) = &self.document.date {
+            self.font().draw(
+                &self.layer,
+                &format!("Date: {}", date),
+                config.font_size,
+                config.margin_left,
+                self.y_position,
+            );
+            debug!("Rendered date: {}", date);
+            self.y_position -= pt_to_mm(config.font_size) + 15.0;
+        }
+    }
+
+    /// Renders a list of elements `indent` mm right of the left margin, adding pages as
+    /// the cursor reaches the bottom margin.
+    fn render_elements(
+        &mut self,
+        elements: &[DocumentElement],
+        indent: f64,
+    ) -> Result<(), RustaTexError> {
+        let config = self.config;
+        for element in elements {
+            match element {
+                DocumentElement::Section(section) => {
+                    let (font_size, spacing, heading_indent) = config.heading_style(section.level);
+                    // Keep the heading on the page of its first line.
+                    self.ensure_space(
+                        spacing
+                            + pt_to_mm(font_size)
+                            + config.paragraph_spacing
+                            + pt_to_mm(config.font_size)
+                            + config.line_spacing,
+                    );
+                    self.y_position -= spacing;
+                    let anchor = Anchor {
+                        page: self.page,
+                        y: self.y_position + pt_to_mm(font_size),
+                    };
+                    self.headings.push(anchor);
+                    if section.level <= SectionLevel::Section {
+                        self.sections.push((self.page, section.heading_text()));
+                    }
+                    for key in &section.labels {
+                        self.anchors.insert(key.clone(), anchor);
+                    }
+                    self.font().draw(
+                        &self.layer,
+                        &section.heading_text(),
+                        font_size,
+                        config.margin_left + indent + heading_indent,
+                        self.y_position,
+                    );
+                    debug!("Rendered {}: {}", section.level.command(), section.title);
+                    self.y_position -= pt_to_mm(font_size) + config.paragraph_spacing;
+                    self.render_elements(&section.elements, indent)?;
+                }
+                DocumentElement::Paragraph(paragraph) => {
+                    self.render_paragraph(paragraph, indent)?
+                }
+                DocumentElement::Environment(environment) => {
+                    debug!("Rendering environment: {}", environment.name);
+                    self.add_anchors(&environment.labels, self.y_position);
+                    // Alignment environments and an `align` option set the alignment of
+                    // the paragraphs inside.
+                    let alignment = match environment.name.as_str() {
+                        "center" | "flushleft" | "flushright" | "justify" => {
+                            Alignment::parse(&environment.name)
+                        }
+                        _ => None,
+                    };
+                    let alignment = environment
+                        .options
+                        .get_text("align")
+                        .and_then(|align| Alignment::parse(&align))
+                        .or(alignment)
+                        .unwrap_or(self.alignment);
+                    let outer = std::mem::replace(&mut self.alignment, alignment);
+                    self.render_elements(&environment.elements, indent)?;
+                    self.alignment = outer;
+                }
+                DocumentElement::ListItem(item) => {
+                    // The marker shares the baseline of the item's first line.
+                    self.add_anchors(&item.labels, self.y_position + pt_to_mm(config.font_size));
+                    self.font().draw(
+                        &self.layer,
+                        &item.marker(),
+                        config.font_size,
+                        config.margin_left + indent,
+                        self.y_position,
+                    );
+                    self.render_elements(&item.elements, indent + LIST_INDENT)?;
+                }
+                DocumentElement::TableOfContents(toc) => self.render_table_of_contents(toc, indent),
+                DocumentElement::Matter(matter) => self.start_matter(*matter),
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws the text of the footnotes, each after its number, under a heading at the
+    /// end of the document. Each footnote mark links to its text.
+    fn render_notes(&mut self) -> Result<(), RustaTexError> {
+        if self.footnotes.is_empty() {
+            return Ok(());
+        }
+        let config = self.config;
+        let (title_size, spacing, _) = config.heading_style(SectionLevel::Section);
+        self.ensure_space(
+            spacing
+                + pt_to_mm(title_size)
+                + config.paragraph_spacing
+                + pt_to_mm(config.font_size)
+                + config.line_spacing,
         );
-        debug!("Rendered author: {}", author);
-        y_position -= config.font_size + 5.0;
-    }
-    if let Some(date) = &document.date {
-        current_layer.use_text(
-            format!("Date: {}", date),
-            config.font_size,
-            Mm(config.margin_left),
-            Mm(y_position),
-            &font,
+        self.y_position -= spacing;
+        self.font().draw(
+            &self.layer,
+            NOTES_TITLE,
+            title_size,
+            config.margin_left,
+            self.y_position,
         );
-        debug!("Rendered date: {}", date);
-        y_position -= config.font_size + 15.0;
+        self.y_position -= pt_to_mm(title_size) + config.paragraph_spacing;
+
+        let footnotes = std::mem::take(&mut self.footnotes);
+        for (number, content) in &footnotes {
+            self.notes.push(Anchor {
+                page: self.page,
+                y: self.y_position + pt_to_mm(config.font_size),
+            });
+            let mut content = content.clone();
+            content.insert(0, Inline::Text(format!("{}. ", number)));
+            self.render_paragraph(
+                &Paragraph {
+                    content,
+                    style: None,
+                },
+                0.0,
+            )?;
+        }
+        debug!("Rendered {} footnotes.", footnotes.len());
+        Ok(())
     }
 
-    // Render document elements
-    for element in &document.elements {
-        match element {
-            DocumentElement::Section(sec) => {
-                y_position -= config.section_spacing;
-                current_layer.use_text(
-                    &sec.title,
-                    config.section_font_size,
-                    Mm(config.margin_left),
-                    Mm(y_position),
-                    &font,
-                );
-                debug!("Rendered section: {}", sec.title);
-                y_position -= config.section_font_size + config.paragraph_spacing;
-            }
-            DocumentElement::Subsection(subsec) => {
-                y_position -= config.subsection_spacing;
-                current_layer.use_text(
-                    &subsec.title,
-                    config.subsection_font_size,
-                    Mm(config.margin_left + 10.0),
-                    Mm(y_position),
-                    &font,
+    /// Starts the front, main or back matter on a new page, unless nothing has been
+    /// drawn on the current one, and switches page numbering. Front matter is numbered
+    /// in lower roman from i and main matter in the configured style from 1; back
+    /// matter continues the count of the main matter.
+    fn start_matter(&mut self, matter: Matter) {
+        if self.y_position < self.content_top {
+            self.new_page();
+        }
+        let format = match matter {
+            Matter::Front => NumberFormat::LowerRoman,
+            Matter::Main | Matter::Back => self.config.page_numbering,
+        };
+        let first = match matter {
+            Matter::Front | Matter::Main => Some(1),
+            Matter::Back => None,
+        };
+        self.numbering.start(self.page, format, first);
+        debug!("Started {:?} matter on page {}.", matter, self.page + 1);
+    }
+
+    fn render_paragraph(
+        &mut self,
+        paragraph: &Paragraph,
+        indent: f64,
+    ) -> Result<(), RustaTexError> {
+        let config = self.config;
+        let style = paragraph.style.as_ref();
+        let base = TextStyle {
+            family: style
+                .and_then(|style| style.font.as_deref())
+                .map_or(MAIN_FAMILY, |name| self.families.index(name)),
+            bold: style.is_some_and(|style| style.bold),
+            italic: style.is_some_and(|style| style.italic),
+            size: style
+                .and_then(|style| style.font_size)
+                .map_or(config.font_size, f64::from),
+        };
+        let alignment = style
+            .and_then(|style| style.align)
+            .unwrap_or(self.alignment);
+        let mut text = String::new();
+        let mut spans = Vec::new();
+        let mut links = Vec::new();
+        self.resolve_inlines(&paragraph.content, base, &mut text, &mut spans, &mut links)?;
+
+        let left = config.margin_left + indent;
+        let line_width = config.line_width - indent;
+        let (items, ranges) = self.paragraph_items(&text, &spans, alignment);
+        let params = BreakParams {
+            tolerance: config.tolerance,
+            looseness: config.looseness,
+            ..BreakParams::default()
+        };
+        let mut start = 0;
+        for line_break in break_lines(&items, line_width, &params) {
+            let boxes: Vec<&Range<usize>> = (start..line_break.position)
+                .filter(|&index| matches!(items[index], Item::Box { .. }))
+                .map(|index| &ranges[index])
+                .collect();
+            start = line_break.position + 1;
+            let line = match (boxes.first(), boxes.last()) {
+                (Some(first), Some(last)) => first.start..last.end,
+                _ => ranges[line_break.position].clone(),
+            };
+            // Justified lines stretch or shrink their spaces to fill the width, except
+            // the last line and lines ending in a hard break, which only shrink if
+            // they must.
+            let forced = matches!(
+                items[line_break.position],
+                Item::Penalty { penalty, .. } if penalty <= -INFINITY
+            );
+            let ratio = match alignment {
+                Alignment::Justify if !forced || line_break.ratio < 0.0 => line_break.ratio,
+                _ => 0.0,
+            };
+            // A line broken within a word ends with a hyphen.
+            let (hyphenated, hyphen_width) = match items[line_break.position] {
+                Item::Penalty { width, flagged, .. } => (flagged, width),
+                _ => (false, 0.0),
+            };
+
+            // Lines with larger text than the body text move down to make room for it.
+            let size = spans
+                .iter()
+                .filter(|span| span.range.start < line.end && span.range.end > line.start)
+                .map(|span| span.size)
+                .fold(config.font_size, f64::max);
+            self.y_position -= pt_to_mm(size - config.font_size);
+            let natural = self.spaced_width(&text, &spans, line.clone(), ratio) + hyphen_width;
+            let slack = (line_width - natural).max(0.0);
+            let left = match alignment {
+                Alignment::Justify | Alignment::Left => left,
+                Alignment::Right => left + slack,
+                Alignment::Center => left + slack / 2.0,
+            };
+            let mut x = left;
+            for span in &spans {
+                let piece = span.range.start.max(line.start)..span.range.end.min(line.end);
+                if piece.start >= piece.end {
+                    continue;
+                }
+                let word_spacing = self.word_spacing(span, ratio);
+                let font = &self.fonts[span.font];
+                let mut drawn = text[piece.clone()].to_string();
+                if hyphenated && piece.end == line.end {
+                    drawn.push('-');
+                }
+                font.draw_spaced(
+                    &self.layer,
+                    &drawn,
+                    span.size,
+                    x,
+                    self.y_position,
+                    word_spacing,
                 );
-                debug!("Rendered subsection: {}", subsec.title);
-                y_position -= config.subsection_font_size + config.paragraph_spacing;
-            }
-            DocumentElement::Paragraph(paragraph) => {
-                let lines = wrap_text(
-                    &paragraph.text,
-                    config.line_width,
-                    config.font_size as u32,
-                    &font,
+                x += self.spaced_width(&text, std::slice::from_ref(span), piece, ratio);
+            }
+            for (range, destination) in &links {
+                if range.start >= line.end || range.end <= line.start {
+                    continue;
+                }
+                let width = |end: usize| self.spaced_width(&text, &spans, line.start..end, ratio);
+                let height = pt_to_mm(size);
+                self.links.push(LinkArea {
+                    page: self.page,
+                    rect: [
+                        left + width(range.start.max(line.start)),
+                        self.y_position - 0.25 * height,
+                        left + width(range.end.min(line.end)),
+                        self.y_position + height,
+                    ],
+                    destination: destination.clone(),
+                });
+            }
+            let hyphen = if hyphenated { "-" } else { "" };
+            debug!("Rendered paragraph line: {}{}", &text[line], hyphen);
+            self.next_line();
+        }
+        self.y_position -= config.paragraph_spacing;
+        Ok(())
+    }
+
+    /// Turns a paragraph's `text`, drawn as `spans`, into the items the line breaker
+    /// sees, with the byte range of `text` each one stands for. Words are boxes and
+    /// the spaces between them glue, which stretches and shrinks in justified text
+    /// and otherwise only lets lines end short. Each `\n` and the end of the paragraph
+    /// force a break.
+    fn paragraph_items(
+        &self,
+        text: &str,
+        spans: &[Span],
+        alignment: Alignment,
+    ) -> (Vec<Item>, Vec<Range<usize>>) {
+        let mut items = Vec::new();
+        let mut ranges = Vec::new();
+        let mut segment_start = 0;
+        for segment in text.split('\n') {
+            let mut previous: Option<(Range<usize>, f64)> = None;
+            for word in segment.split_whitespace() {
+                let start = word.as_ptr() as usize - text.as_ptr() as usize;
+                let range = start..start + word.len();
+                let width = self.spans_width(text, spans, range.clone());
+                if let Some((previous, previous_width)) = previous {
+                    let gap = previous.end..range.start;
+                    // The space takes up whatever kerning the words around it add.
+                    let space = self.spans_width(text, spans, previous.start..range.end)
+                        - previous_width
+                        - width;
+                    let spaces: f64 = text[gap.clone()]
+                        .char_indices()
+                        .filter(|&(_, c)| c == ' ')
+                        .filter_map(|(index, _)| {
+                            let span = spans
+                                .iter()
+                                .find(|span| span.range.contains(&(gap.start + index)))?;
+                            Some(self.fonts[span.font].width(" ", span.size))
+                        })
+                        .sum();
+                    match alignment {
+                        Alignment::Justify => {
+                            items.push(Item::Glue {
+                                width: space,
+                                stretch: spaces * SPACE_STRETCH,
+                                shrink: spaces * SPACE_SHRINK,
+                            });
+                            ranges.push(gap);
+                        }
+                        // Glue that only stretches at the end of a line, as in Knuth and
+                        // Plass's ragged-right setting, so that lines end unevenly but
+                        // not much more than they must.
+                        _ => {
+                            items.extend([
+                                Item::Glue {
+                                    width: 0.0,
+                                    stretch: RAGGED_STRETCH * space,
+                                    shrink: 0.0,
+                                },
+                                Item::Penalty {
+                                    width: 0.0,
+                                    penalty: 0.0,
+                                    flagged: false,
+                                },
+                                Item::Glue {
+                                    width: space,
+                                    stretch: -RAGGED_STRETCH * space,
+                                    shrink: 0.0,
+                                },
+                            ]);
+                            ranges.extend([gap.start..gap.start, gap.start..gap.start, gap]);
+                        }
+                    }
+                }
+                // A word that may be hyphenated is a box for each part, with a flagged
+                // penalty as wide as the hyphen between them.
+                let mut part_start = range.start;
+                let mut part_offset = 0.0;
+                for hyphen in self.hyphens(text, range.clone()) {
+                    let offset = self.spans_width(text, spans, range.start..hyphen);
+                    items.extend([
+                        Item::Box {
+                            width: offset - part_offset,
+                        },
+                        Item::Penalty {
+                            width: self.hyphen_width(text, spans, hyphen),
+                            penalty: HYPHEN_PENALTY,
+                            flagged: true,
+                        },
+                    ]);
+                    ranges.extend([part_start..hyphen, hyphen..hyphen]);
+                    part_start = hyphen;
+                    part_offset = offset;
+                }
+                items.push(Item::Box {
+                    width: width - part_offset,
+                });
+                ranges.push(part_start..range.end);
+                previous = Some((range, width));
+            }
+            let segment_end = segment_start + segment.len();
+            // An empty box keeps the empty lines of consecutive hard breaks.
+            if previous.is_none() {
+                items.push(Item::Box { width: 0.0 });
+                ranges.push(segment_end..segment_end);
+            }
+            // The line ends with glue that fills it, and must break after it.
+            items.extend([
+                Item::Penalty {
+                    width: 0.0,
+                    penalty: INFINITY,
+                    flagged: false,
+                },
+                Item::Glue {
+                    width: 0.0,
+                    stretch: INFINITY,
+                    shrink: 0.0,
+                },
+                Item::Penalty {
+                    width: 0.0,
+                    penalty: -INFINITY,
+                    flagged: false,
+                },
+            ]);
+            ranges.extend([
+                segment_end..segment_end,
+                segment_end..segment_end,
+                segment_end..segment_end,
+            ]);
+            segment_start = segment_end + 1;
+        }
+        (items, ranges)
+    }
+
+    /// Returns the byte offsets in `text` where the word at `range` may be hyphenated.
+    /// Punctuation around the word is left out, and words with other characters than
+    /// letters and apostrophes are not hyphenated.
+    fn hyphens(&self, text: &str, range: Range<usize>) -> Vec<usize> {
+        let Some(hyphenator) = self.hyphenator else {
+            return Vec::new();
+        };
+        let word = &text[range.clone()];
+        let mut letters = word.char_indices().filter(|(_, c)| c.is_alphabetic());
+        let Some((start, first)) = letters.next() else {
+            return Vec::new();
+        };
+        let end = letters
+            .next_back()
+            .map_or(start + first.len_utf8(), |(end, c)| end + c.len_utf8());
+        let core = &word[start..end];
+        if !core
+            .chars()
+            .all(|c| c.is_alphabetic() || c == '\'' || c == '\u{2019}')
+        {
+            return Vec::new();
+        }
+        hyphenator
+            .hyphenate(core)
+            .into_iter()
+            .map(|offset| range.start + start + offset)
+            .collect()
+    }
+
+    /// Returns how much wider in mm a paragraph's `text` is with a hyphen at `at`, in
+    /// the font of the character before it.
+    fn hyphen_width(&self, text: &str, spans: &[Span], at: usize) -> f64 {
+        let Some((index, c)) = text[..at].char_indices().next_back() else {
+            return 0.0;
+        };
+        let Some(span) = spans.iter().find(|span| span.range.contains(&index)) else {
+            return 0.0;
+        };
+        let font = &self.fonts[span.font];
+        let last = c.to_string();
+        font.width(&format!("{}-", last), span.size) - font.width(&last, span.size)
+    }
+
+    /// Returns the width in mm of `range` of a paragraph's `text`, which is drawn as
+    /// `spans`.
+    fn spans_width(&self, text: &str, spans: &[Span], range: Range<usize>) -> f64 {
+        spans
+            .iter()
+            .map(|span| {
+                let piece = span.range.start.max(range.start)..span.range.end.min(range.end);
+                match piece.start < piece.end {
+                    true => self.fonts[span.font].width(&text[piece], span.size),
+                    false => 0.0,
+                }
+            })
+            .sum()
+    }
+
+    /// Returns the width in mm of `range` like [`Self::spans_width`], on a line whose
+    /// spaces are stretched or shrunk by `ratio`.
+    fn spaced_width(&self, text: &str, spans: &[Span], range: Range<usize>, ratio: f64) -> f64 {
+        let spacing: f64 = spans
+            .iter()
+            .map(|span| {
+                let piece = span.range.start.max(range.start)..span.range.end.min(range.end);
+                match piece.start < piece.end {
+                    true => {
+                        let spaces = text[piece].matches(' ').count();
+                        spaces as f64 * self.word_spacing(span, ratio)
+                    }
+                    false => 0.0,
+                }
+            })
+            .sum();
+        self.spans_width(text, spans, range) + spacing
+    }
+
+    /// Returns how much wider in mm each space of `span` is drawn on a line whose
+    /// spaces are stretched or, if `ratio` is negative, shrunk by `ratio` of what they
+    /// can.
+    fn word_spacing(&self, span: &Span, ratio: f64) -> f64 {
+        let factor = if ratio > 0.0 {
+            SPACE_STRETCH
+        } else {
+            SPACE_SHRINK
+        };
+        ratio * factor * self.fonts[span.font].width(" ", span.size)
+    }
+
+    /// Lists the headings up to `toc.depth`, each with dotted leaders to its page
+    /// number and linking to the heading.
+    fn render_table_of_contents(&mut self, toc: &TableOfContents, indent: f64) {
+        let config = self.config;
+        let font_size = config.font_size;
+        self.needs_pages = true;
+
+        let (title_size, spacing, _) = config.heading_style(SectionLevel::Section);
+        self.y_position -= spacing;
+        self.font().draw(
+            &self.layer,
+            &toc.title,
+            title_size,
+            config.margin_left + indent,
+            self.y_position,
+        );
+        self.y_position -= pt_to_mm(title_size) + config.paragraph_spacing;
+
+        let mut headings = Vec::new();
+        collect_headings(&self.document.elements, &mut headings);
+        let listed = |section: &Section| section.in_toc && section.level <= toc.depth;
+        let Some(top) = headings
+            .iter()
+            .filter(|section| listed(section))
+            .map(|section| section.level)
+            .min()
+        else {
+            return;
+        };
+
+        let right = config.margin_left + config.line_width;
+        let dots_width = self.font().width(". ", font_size);
+        for (index, section) in headings.iter().enumerate() {
+            if !listed(section) {
+                continue;
+            }
+            let left = config.margin_left
+                + indent
+                + TOC_INDENT * (section.level.index() - top.index()) as f64;
+            let text = section.heading_text();
+            let page = match self.pages.headings.get(index) {
+                Some(&page) => self.page_label(page),
+                None => {
+                    self.unresolved
+                        .insert(format!("the page of '{}' in the table of contents", text));
+                    "??".to_string()
+                }
+            };
+            let page_x = right - self.font().width(&page, font_size);
+            self.font()
+                .draw(&self.layer, &text, config.font_size, left, self.y_position);
+            self.font().draw(
+                &self.layer,
+                &page,
+                config.font_size,
+                page_x,
+                self.y_position,
+            );
+
+            // Leaders end at the same place on every line so that their dots line up.
+            let text_end = left + self.font().width(&text, font_size);
+            let leader_end = right - self.font().width("000", font_size);
+            let dots = ((leader_end - text_end) / dots_width - 1.0).floor();
+            if dots >= 1.0 {
+                let dots = dots as usize;
+                self.font().draw(
+                    &self.layer,
+                    &". ".repeat(dots),
+                    config.font_size,
+                    leader_end - dots as f64 * dots_width,
+                    self.y_position,
                 );
-                for line in lines {
-                    current_layer.use_text(
-                        &line,
-                        config.font_size,
-                        Mm(config.margin_left),
-                        Mm(y_position),
-                        &font,
-                    );
-                    y_position -= config.font_size + config.line_spacing;
-                    debug!("Rendered paragraph line: {}", line);
-                    if y_position < config.bottom_margin {
-                        // Add new page if necessary
-                        let (new_page, new_layer) =
-                            doc.add_page(config.page_width, config.page_height, "Layer 1");
-                        current_layer = doc.get_page(new_page).get_layer(new_layer);
-                        y_position = config.start_y;
-                        debug!("Added new page.");
+            }
+
+            let height = pt_to_mm(config.font_size);
+            self.links.push(LinkArea {
+                page: self.page,
+                rect: [
+                    left,
+                    self.y_position - 0.25 * height,
+                    right,
+                    self.y_position + height,
+                ],
+                destination: Destination::Heading(index),
+            });
+            debug!("Rendered table of contents entry: {}", text);
+            self.next_line();
+        }
+        self.y_position -= config.paragraph_spacing;
+    }
+
+    /// Returns the number printed for the page at `index` in the previous pass.
+    fn page_label(&self, index: usize) -> String {
+        self.pages.numbering.label(index)
+    }
+
+    /// Draws the header and footer templates on every page.
+    fn render_headers_and_footers(&mut self) {
+        let config = self.config;
+        if config.header.is_none() && config.footer.is_none() {
+            return;
+        }
+        let date = match &self.document.date {
+            Some(date) => date.clone(),
+            None => OffsetDateTime::now_utc().format("%Y-%m-%d"),
+        };
+        let pages = config.page_numbering.format(self.layers.len() as u32);
+        let title = self.document.title.clone().unwrap_or_default();
+
+        for (page, layer) in self.layers.iter().enumerate() {
+            // The section of a page is the first one starting on it, or else the last
+            // one started before it.
+            let section = self
+                .sections
+                .iter()
+                .find(|(start, _)| *start == page)
+                .or_else(|| self.sections.iter().rev().find(|(start, _)| *start < page))
+                .map(|(_, heading)| heading.as_str())
+                .unwrap_or_default();
+            let fill = |text: &str| {
+                text.replace("{page}", &self.numbering.label(page))
+                    .replace("{pages}", &pages)
+                    .replace("{title}", &title)
+                    .replace("{section}", section)
+                    .replace("{date}", &date)
+            };
+            let header_y = config.page_height.0
+                - config
+                    .header_offset
+                    .unwrap_or((config.page_height.0 - config.start_y) / 2.0);
+            let footer_y = config.footer_offset.unwrap_or(config.bottom_margin / 2.0);
+            for (template, y) in [(&config.header, header_y), (&config.footer, footer_y)] {
+                let Some(template) = template else {
+                    continue;
+                };
+                let slots = [
+                    (fill(&template.left), 0.0),
+                    (fill(&template.center), 0.5),
+                    (fill(&template.right), 1.0),
+                ];
+                for (text, align) in slots {
+                    if text.is_empty() {
+                        continue;
                     }
+                    let width = self.font().width(&text, config.header_font_size);
+                    let x = config.margin_left + (config.line_width - width) * align;
+                    self.font()
+                        .draw(layer, &text, config.header_font_size, x, y);
                 }
-                y_position -= config.paragraph_spacing;
             }
         }
+        debug!(
+            "Rendered headers and footers on {} pages.",
+            self.layers.len()
+        );
     }
 
-    debug!("Saving PDF to {}", output_path);
-    doc.save(&mut BufWriter::new(File::create(output_path)?))
-        .map_err(|e| RustaTexError::RenderError(e.to_string()))?;
+    /// Draws the watermark on its own layer above the content of each selected page.
+    /// The text is drawn with the watermark opacity, so the content shows through.
+    fn render_watermark(&mut self) {
+        let config = self.config;
+        let Some(watermark) = &config.watermark else {
+            return;
+        };
+        let [r, g, b] = watermark.color;
+        let width = self.font().width(&watermark.text, watermark.font_size);
+        let height = pt_to_mm(watermark.font_size);
+
+        for (page, &index) in self.page_indices.iter().enumerate() {
+            if !watermark
+                .pages
+                .as_ref()
+                .is_none_or(|pages| pages.contains(page))
+            {
+                continue;
+            }
+            let layer = self.doc.get_page(index).add_layer("Watermark");
+            layer.save_graphics_state();
+            // This adds `WATERMARK_STATE`, whose opacity is set when the PDF is finished.
+            layer.set_blend_mode(BlendMode::Seperable(SeperableBlendMode::Normal));
+            layer.set_fill_color(Color::Rgb(Rgb::new(r, g, b, None)));
+            // Rotate about the centre of the page and centre the text on it.
+            layer.set_ctm(CurTransMat::Translate(
+                Mm(config.page_width.0 / 2.0),
+                Mm(config.page_height.0 / 2.0),
+            ));
+            layer.set_ctm(CurTransMat::Rotate(watermark.angle));
+            self.font().draw(
+                &layer,
+                watermark.text.as_str(),
+                watermark.font_size,
+                -width / 2.0,
+                -height / 3.0,
+            );
+            layer.restore_graphics_state();
+            self.watermarked.push(page);
+        }
+        debug!("Rendered watermark on {} pages.", self.watermarked.len());
+    }
+
+    /// Moves down by one line, starting a new page at the bottom margin.
+    fn next_line(&mut self) {
+        let config = self.config;
+        self.y_position -= pt_to_mm(config.font_size) + config.line_spacing;
+        if self.y_position < config.bottom_margin {
+            self.new_page();
+        }
+    }
+
+    /// Starts a new page unless `height` fits above the bottom margin.
+    fn ensure_space(&mut self, height: f64) {
+        if self.y_position - height < self.config.bottom_margin
+            && self.y_position < self.config.start_y
+        {
+            self.new_page();
+        }
+    }
+
+    fn new_page(&mut self) {
+        let config = self.config;
+        let (new_page, new_layer) =
+            self.doc
+                .add_page(config.page_width, config.page_height, "Layer 1");
+        self.layer = self.doc.get_page(new_page).get_layer(new_layer);
+        self.layers.push(self.layer.clone());
+        self.page_indices.push(new_page);
+        self.page += 1;
+        self.y_position = config.start_y;
+        self.content_top = config.start_y;
+        debug!("Added new page.");
+    }
+
+    /// Flattens inline content into `text` like [`inline_text`], recording the font and
+    /// size of each part in `spans`. References are resolved, and the byte range of
+    /// each one is recorded with its label in `links`.
+    fn resolve_inlines(
+        &mut self,
+        content: &[Inline],
+        style: TextStyle,
+        text: &mut String,
+        spans: &mut Vec<Span>,
+        links: &mut Vec<(Range<usize>, Destination)>,
+    ) -> Result<(), RustaTexError> {
+        for inline in content {
+            match inline {
+                // Emphasis within italic text is upright, as in LaTeX.
+                Inline::Emph(inner) => {
+                    let style = TextStyle {
+                        italic: !style.italic,
+                        ..style
+                    };
+                    self.resolve_inlines(inner, style, text, spans, links)?
+                }
+                Inline::Strong(inner) => {
+                    let style = TextStyle {
+                        bold: true,
+                        ..style
+                    };
+                    self.resolve_inlines(inner, style, text, spans, links)?
+                }
+                Inline::Code(code) => {
+                    let style = TextStyle {
+                        family: MONO_FAMILY,
+                        ..style
+                    };
+                    self.push_span(code, style, text, spans)?;
+                }
+                Inline::Link { url, content } => {
+                    let start = text.len();
+                    self.resolve_inlines(content, style, text, spans, links)?;
+                    links.push((start..text.len(), Destination::Uri(url.clone())));
+                }
+                Inline::Ref(key) => {
+                    self.check_label(key)?;
+                    let start = text.len();
+                    let reference = self.document.reference_text(key);
+                    if reference == "??" {
+                        self.unresolved.insert(format!("\\ref{{{}}}", key));
+                    }
+                    self.push_span(&reference, style, text, spans)?;
+                    links.push((start..text.len(), Destination::Label(key.clone())));
+                }
+                Inline::PageRef(key) => {
+                    self.check_label(key)?;
+                    self.needs_pages = true;
+                    let start = text.len();
+                    let page = match self.pages.labels.get(key) {
+                        Some(&page) => self.page_label(page),
+                        None => {
+                            self.unresolved.insert(format!("\\pageref{{{}}}", key));
+                            "??".to_string()
+                        }
+                    };
+                    self.push_span(&page, style, text, spans)?;
+                    links.push((start..text.len(), Destination::Label(key.clone())));
+                }
+                Inline::FootnoteMark { number, content } => {
+                    let start = text.len();
+                    self.push_span(&number.to_string(), style, text, spans)?;
+                    let index = self.footnotes.len();
+                    links.push((start..text.len(), Destination::Footnote(index)));
+                    self.footnotes.push((*number, content.clone()));
+                }
+                inline => {
+                    let value = inline_text(std::slice::from_ref(inline));
+                    self.push_span(&value, style, text, spans)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if no label `key` is defined, as the parser does for the `\ref` and
+    /// `\pageref` it reads.
+    fn check_label(&self, key: &str) -> Result<(), RustaTexError> {
+        if self.document.labels.contains_key(key) {
+            return Ok(());
+        }
+        error!("Undefined reference '{}'", key);
+        Err(RustaTexError::ReferenceError(format!(
+            "Undefined reference '{}'",
+            key
+        )))
+    }
+
+    /// Appends `value` in `style` to a paragraph's `text` and `spans`.
+    fn push_span(
+        &mut self,
+        value: &str,
+        style: TextStyle,
+        text: &mut String,
+        spans: &mut Vec<Span>,
+    ) -> Result<(), RustaTexError> {
+        let font = self.font_for(style)?;
+        let start = text.len();
+        text.push_str(value);
+        match spans.last_mut() {
+            Some(span) if span.font == font && span.size == style.size => {
+                span.range.end = text.len()
+            }
+            _ => spans.push(Span {
+                range: start..text.len(),
+                font,
+                size: style.size,
+            }),
+        }
+        Ok(())
+    }
+
+    /// The regular face of the main family, which text outside paragraphs is drawn in.
+    fn font(&self) -> &PdfFont {
+        &self.fonts[0]
+    }
+
+    /// Returns the index in `fonts` of the font text in `style` is drawn in, adding it
+    /// to the PDF when it is first used.
+    fn font_for(&mut self, style: TextStyle) -> Result<usize, RustaTexError> {
+        let family = &self.families.families[style.family];
+        let face = family.face(Variant::new(style.bold, style.italic));
+        if let Some(index) = self.fonts.iter().position(|font| font.face.same(face)) {
+            return Ok(index);
+        }
+        self.fonts.push(PdfFont::new(&self.doc, face)?);
+        Ok(self.fonts.len() - 1)
+    }
+
+    /// Records that the target of each of `labels` is drawn at `y` on the current page.
+    fn add_anchors(&mut self, labels: &[String], y: f64) {
+        for key in labels {
+            self.anchors
+                .insert(key.clone(), Anchor { page: self.page, y });
+        }
+    }
+}
+
+/// Collects all headings under `elements` in document order.
+fn collect_headings<'d>(elements: &'d [DocumentElement], headings: &mut Vec<&'d Section>) {
+    for element in elements {
+        match element {
+            DocumentElement::Section(section) => {
+                headings.push(section);
+                collect_headings(&section.elements, headings);
+            }
+            DocumentElement::Environment(environment) => {
+                collect_headings(&environment.elements, headings)
+            }
+            DocumentElement::ListItem(item) => collect_headings(&item.elements, headings),
+            DocumentElement::Paragraph(_)
+            | DocumentElement::TableOfContents(_)
+            | DocumentElement::Matter(_) => (),
+        }
+    }
+}
+
+fn contains_table_of_contents(elements: &[DocumentElement]) -> bool {
+    elements.iter().any(|element| match element {
+        DocumentElement::TableOfContents(_) => true,
+        DocumentElement::Section(section) => contains_table_of_contents(&section.elements),
+        DocumentElement::Environment(environment) => {
+            contains_table_of_contents(&environment.elements)
+        }
+        DocumentElement::ListItem(item) => contains_table_of_contents(&item.elements),
+        DocumentElement::Paragraph(_) | DocumentElement::Matter(_) => false,
+    })
+}
+
+/// Builds the outline from the headings under `elements`, which are drawn at
+/// `headings[next..]`. The entries nested under a heading that opts out with
+/// `[bookmark=false]` move up to its level.
+fn outline_entries(
+    elements: &[DocumentElement],
+    headings: &[Anchor],
+    next: &mut usize,
+    entries: &mut Vec<OutlineEntry>,
+) {
+    for element in elements {
+        match element {
+            DocumentElement::Section(section) => {
+                let anchor = headings.get(*next).copied();
+                *next += 1;
+                let mut children = Vec::new();
+                outline_entries(&section.elements, headings, next, &mut children);
+                match anchor {
+                    Some(anchor) if section.in_bookmarks => entries.push(OutlineEntry {
+                        title: section.heading_text(),
+                        anchor,
+                        children,
+                    }),
+                    _ => entries.extend(children),
+                }
+            }
+            DocumentElement::Environment(environment) => {
+                outline_entries(&environment.elements, headings, next, entries)
+            }
+            DocumentElement::ListItem(item) => {
+                outline_entries(&item.elements, headings, next, entries)
+            }
+            DocumentElement::Paragraph(_)
+            | DocumentElement::TableOfContents(_)
+            | DocumentElement::Matter(_) => (),
+        }
+    }
+}
+
+/// Returns the object of each page by index. Page numbers in lopdf start at 1.
+fn page_ids(pdf: &lopdf::Document) -> Vec<ObjectId> {
+    pdf.get_pages().into_values().collect()
+}
+
+/// Returns the `/Dest` array that shows `anchor` at the top of the window.
+fn destination(pages: &[ObjectId], anchor: Anchor) -> Option<Object> {
+    let page = *pages.get(anchor.page)?;
+    Some(Object::Array(vec![
+        page.into(),
+        "XYZ".into(),
+        Object::Null,
+        Object::Real(mm_to_pt(anchor.y)),
+        Object::Null,
+    ]))
+}
+
+/// Encodes `text` as a PDF text string, in UTF-16 unless it is ASCII.
+fn text_string(text: &str) -> Object {
+    if text.is_ascii() {
+        return Object::String(text.as_bytes().to_vec(), StringFormat::Literal);
+    }
+    let mut bytes = vec![0xFE, 0xFF];
+    for unit in text.encode_utf16() {
+        bytes.extend(unit.to_be_bytes());
+    }
+    Object::String(bytes, StringFormat::Hexadecimal)
+}
+
+/// Adds a link annotation for each link, jumping to the paired anchor or opening the
+/// web address.
+fn add_links(
+    pdf: &mut lopdf::Document,
+    links: &[(LinkArea, Option<Anchor>)],
+) -> Result<(), lopdf::Error> {
+    let pages = page_ids(pdf);
+    let mut annotations: BTreeMap<ObjectId, Vec<Object>> = BTreeMap::new();
+    for (link, anchor) in links {
+        let target = match (&link.destination, anchor) {
+            (Destination::Uri(uri), _) => {
+                let action = Dictionary::from_iter(vec![
+                    ("S", "URI".into()),
+                    ("URI", Object::string_literal(uri.as_str())),
+                ]);
+                Some(("A", Object::Dictionary(action)))
+            }
+            (_, Some(anchor)) => destination(&pages, *anchor).map(|target| ("Dest", target)),
+            (_, None) => None,
+        };
+        let (Some(&page), Some((key, target))) = (pages.get(link.page), target) else {
+            continue;
+        };
+        let rect = link
+            .rect
+            .iter()
+            .map(|&mm| Object::Real(mm_to_pt(mm)))
+            .collect();
+        let annotation = pdf.add_object(Dictionary::from_iter(vec![
+            ("Type", "Annot".into()),
+            ("Subtype", "Link".into()),
+            ("Rect", Object::Array(rect)),
+            ("Border", vec![0.into(), 0.into(), 0.into()].into()),
+            (key, target),
+        ]));
+        annotations.entry(page).or_default().push(annotation.into());
+    }
+
+    for (page, mut page_annotations) in annotations {
+        let dictionary = pdf.get_object_mut(page)?.as_dict_mut()?;
+        if let Ok(Object::Array(existing)) = dictionary.get(b"Annots") {
+            page_annotations.splice(0..0, existing.iter().cloned());
+        }
+        dictionary.set("Annots", page_annotations);
+    }
+    Ok(())
+}
+
+/// Replaces the empty outline printpdf writes with `entries`, reusing its object, and
+/// opens the outline when the document is viewed.
+fn add_outline(pdf: &mut lopdf::Document, entries: &[OutlineEntry]) -> Result<(), lopdf::Error> {
+    let pages = page_ids(pdf);
+    let catalog = pdf.trailer.get(b"Root")?.as_reference()?;
+    let existing = pdf
+        .get_object(catalog)?
+        .as_dict()?
+        .get(b"Outlines")
+        .and_then(Object::as_reference);
+    let root = match existing {
+        Ok(root) => root,
+        Err(_) => pdf.new_object_id(),
+    };
+    let (items, count) = add_outline_items(pdf, &pages, entries, root);
+    let mut outlines =
+        Dictionary::from_iter(vec![("Type", "Outlines".into()), ("Count", count.into())]);
+    if let (Some(&first), Some(&last)) = (items.first(), items.last()) {
+        outlines.set("First", first);
+        outlines.set("Last", last);
+    }
+    pdf.objects.insert(root, Object::Dictionary(outlines));
+
+    let catalog = pdf.get_object_mut(catalog)?.as_dict_mut()?;
+    catalog.set("Outlines", root);
+    catalog.set("PageMode", "UseOutlines");
+    Ok(())
+}
+
+/// Sets the page labels of the catalog to `numbering`, so viewers show the printed
+/// page numbers. PDF letters repeat after z (aa, bb) where ours continue with ab.
+fn add_page_labels(
+    pdf: &mut lopdf::Document,
+    numbering: &PageNumbering,
+) -> Result<(), lopdf::Error> {
+    let mut nums = Vec::new();
+    for range in &numbering.ranges {
+        let style = match range.format {
+            NumberFormat::Arabic => "D",
+            NumberFormat::LowerRoman => "r",
+            NumberFormat::UpperRoman => "R",
+            NumberFormat::LowerAlpha => "a",
+            NumberFormat::UpperAlpha => "A",
+        };
+        let mut label = Dictionary::from_iter(vec![("S", style.into())]);
+        if range.first != 1 {
+            label.set("St", range.first as i64);
+        }
+        nums.push(Object::Integer(range.start as i64));
+        nums.push(Object::Dictionary(label));
+    }
+    let labels = Dictionary::from_iter(vec![("Nums", Object::Array(nums))]);
+
+    let catalog = pdf.trailer.get(b"Root")?.as_reference()?;
+    let catalog = pdf.get_object_mut(catalog)?.as_dict_mut()?;
+    catalog.set("PageLabels", labels);
+    Ok(())
+}
+
+/// Rewrites the text drawn in builtin fonts with one byte per character. printpdf
+/// writes the codes in `TJ` arrays with two bytes each, as embedded fonts read them,
+/// while builtin fonts read one byte per character, so the first byte, which is 0,
+/// is dropped.
+fn narrow_builtin_codes(pdf: &mut lopdf::Document) -> Result<(), lopdf::Error> {
+    for page in page_ids(pdf) {
+        // printpdf writes the resources of each page and their fonts as objects of
+        // their own.
+        let resources = dereference(pdf, pdf.get_dictionary(page)?.get(b"Resources")?)?;
+        let builtin: BTreeSet<Vec<u8>> = match resources.get(b"Font") {
+            Ok(fonts) => dereference(pdf, fonts)?
+                .iter()
+                .filter(|(_, font)| {
+                    dereference(pdf, font)
+                        .and_then(|font| font.get(b"Subtype"))
+                        .and_then(Object::as_name)
+                        .ok()
+                        == Some(b"Type1")
+                })
+                .map(|(name, _)| name.clone())
+                .collect(),
+            Err(_) => continue,
+        };
+        if builtin.is_empty() {
+            continue;
+        }
+        let mut content = pdf.get_and_decode_page_content(page)?;
+        let mut in_builtin = false;
+        for operation in &mut content.operations {
+            match (
+                operation.operator.as_str(),
+                operation.operands.as_mut_slice(),
+            ) {
+                ("Tf", [font, ..]) => {
+                    in_builtin = font.as_name().is_ok_and(|font| builtin.contains(font))
+                }
+                ("TJ", [Object::Array(items)]) if in_builtin => {
+                    for item in items {
+                        if let Object::String(bytes, _) = item {
+                            *bytes = bytes.iter().skip(1).step_by(2).copied().collect();
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        pdf.change_page_content(page, content.encode()?)?;
+    }
     Ok(())
 }
 
-fn wrap_text(text: &str, max_width_mm: f64, font_size: u32, font: &IndirectFontRef) -> Vec<String> {
-    let words: Vec<&str> = text.split_whitespace().collect();
-    let mut lines = Vec::new();
-    let mut current_line = String::new();
-    let mut current_width = 0.0;
+/// Returns the dictionary `object` is or refers to.
+fn dereference<'a>(
+    pdf: &'a lopdf::Document,
+    object: &'a Object,
+) -> Result<&'a Dictionary, lopdf::Error> {
+    match object {
+        Object::Reference(id) => pdf.get_dictionary(*id),
+        object => object.as_dict(),
+    }
+}
+
+/// The name of the graphics state the watermark is drawn in. printpdf names the
+/// graphics states of a page `GS0`, `GS1` and so on, in the order they are added, and
+/// the blend mode the watermark sets is the only one the renderer adds to a page.
+const WATERMARK_STATE: &[u8] = b"GS0";
 
-    for word in words {
-        let word_width = estimate_text_width(word, font_size, font);
-        if current_width + word_width > max_width_mm {
-            if !current_line.is_empty() {
-                lines.push(current_line.clone());
-                current_line.clear();
-                current_width = 0.0;
+/// Sets the fill and stroke alpha of the graphics state the watermark is drawn in on
+/// each of `pages`. printpdf can only add graphics states with fixed settings, so the
+/// alpha is added here.
+fn set_watermark_opacity(
+    pdf: &mut lopdf::Document,
+    opacity: f64,
+    pages: &[usize],
+) -> Result<(), lopdf::Error> {
+    // printpdf writes the resources of each page as their own object, with the
+    // graphics states inline.
+    let ids = page_ids(pdf);
+    for &page in pages {
+        let Some(&id) = ids.get(page) else {
+            continue;
+        };
+        let resources = pdf.get_dictionary(id)?.get(b"Resources")?.as_reference()?;
+        let state = pdf
+            .get_object_mut(resources)?
+            .as_dict_mut()?
+            .get_mut(b"ExtGState")?
+            .as_dict_mut()?
+            .get_mut(WATERMARK_STATE)?
+            .as_dict_mut()?;
+        state.set("ca", opacity);
+        state.set("CA", opacity);
+    }
+    Ok(())
+}
+
+/// Replaces the font file of each subset font with the subset, and names the font
+/// after it.
+fn add_font_subsets(pdf: &mut lopdf::Document, subsets: &[FontSubset]) -> Result<(), lopdf::Error> {
+    for subset in subsets {
+        let file = pdf.objects.iter().find_map(|(&id, object)| match object {
+            Object::Stream(stream) if stream.content == subset.font.data() => Some(id),
+            _ => None,
+        });
+        let Some(file) = file else {
+            warn!(
+                "Font file of {} not found in the PDF; embedding the whole font",
+                subset.font.name
+            );
+            continue;
+        };
+        if let Object::Stream(stream) = pdf.get_object_mut(file)? {
+            stream.set_content(subset.data.clone());
+            stream.dict.set("Length1", subset.data.len() as i64);
+        }
+        // The font descriptor, the CID font and the Type 0 font all carry the name.
+        let name = Object::Name(subset.name.clone().into_bytes());
+        let mut descriptors = Vec::new();
+        for (&id, object) in pdf.objects.iter_mut() {
+            if let Object::Dictionary(descriptor) = object {
+                if descriptor
+                    .get(b"FontFile2")
+                    .and_then(Object::as_reference)
+                    .ok()
+                    == Some(file)
+                {
+                    descriptor.set("FontName", name.clone());
+                    descriptors.push(id);
+                }
             }
         }
-        if !current_line.is_empty() {
-            current_line.push(' ');
-            current_width += estimate_text_width(" ", font_size, font);
+        // printpdf writes the CID font inside the Type 0 font.
+        for object in pdf.objects.values_mut() {
+            let Object::Dictionary(font) = object else {
+                continue;
+            };
+            let Ok(Object::Array(descendants)) = font.get_mut(b"DescendantFonts") else {
+                continue;
+            };
+            let mut uses_subset = false;
+            for descendant in descendants {
+                if let Object::Dictionary(cid_font) = descendant {
+                    let descriptor = cid_font
+                        .get(b"FontDescriptor")
+                        .and_then(Object::as_reference);
+                    if descriptor.is_ok_and(|descriptor| descriptors.contains(&descriptor)) {
+                        cid_font.set("BaseFont", name.clone());
+                        uses_subset = true;
+                    }
+                }
+            }
+            if uses_subset {
+                font.set("BaseFont", name.clone());
+            }
         }
-        current_line.push_str(word);
-        current_width += word_width;
     }
+    Ok(())
+}
 
-    if !current_line.is_empty() {
-        lines.push(current_line);
+/// Adds the outline items for `entries` under `parent`, returning their objects and
+/// the number of items including all descendants, which are shown expanded.
+fn add_outline_items(
+    pdf: &mut lopdf::Document,
+    pages: &[ObjectId],
+    entries: &[OutlineEntry],
+    parent: ObjectId,
+) -> (Vec<ObjectId>, i64) {
+    let ids: Vec<ObjectId> = entries.iter().map(|_| pdf.new_object_id()).collect();
+    let mut count = 0;
+    for (index, entry) in entries.iter().enumerate() {
+        let (children, descendants) = add_outline_items(pdf, pages, &entry.children, ids[index]);
+        count += 1 + descendants;
+
+        let mut item = Dictionary::from_iter(vec![
+            ("Title", text_string(&entry.title)),
+            ("Parent", parent.into()),
+        ]);
+        if let Some(destination) = destination(pages, entry.anchor) {
+            item.set("Dest", destination);
+        }
+        if index > 0 {
+            item.set("Prev", ids[index - 1]);
+        }
+        if let Some(&next) = ids.get(index + 1) {
+            item.set("Next", next);
+        }
+        if let (Some(&first), Some(&last)) = (children.first(), children.last()) {
+            item.set("First", first);
+            item.set("Last", last);
+            item.set("Count", descendants);
+        }
+        pdf.objects.insert(ids[index], Object::Dictionary(item));
     }
+    (ids, count)
+}
 
-    lines
+/// Loads the hyphenation patterns of the document's language, or else the configured
+/// one, with the words the document gives hyphens for.
+fn load_hyphenator(
+    document: &Document,
+    config: &RenderConfig,
+) -> Result<Hyphenator, RustaTexError> {
+    let language = document.language.as_deref().unwrap_or(&config.language);
+    let Some(mut hyphenator) = Hyphenator::for_language(language) else {
+        error!("No hyphenation patterns for language {}", language);
+        return Err(RustaTexError::RenderError(format!(
+            "No hyphenation patterns for language {} (expected en, de or fr)",
+            language
+        )));
+    };
+    hyphenator.left_min = config.left_hyphen_min;
+    hyphenator.right_min = config.right_hyphen_min;
+    for word in &document.hyphenation {
+        hyphenator.add_exception(word);
+    }
+    debug!("Hyphenating in language {}.", language);
+    Ok(hyphenator)
 }
 
-fn estimate_text_width(text: &str, font_size: u32, _font: &IndirectFontRef) -> f64 {
-    text.len() as f64 * (font_size as f64 * 0.5)
+/// The font families text is drawn in: the main family, the monospace family for code
+/// and the families named in paragraph styles.
+struct FontFamilies {
+    families: Vec<FontFamily>,
+    /// The index in `families` of each family by the name styles use.
+    names: HashMap<String, usize>,
 }
+
+impl FontFamilies {
+    /// Loads the families chosen in the document, or else configured, and those named
+    /// in paragraph styles.
+    fn load(document: &Document, config: &RenderConfig) -> Result<Self, RustaTexError> {
+        let dirs = &config.font_dirs;
+        let main = load_family(document.main_font.as_ref(), &config.font, dirs)?;
+        let mono = load_family(document.mono_font.as_ref(), &config.mono_font, dirs)?;
+        let mut families = FontFamilies {
+            families: vec![main, mono],
+            names: HashMap::from([
+                ("main".to_string(), MAIN_FAMILY),
+                ("mono".to_string(), MONO_FAMILY),
+            ]),
+        };
+        let mut names = Vec::new();
+        collect_style_fonts(&document.elements, &mut names);
+        for name in names {
+            if !families.names.contains_key(name) {
+                families.families.push(FontFamily::load(name, dirs)?);
+                families
+                    .names
+                    .insert(name.to_string(), families.families.len() - 1);
+            }
+        }
+        Ok(families)
+    }
+
+    fn main(&self) -> &FontFamily {
+        &self.families[MAIN_FAMILY]
+    }
+
+    /// Returns the index of the family a style names.
+    fn index(&self, name: &str) -> usize {
+        self.names.get(name).copied().unwrap_or(MAIN_FAMILY)
+    }
+}
+
+/// Loads the family chosen in the document, with the faces it sets, or else the
+/// configured family `name`.
+fn load_family(
+    choice: Option<&FontChoice>,
+    name: &str,
+    dirs: &[PathBuf],
+) -> Result<FontFamily, RustaTexError> {
+    let Some(choice) = choice else {
+        return FontFamily::load(name, dirs);
+    };
+    let dirs: Vec<PathBuf> = choice.dirs.iter().chain(dirs).cloned().collect();
+    let mut family = FontFamily::load(&choice.name, &dirs)?;
+    let faces = [
+        (Variant::Bold, &choice.bold),
+        (Variant::Italic, &choice.italic),
+        (Variant::BoldItalic, &choice.bold_italic),
+    ];
+    for (variant, face) in faces {
+        if let Some(face) = face {
+            family.set_face(variant, FontFace::load(face, &dirs)?);
+        }
+    }
+    Ok(family)
+}
+
+/// Collects the font families named in the paragraph styles under `elements`.
+fn collect_style_fonts<'d>(elements: &'d [DocumentElement], fonts: &mut Vec<&'d str>) {
+    for element in elements {
+        match element {
+            DocumentElement::Paragraph(paragraph) => fonts.extend(
+                paragraph
+                    .style
+                    .as_ref()
+                    .and_then(|style| style.font.as_deref()),
+            ),
+            DocumentElement::Section(section) => collect_style_fonts(&section.elements, fonts),
+            DocumentElement::Environment(environment) => {
+                collect_style_fonts(&environment.elements, fonts)
+            }
+            DocumentElement::ListItem(item) => collect_style_fonts(&item.elements, fonts),
+            DocumentElement::TableOfContents(_) | DocumentElement::Matter(_) => (),
+        }
+    }
+}
+
+/// The style in effect for a stretch of inline content.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TextStyle {
+    /// The index of the font family in [`FontFamilies`].
+    family: usize,
+    bold: bool,
+    italic: bool,
+    /// The font size in points.
+    size: f64,
+}
+
+/// A stretch of a paragraph's text drawn in one font and size.
+#[derive(Debug, Clone)]
+struct Span {
+    /// The byte range in the paragraph's text.
+    range: Range<usize>,
+    /// The index of the font in the fonts added to the PDF.
+    font: usize,
+    size: f64,
+}
+
+/// A font added to the PDF and the metrics text in it is laid out with.
+struct PdfFont {
+    reference: IndirectFontRef,
+    face: FontFace,
+    /// The glyphs drawn in an embedded font.
+    used: Option<UsedGlyphs>,
+}
+
+impl PdfFont {
+    /// Adds `face` to the PDF, failing if printpdf rejects the font.
+    fn new(doc: &PdfDocumentReference, face: &FontFace) -> Result<Self, RustaTexError> {
+        let (reference, used) = match face {
+            FontFace::Builtin(font) => (doc.add_builtin_font(*font), None),
+            FontFace::TrueType(font) => {
+                let data = EmbeddedFont::new(Rc::clone(font));
+                let used = data.used();
+                let reference = doc.add_external_font_data(font.data().to_vec(), data);
+                (reference, Some(used))
+            }
+        };
+        let reference = reference.map_err(|e| {
+            error!("Cannot add font {} to the PDF: {}", face.name(), e);
+            RustaTexError::FontError(format!("Cannot add font {} to the PDF: {}", face.name(), e))
+        })?;
+        Ok(PdfFont {
+            reference,
+            face: face.clone(),
+            used,
+        })
+    }
+
+    fn metrics(&self) -> &dyn TextMetrics {
+        self.face.metrics()
+    }
+
+    /// Returns the subset of an embedded font with the glyphs drawn in it.
+    fn subset(&self) -> Result<Option<FontSubset>, RustaTexError> {
+        let (FontFace::TrueType(font), Some(used)) = (&self.face, &self.used) else {
+            return Ok(None);
+        };
+        let glyphs: BTreeSet<u16> = used.borrow().keys().copied().collect();
+        let data = font.subset(&glyphs)?;
+        // Subsets are named with six capital letters derived from their glyphs.
+        let mut hasher = DefaultHasher::new();
+        glyphs.hash(&mut hasher);
+        let mut hash = hasher.finish();
+        let mut tag = String::new();
+        for _ in 0..6 {
+            tag.push((b'A' + (hash % 26) as u8) as char);
+            hash /= 26;
+        }
+        let name = if font.name.is_empty() {
+            "Font"
+        } else {
+            &font.name
+        };
+        Ok(Some(FontSubset {
+            font: Rc::clone(font),
+            data,
+            name: format!("{}+{}", tag, name),
+        }))
+    }
+
+    /// Returns the width of `text` in mm, including kerning.
+    fn width(&self, text: &str, font_size: f64) -> f64 {
+        pt_to_mm(self.metrics().text_width(text, font_size))
+    }
+
+    /// Draws `text` with its left end at `x` mm on the baseline `y`, in one text
+    /// object whose kerning is given by adjustments between the characters.
+    fn draw(&self, layer: &PdfLayerReference, text: &str, font_size: f64, x: f64, y: f64) {
+        self.draw_spaced(layer, text, font_size, x, y, 0.0);
+    }
+
+    /// Draws `text` like [`Self::draw`], with each space `word_spacing` mm wider, as
+    /// justified text is.
+    fn draw_spaced(
+        &self,
+        layer: &PdfLayerReference,
+        text: &str,
+        font_size: f64,
+        x: f64,
+        y: f64,
+        word_spacing: f64,
+    ) {
+        // Spaces are widened by adjustments too, as the word spacing of PDF text does
+        // not apply to the two-byte codes of embedded fonts.
+        let space = -mm_to_pt(word_spacing) * 1000.0 / font_size;
+        let metrics = self.metrics();
+        let mut codes = Vec::new();
+        let mut adjustment = 0.0;
+        let mut previous = None;
+        for c in text.chars() {
+            let code = match (&self.face, &self.used) {
+                (FontFace::TrueType(font), Some(used)) => font.glyph(c).inspect(|&glyph| {
+                    used.borrow_mut().insert(glyph, c);
+                }),
+                // Narrowed to one byte by `narrow_builtin_codes`.
+                _ => win_ansi(c).map(u16::from),
+            };
+            let Some(code) = code else {
+                continue;
+            };
+            if let Some(previous) = previous {
+                adjustment -= metrics.kerning(previous, c);
+            }
+            codes.push((adjustment.round() as i64, code));
+            adjustment = if c == ' ' { space } else { 0.0 };
+            previous = Some(c);
+        }
+        layer.begin_text_section();
+        layer.set_font(&self.reference, font_size);
+        layer.set_text_cursor(Mm(x), Mm(y));
+        layer.write_positioned_codepoints(codes);
+        layer.end_text_section();
+    }
+}
+
+fn pt_to_mm(pt: f64) -> f64 {
+    pt * 25.4 / 72.0
+}
+
+fn mm_to_pt(mm: f64) -> f64 {
+    mm * 72.0 / 25.4
+}
+
 #[allow(dead_code)]
 /// Configuration for rendering
 pub struct RenderConfig {
@@ -166,15 +1984,219 @@ pub struct RenderConfig {
     pub margin_bottom: f64,
     pub start_y: f64,
     pub bottom_margin: f64,
+    /// The body text size in points, as are the other font sizes.
     pub font_size: f64,
     pub title_font_size: f64,
     pub section_font_size: f64,
     pub subsection_font_size: f64,
     pub line_width: f64,
+    /// The space between lines in mm, as are the other spacings.
     pub line_spacing: f64,
     pub paragraph_spacing: f64,
     pub section_spacing: f64,
     pub subsection_spacing: f64,
+    /// Whether to add a table of contents after the title when the document does not
+    /// place one with `\tableofcontents`.
+    pub table_of_contents: bool,
+    /// Whether to add a PDF outline of the headings.
+    pub bookmarks: bool,
+    /// The text at the top of every page.
+    pub header: Option<PageTemplate>,
+    /// The text at the bottom of every page.
+    pub footer: Option<PageTemplate>,
+    pub header_font_size: f64,
+    /// The distance in mm from the top of the page to the baseline of the header, or
+    /// `None` for halfway between the top and the text.
+    pub header_offset: Option<f64>,
+    /// The distance in mm from the bottom of the page to the baseline of the footer,
+    /// or `None` for halfway between the bottom and the bottom margin.
+    pub footer_offset: Option<f64>,
+    /// How `{page}` and `{pages}` are written in headers and footers.
+    pub page_numbering: NumberFormat,
+    /// Text drawn across the pages.
+    pub watermark: Option<Watermark>,
+    /// The font family text is drawn in unless the document chooses one: a standard
+    /// font family, a font file or the name of one in `font_dirs` or the system fonts.
+    pub font: String,
+    /// The font family code is drawn in unless the document chooses one.
+    pub mono_font: String,
+    /// Where fonts are looked for by name before the system font directories.
+    pub font_dirs: Vec<PathBuf>,
+    /// How paragraphs are aligned unless their style or environment says otherwise.
+    pub alignment: Alignment,
+    /// The highest badness a line of a paragraph may have, as TeX's `\tolerance`.
+    pub tolerance: f64,
+    /// How many lines longer, or if negative shorter, paragraphs are made than is best
+    /// where the tolerance allows, as TeX's `\looseness`.
+    pub looseness: i32,
+    /// Whether words may be hyphenated to break lines.
+    pub hyphenation: bool,
+    /// The language words are hyphenated in unless the document chooses one.
+    pub language: String,
+    /// The fewest characters kept before a hyphen, as TeX's `\lefthyphenmin`.
+    pub left_hyphen_min: usize,
+    /// The fewest characters carried over after a hyphen, as TeX's `\righthyphenmin`.
+    pub right_hyphen_min: usize,
+}
+
+/// Large rotated text drawn across the pages, such as "DRAFT".
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watermark {
+    pub text: String,
+    /// The font size in points.
+    pub font_size: f64,
+    /// The angle in degrees, counterclockwise from the baseline of the page.
+    pub angle: f64,
+    /// From 0 (invisible) to 1 (opaque).
+    pub opacity: f64,
+    /// The red, green and blue components, each from 0 to 1.
+    pub color: [f64; 3],
+    /// The pages the watermark is drawn on, or `None` for every page. The pages are
+    /// counted from the first page of the PDF, not by their printed numbers.
+    pub pages: Option<PageSelection>,
+}
+
+impl Watermark {
+    /// Returns a light grey watermark at 45 degrees on every page.
+    pub fn new(text: impl Into<String>) -> Self {
+        Watermark {
+            text: text.into(),
+            font_size: 72.0,
+            angle: 45.0,
+            opacity: 0.3,
+            color: [0.5, 0.5, 0.5],
+            pages: None,
+        }
+    }
+
+    /// Parses a colour as `#rrggbb`, `#rgb` or one of the names `black`, `grey`
+    /// (or `gray`), `red`, `green` and `blue`.
+    pub fn parse_color(spec: &str) -> Option<[f64; 3]> {
+        let spec = spec.trim();
+        let named = match spec.to_lowercase().as_str() {
+            "black" => Some([0.0, 0.0, 0.0]),
+            "grey" | "gray" => Some([0.5, 0.5, 0.5]),
+            "red" => Some([1.0, 0.0, 0.0]),
+            "green" => Some([0.0, 0.5, 0.0]),
+            "blue" => Some([0.0, 0.0, 1.0]),
+            _ => None,
+        };
+        if named.is_some() {
+            return named;
+        }
+        let hex = spec.strip_prefix('#')?;
+        if !hex.is_ascii() {
+            return None;
+        }
+        let digits = match hex.len() {
+            3 => hex.chars().map(|digit| format!("{0}{0}", digit)).collect(),
+            6 => hex.to_string(),
+            _ => return None,
+        };
+        let mut color = [0.0; 3];
+        for (channel, value) in color.iter_mut().enumerate() {
+            let byte = u8::from_str_radix(&digits[channel * 2..channel * 2 + 2], 16).ok()?;
+            *value = byte as f64 / 255.0;
+        }
+        Some(color)
+    }
+}
+
+/// A set of pages given by number, counting the first page of the PDF as 1 whatever
+/// its printed number.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageSelection {
+    /// Inclusive ranges of page numbers; an open range has `usize::MAX` as its end.
+    ranges: Vec<(usize, usize)>,
+}
+
+impl PageSelection {
+    /// Parses a comma-separated list of pages and ranges, such as `1,3-5,10-`.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut ranges = Vec::new();
+        for part in spec.split(',') {
+            let part = part.trim();
+            let range = match part.split_once('-') {
+                Some((first, "")) => (first.trim().parse().ok()?, usize::MAX),
+                Some((first, last)) => (first.trim().parse().ok()?, last.trim().parse().ok()?),
+                None => {
+                    let page = part.parse().ok()?;
+                    (page, page)
+                }
+            };
+            if range.0 == 0 || range.0 > range.1 {
+                return None;
+            }
+            ranges.push(range);
+        }
+        Some(PageSelection { ranges })
+    }
+
+    /// Whether the page at `index`, counting from 0, is selected.
+    pub fn contains(&self, index: usize) -> bool {
+        let page = index + 1;
+        self.ranges
+            .iter()
+            .any(|&(first, last)| (first..=last).contains(&page))
+    }
+}
+
+/// A header or footer with left-aligned, centred and right-aligned text.
+///
+/// The text may contain `{page}` (the printed page number), `{pages}` (the number of
+/// pages in the PDF, front matter included, in the configured page numbering),
+/// `{title}`, `{section}` (the current chapter or section heading) and `{date}` (the
+/// `\date`, or today).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PageTemplate {
+    pub left: String,
+    pub center: String,
+    pub right: String,
+}
+
+impl PageTemplate {
+    /// Parses `left|center|right`. A single part is centred and two parts go left and
+    /// right.
+    pub fn parse(spec: &str) -> Self {
+        let parts: Vec<String> = spec
+            .split('|')
+            .map(|part| part.trim().to_string())
+            .collect();
+        match <[String; 3]>::try_from(parts) {
+            Ok([left, center, right]) => PageTemplate {
+                left,
+                center,
+                right,
+            },
+            Err(parts) => match parts.as_slice() {
+                [left, right] => PageTemplate {
+                    left: left.clone(),
+                    right: right.clone(),
+                    ..PageTemplate::default()
+                },
+                _ => PageTemplate {
+                    center: spec.trim().to_string(),
+                    ..PageTemplate::default()
+                },
+            },
+        }
+    }
+}
+
+impl RenderConfig {
+    /// Returns the font size, space above and indentation of a heading at `level`.
+    fn heading_style(&self, level: SectionLevel) -> (f64, f64, f64) {
+        match level {
+            SectionLevel::Part | SectionLevel::Chapter => {
+                (self.title_font_size, self.section_spacing, 0.0)
+            }
+            SectionLevel::Section => (self.section_font_size, self.section_spacing, 0.0),
+            SectionLevel::Subsection => (self.subsection_font_size, self.subsection_spacing, 10.0),
+            SectionLevel::Subsubsection | SectionLevel::Paragraph => {
+                (self.font_size, self.subsection_spacing, 10.0)
+            }
+        }
+    }
 }
 
 impl Default for RenderConfig {
@@ -197,6 +2219,401 @@ impl Default for RenderConfig {
             paragraph_spacing: 10.0,
             section_spacing: 15.0,
             subsection_spacing: 10.0,
+            table_of_contents: false,
+            bookmarks: false,
+            header: None,
+            footer: None,
+            header_font_size: 10.0,
+            header_offset: None,
+            footer_offset: None,
+            page_numbering: NumberFormat::Arabic,
+            watermark: None,
+            font: "Times".to_string(),
+            mono_font: "Courier".to_string(),
+            font_dirs: Vec::new(),
+            alignment: Alignment::Justify,
+            tolerance: 200.0,
+            looseness: 0,
+            hyphenation: false,
+            language: "en".to_string(),
+            left_hyphen_min: 2,
+            right_hyphen_min: 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_input;
+
+    #[test]
+    fn fills_page_placeholders_in_headers_and_footers() {
+        let mut source =
+            "\\title{Report}\n\\frontmatter\n\nPreface.\n\n\\mainmatter\n\n\\section{Start}\n\n"
+                .to_string();
+        source.push_str(&"Body text.\n\n".repeat(40));
+        let document = parse_input(&source).unwrap();
+        let config = RenderConfig {
+            header: Some(PageTemplate::parse("{title}||{section}")),
+            footer: Some(PageTemplate::parse("{page} of {pages}")),
+            ..RenderConfig::default()
+        };
+        let pdf = lopdf::Document::load_mem(&render(&document, &config).unwrap()).unwrap();
+        let pages = pdf.get_pages().len();
+        assert!(pages >= 3, "{} pages", pages);
+
+        let first = pdf.extract_text(&[1]).unwrap();
+        assert!(first.contains(&format!("i of {}", pages)), "{}", first);
+        let last = pdf.extract_text(&[pages as u32]).unwrap();
+        assert!(
+            last.contains(&format!("{} of {}", pages - 1, pages)),
+            "{}",
+            last
+        );
+        assert!(
+            last.contains("Report") && last.contains("1 Start"),
+            "{}",
+            last
+        );
+    }
+
+    #[test]
+    fn lists_headings_with_their_pages_in_the_table_of_contents() {
+        let mut source = "\\frontmatter\n\n\\tableofcontents\n\n\\mainmatter\n\n\\section{One}\n\n\\section*{Hidden}\n\n\\section*[toc]{Shown}\n\n\\section[toc=false]{Left out}\n\n".to_string();
+        source.push_str(&"Body text.\n\n".repeat(60));
+        source.push_str("\\section{Three}");
+        let document = parse_input(&source).unwrap();
+        let pdf = lopdf::Document::load_mem(&render(&document, &RenderConfig::default()).unwrap())
+            .unwrap();
+        // The last heading is on a later page of the main matter, which is numbered from
+        // the second page of the PDF.
+        let pages = pdf.get_pages().len() as u32;
+        let three = (2..=pages)
+            .find(|&page| pdf.extract_text(&[page]).unwrap().contains("3 Three"))
+            .unwrap();
+        assert!(three > 2);
+
+        let text = pdf.extract_text(&[1]).unwrap();
+        let entries: Vec<&str> = text
+            .lines()
+            .filter(|line| !line.starts_with(". "))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                "Contents",
+                "1 One",
+                "1",
+                "Shown",
+                "1",
+                "3 Three",
+                &(three - 1).to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_the_pages_of_front_main_and_back_matter() {
+        let mut source = "\\frontmatter\n\nPreface.\n\n\\mainmatter\n\n".to_string();
+        source.push_str(&"Body text.\n\n".repeat(60));
+        source.push_str("\\backmatter\n\nIndex.");
+        let document = parse_input(&source).unwrap();
+        let config = RenderConfig {
+            footer: Some(PageTemplate::parse("|{page}|")),
+            ..RenderConfig::default()
+        };
+        let pdf = lopdf::Document::load_mem(&render(&document, &config).unwrap()).unwrap();
+        let pages = pdf.get_pages().len();
+        assert!(pages >= 4, "{} pages", pages);
+        let footers: Vec<String> = (1..=pages as u32)
+            .map(|page| {
+                let text = pdf.extract_text(&[page]).unwrap();
+                text.lines().last().unwrap().to_string()
+            })
+            .collect();
+        let expected: Vec<String> = std::iter::once("i".to_string())
+            .chain((1..pages).map(|page| page.to_string()))
+            .collect();
+        assert_eq!(footers, expected);
+
+        let catalog = pdf.catalog().unwrap();
+        let nums = catalog
+            .get(b"PageLabels")
+            .and_then(Object::as_dict)
+            .and_then(|labels| labels.get(b"Nums"))
+            .and_then(Object::as_array)
+            .unwrap();
+        let ranges: Vec<(i64, Vec<u8>, Option<i64>)> = nums
+            .chunks(2)
+            .map(|pair| {
+                let label = pair[1].as_dict().unwrap();
+                (
+                    pair[0].as_i64().unwrap(),
+                    label.get(b"S").and_then(Object::as_name).unwrap().to_vec(),
+                    label.get(b"St").and_then(Object::as_i64).ok(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            ranges,
+            [
+                (0, b"r".to_vec(), None),
+                (1, b"D".to_vec(), None),
+                (pages as i64 - 1, b"D".to_vec(), Some(pages as i64 - 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_a_nested_outline_in_place_of_printpdfs() {
+        let document = parse_input(
+            "\\chapter{One}\n\n\\section{A}\n\n\\section*{Hidden}\n\n\\section{B}\n\n\\chapter{Two}",
+        )
+        .unwrap();
+        let config = RenderConfig {
+            bookmarks: true,
+            ..RenderConfig::default()
+        };
+        let pdf = lopdf::Document::load_mem(&render(&document, &config).unwrap()).unwrap();
+        let outlines: Vec<&Dictionary> = pdf
+            .objects
+            .values()
+            .filter_map(|object| object.as_dict().ok())
+            .filter(|dict| dict.get(b"Type").and_then(Object::as_name).ok() == Some(b"Outlines"))
+            .collect();
+        assert_eq!(outlines.len(), 1);
+        let root = outlines[0];
+        assert_eq!(root.get(b"Count").unwrap().as_i64().unwrap(), 4);
+
+        let item = |id: &Object| {
+            pdf.get_object(id.as_reference().unwrap())
+                .unwrap()
+                .as_dict()
+                .unwrap()
+        };
+        let title = |item: &Dictionary| {
+            String::from_utf8(item.get(b"Title").unwrap().as_str().unwrap().to_vec()).unwrap()
+        };
+        let one = item(root.get(b"First").unwrap());
+        assert_eq!(title(one), "1 One");
+        assert_eq!(one.get(b"Count").unwrap().as_i64().unwrap(), 2);
+        let a = item(one.get(b"First").unwrap());
+        let b = item(a.get(b"Next").unwrap());
+        assert_eq!(
+            (title(a), title(b)),
+            ("1.1 A".to_string(), "1.2 B".to_string())
+        );
+        assert!(!b.has(b"Next") && !b.has(b"Count"));
+        let two = item(one.get(b"Next").unwrap());
+        assert_eq!(title(two), "2 Two");
+        assert_eq!(title(item(root.get(b"Last").unwrap())), "2 Two");
+    }
+
+    #[test]
+    fn resolves_references_and_links_them_to_their_targets() {
+        let mut source =
+            "\\section{Start}\n\nSee \\ref{later} on page \\pageref{later}.\n\n".to_string();
+        source.push_str(&"Body text.\n\n".repeat(60));
+        source.push_str("\\section{Later}\\label{later}\n\nThe end.");
+        let document = parse_input(&source).unwrap();
+        let pdf = lopdf::Document::load_mem(&render(&document, &RenderConfig::default()).unwrap())
+            .unwrap();
+        let pages = page_ids(&pdf);
+        let target = (0..pages.len())
+            .find(|&index| {
+                let text = pdf.extract_text(&[index as u32 + 1]).unwrap();
+                text.contains("2 Later")
+            })
+            .unwrap();
+        assert!(target > 0);
+        let first = pdf.extract_text(&[1]).unwrap();
+        assert!(
+            first.contains(&format!("See 2 on page {}.", target + 1)),
+            "{}",
+            first
+        );
+
+        let annotations = pdf
+            .get_dictionary(pages[0])
+            .unwrap()
+            .get(b"Annots")
+            .and_then(Object::as_array)
+            .unwrap();
+        assert_eq!(annotations.len(), 2);
+        for annotation in annotations {
+            let annotation = pdf
+                .get_dictionary(annotation.as_reference().unwrap())
+                .unwrap();
+            assert_eq!(
+                annotation
+                    .get(b"Subtype")
+                    .and_then(Object::as_name)
+                    .unwrap(),
+                b"Link"
+            );
+            let destination = annotation.get(b"Dest").and_then(Object::as_array).unwrap();
+            assert_eq!(destination[0].as_reference().unwrap(), pages[target]);
+        }
+    }
+
+    #[test]
+    fn draws_footnotes_at_the_end_and_links_their_marks() {
+        let document =
+            parse_input("Text\\footnote{A \\emph{first} note.} and more\\footnote{Second.}.")
+                .unwrap();
+        let pdf = lopdf::Document::load_mem(&render(&document, &RenderConfig::default()).unwrap())
+            .unwrap();
+        let page = page_ids(&pdf)[0];
+        // Each text object, such as the emphasised word, is extracted as a line.
+        let text = pdf.extract_text(&[1]).unwrap().replace('\n', "");
+        let body = text.find("Text1 and more2.").unwrap();
+        let notes = text.find("Notes").unwrap();
+        let first = text.find("1. A first note.").unwrap();
+        let second = text.find("2. Second.").unwrap();
+        assert!(body < notes && notes < first && first < second, "{}", text);
+
+        let annotations = pdf
+            .get_dictionary(page)
+            .unwrap()
+            .get(b"Annots")
+            .and_then(Object::as_array)
+            .unwrap();
+        assert_eq!(annotations.len(), 2);
+        for annotation in annotations {
+            let annotation = pdf
+                .get_dictionary(annotation.as_reference().unwrap())
+                .unwrap();
+            let destination = annotation.get(b"Dest").and_then(Object::as_array).unwrap();
+            assert_eq!(destination[0].as_reference().unwrap(), page);
+        }
+    }
+
+    #[test]
+    fn links_web_addresses() {
+        let document =
+            parse_input("Visit \\href{https://example.com/a}{the site} or \\url{https://x.org}.")
+                .unwrap();
+        let pdf = lopdf::Document::load_mem(&render(&document, &RenderConfig::default()).unwrap())
+            .unwrap();
+        let page = page_ids(&pdf)[0];
+        let text = pdf.extract_text(&[1]).unwrap();
+        assert!(
+            text.contains("Visit the site or https://x.org."),
+            "{}",
+            text
+        );
+        let uris: Vec<Vec<u8>> = pdf
+            .get_dictionary(page)
+            .unwrap()
+            .get(b"Annots")
+            .and_then(Object::as_array)
+            .unwrap()
+            .iter()
+            .map(|annotation| {
+                let annotation = pdf
+                    .get_dictionary(annotation.as_reference().unwrap())
+                    .unwrap();
+                let action = annotation.get(b"A").and_then(Object::as_dict).unwrap();
+                assert_eq!(action.get(b"S").and_then(Object::as_name).unwrap(), b"URI");
+                action
+                    .get(b"URI")
+                    .and_then(Object::as_str)
+                    .unwrap()
+                    .to_vec()
+            })
+            .collect();
+        assert_eq!(uris, [&b"https://example.com/a"[..], b"https://x.org"]);
+    }
+
+    #[test]
+    fn fails_on_references_to_undefined_labels() {
+        let mut document = parse_input("\\section{A}\\label{a}\n\nPage \\pageref{a}.").unwrap();
+        document.labels.clear();
+        assert!(matches!(
+            render(&document, &RenderConfig::default()),
+            Err(RustaTexError::ReferenceError(_))
+        ));
+    }
+
+    #[test]
+    fn draws_kerned_text_as_one_array_of_single_byte_codes() {
+        let document = parse_input("AVATAR Wave.").unwrap();
+        let pdf = lopdf::Document::load_mem(&render(&document, &RenderConfig::default()).unwrap())
+            .unwrap();
+        let page = page_ids(&pdf)[0];
+        let content = pdf.get_and_decode_page_content(page).unwrap();
+        let arrays: Vec<&Vec<Object>> = content
+            .operations
+            .iter()
+            .filter(|operation| operation.operator == "TJ")
+            .filter_map(|operation| operation.operands[0].as_array().ok())
+            .filter(|items| {
+                let text: Vec<u8> = items
+                    .iter()
+                    .filter_map(|item| item.as_str().ok())
+                    .flatten()
+                    .copied()
+                    .collect();
+                text == b"AVATAR Wave."
+            })
+            .collect();
+        assert_eq!(arrays.len(), 1);
+        let kerns = arrays[0]
+            .iter()
+            .filter_map(|item| item.as_i64().ok())
+            .filter(|&kern| kern > 0)
+            .count();
+        // A-V, V-A, A-T, T-A and W-a move closer together.
+        assert!(kerns >= 5, "{:?}", arrays[0]);
+        assert!(!content
+            .operations
+            .iter()
+            .any(|operation| operation.operator == "Tj"));
+    }
+
+    #[test]
+    fn sets_the_opacity_of_the_watermark_on_the_selected_pages_only() {
+        let mut source = "\\frontmatter\n\nPreface.\n\n\\mainmatter\n\n".to_string();
+        source.push_str(&"Body text.\n\n".repeat(40));
+        let document = parse_input(&source).unwrap();
+        let config = RenderConfig {
+            watermark: Some(Watermark {
+                opacity: 0.25,
+                pages: PageSelection::parse("2"),
+                ..Watermark::new("DRAFT")
+            }),
+            ..RenderConfig::default()
+        };
+        let pdf = lopdf::Document::load_mem(&render(&document, &config).unwrap()).unwrap();
+        let pages = page_ids(&pdf);
+        assert!(pages.len() >= 3, "{} pages", pages.len());
+
+        for (index, &page) in pages.iter().enumerate() {
+            let text = pdf.extract_text(&[index as u32 + 1]).unwrap();
+            let resources = pdf.get_dictionary(page).unwrap().get(b"Resources").unwrap();
+            let resources = pdf
+                .get_dictionary(resources.as_reference().unwrap())
+                .unwrap();
+            if index != 1 {
+                assert!(!text.contains("DRAFT"), "{}", text);
+                assert!(!resources.has(b"ExtGState"));
+                continue;
+            }
+            // The second page of the PDF, whose printed number is 1.
+            assert!(
+                text.contains("DRAFT") && text.contains("Body text."),
+                "{}",
+                text
+            );
+            let state = resources
+                .get(b"ExtGState")
+                .and_then(Object::as_dict)
+                .and_then(|states| states.get(WATERMARK_STATE))
+                .and_then(Object::as_dict)
+                .unwrap();
+            assert_eq!(state.get(b"ca").and_then(Object::as_f64).unwrap(), 0.25);
+            assert_eq!(state.get(b"CA").and_then(Object::as_f64).unwrap(), 0.25);
         }
     }
 }
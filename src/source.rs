@@ -0,0 +1,201 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Identifies a file in [`SourceFiles`].
+pub type FileId = usize;
+
+/// A file read while expanding a document.
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    /// The path the file was read from, or `None` for input given as a string.
+    pub path: Option<PathBuf>,
+    pub text: Rc<str>,
+    /// The file and offset of the `\input` or `\include` that spliced in this file.
+    pub included_from: Option<(FileId, usize)>,
+    lines: LineIndex,
+}
+
+/// All files that make up a document, the main file first.
+#[derive(Debug, Clone, Default)]
+pub struct SourceFiles {
+    files: Vec<SourceFile>,
+}
+
+impl SourceFiles {
+    pub fn new() -> Self {
+        SourceFiles::default()
+    }
+
+    /// Adds a file and returns its id.
+    pub fn add(
+        &mut self,
+        path: Option<PathBuf>,
+        text: impl Into<Rc<str>>,
+        included_from: Option<(FileId, usize)>,
+    ) -> FileId {
+        let text = text.into();
+        self.files.push(SourceFile {
+            path,
+            lines: LineIndex::new(&text),
+            text,
+            included_from,
+        });
+        self.files.len() - 1
+    }
+
+    pub fn get(&self, file: FileId) -> &SourceFile {
+        &self.files[file]
+    }
+
+    /// Returns the location of `offset` in `file`, including the chain of includes
+    /// that led to the file.
+    pub fn location(&self, file: FileId, offset: usize) -> Location {
+        let source = &self.files[file];
+        let (line, column) = source.lines.line_col(&source.text, offset);
+        Location {
+            path: source.path.clone(),
+            line,
+            column,
+            included_from: source
+                .included_from
+                .map(|(file, offset)| Box::new(self.location(file, offset))),
+        }
+    }
+}
+
+/// A position in one of the files of a document, as shown in diagnostics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// The file, or `None` for input given as a string.
+    pub path: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+    /// Where the file was included from.
+    pub included_from: Option<Box<Location>>,
+}
+
+impl fmt::Display for Location {
+    /// Formats as `line L, column C` for input without a path and as `file:L:C`
+    /// otherwise, followed by each include site.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}:{}:{}", path.display(), self.line, self.column)?,
+            None => write!(f, "line {}, column {}", self.line, self.column)?,
+        }
+        if let Some(parent) = &self.included_from {
+            write!(f, ", included from {}", parent)?;
+        }
+        Ok(())
+    }
+}
+
+/// Maps offsets in expanded text back to offsets in the source files.
+///
+/// Text copied verbatim from a file keeps its exact position; text produced by a
+/// macro expansion maps to the start of the macro invocation.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    /// Offset in the expanded text at which this segment starts.
+    output: usize,
+    file: FileId,
+    /// Offset in the file the segment maps to.
+    source: usize,
+    /// Whether the segment was copied verbatim from the source.
+    verbatim: bool,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    /// Records that expanded text from `output` on was copied verbatim from `source`
+    /// in `file`.
+    pub fn push_verbatim(&mut self, output: usize, file: FileId, source: usize) {
+        if let Some(last) = self.segments.last() {
+            if last.verbatim
+                && last.file == file
+                && output - last.output == source.wrapping_sub(last.source)
+            {
+                return;
+            }
+        }
+        self.push(Segment {
+            output,
+            file,
+            source,
+            verbatim: true,
+        });
+    }
+
+    /// Records that expanded text from `output` on was generated by the macro at `source`
+    /// in `file`.
+    pub fn push_generated(&mut self, output: usize, file: FileId, source: usize) {
+        self.push(Segment {
+            output,
+            file,
+            source,
+            verbatim: false,
+        });
+    }
+
+    fn push(&mut self, segment: Segment) {
+        // A later segment starting at the same output offset replaces the earlier one.
+        if self
+            .segments
+            .last()
+            .is_some_and(|last| last.output == segment.output)
+        {
+            self.segments.pop();
+        }
+        self.segments.push(segment);
+    }
+
+    /// Returns the file and offset in it for an offset in the expanded text.
+    pub fn source_position(&self, output: usize) -> (FileId, usize) {
+        let index = self
+            .segments
+            .partition_point(|segment| segment.output <= output);
+        match index.checked_sub(1).map(|index| self.segments[index]) {
+            Some(segment) if segment.verbatim => {
+                (segment.file, segment.source + (output - segment.output))
+            }
+            Some(segment) => (segment.file, segment.source),
+            None => (0, output),
+        }
+    }
+}
+
+/// Converts byte offsets in a text into 1-based line and column numbers.
+#[derive(Debug, Clone)]
+pub struct LineIndex {
+    /// Byte offset at which each line starts.
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        LineIndex { line_starts }
+    }
+
+    /// Returns the line and column of `offset` in `text`, the text this index was built from.
+    pub fn line_col(&self, text: &str, offset: usize) -> (usize, usize) {
+        let offset = offset.min(text.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        let line_start = self.line_starts[line - 1];
+        let column = text
+            .get(line_start..offset)
+            .map_or(offset - line_start, |prefix| prefix.chars().count())
+            + 1;
+        (line, column)
+    }
+}
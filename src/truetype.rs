@@ -0,0 +1,596 @@
+use crate::errors::RustaTexError;
+use crate::metrics::TextMetrics;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::ops::Range;
+
+/// The tables a font embedded in a PDF needs, which are all a subset keeps. PDF viewers
+/// do not use the `cmap` table of a font drawn by glyph id, but some font parsers
+/// refuse fonts without one.
+const SUBSET_TABLES: [&[u8; 4]; 10] = [
+    b"cmap", b"cvt ", b"fpgm", b"glyf", b"head", b"hhea", b"hmtx", b"loca", b"maxp", b"prep",
+];
+
+/// A TrueType font, or an OpenType font with TrueType outlines, read from its tables.
+pub struct TrueTypeFont {
+    data: Vec<u8>,
+    /// The byte range of each table in `data`.
+    tables: HashMap<[u8; 4], Range<usize>>,
+    /// The PostScript name, or an empty string if the font has none.
+    pub name: String,
+    pub units_per_em: u16,
+    pub ascent: i16,
+    pub descent: i16,
+    /// The advance width of each glyph in font units.
+    advances: Vec<u16>,
+    /// The glyph of each character.
+    glyphs: HashMap<char, u16>,
+    /// The change to the advance of the first glyph of each kerned pair, in font units.
+    kerning: HashMap<(u16, u16), i16>,
+}
+
+impl TrueTypeFont {
+    /// Reads the tables of a font file. Fonts with CFF outlines and font collections
+    /// are rejected, as only TrueType outlines can be embedded.
+    pub fn parse(data: Vec<u8>) -> Result<Self, RustaTexError> {
+        match data.get(..4) {
+            Some(b"OTTO") => {
+                return Err(RustaTexError::FontError(
+                    "OpenType fonts with CFF outlines are not supported".to_string(),
+                ))
+            }
+            Some(b"ttcf") => {
+                return Err(RustaTexError::FontError(
+                    "Font collections are not supported".to_string(),
+                ))
+            }
+            Some(b"\0\x01\0\0" | b"true") => (),
+            _ => {
+                return Err(RustaTexError::FontError(
+                    "Not a TrueType or OpenType font".to_string(),
+                ))
+            }
+        }
+        let mut tables = HashMap::new();
+        for index in 0..read_u16(&data, 4)? as usize {
+            let record = 12 + 16 * index;
+            let tag = read(&data, record..record + 4)?;
+            let offset = read_u32(&data, record + 8)? as usize;
+            let length = read_u32(&data, record + 12)? as usize;
+            read(&data, offset..offset + length)?;
+            tables.insert([tag[0], tag[1], tag[2], tag[3]], offset..offset + length);
+        }
+        let table = |tag| find_table(&data, &tables, tag);
+
+        let head = table(b"head")?;
+        let hhea = table(b"hhea")?;
+        let glyph_count = read_u16(table(b"maxp")?, 4)? as usize;
+        let hmtx = table(b"hmtx")?;
+        let metric_count = (read_u16(hhea, 34)? as usize).clamp(1, glyph_count.max(1));
+        let mut advances = Vec::with_capacity(glyph_count);
+        for glyph in 0..glyph_count {
+            advances.push(read_u16(hmtx, 4 * glyph.min(metric_count - 1))?);
+        }
+        let name = match tables.get(b"name") {
+            Some(range) => postscript_name(&data[range.clone()])?,
+            None => String::new(),
+        };
+        let kerning = match tables.get(b"kern") {
+            Some(range) => kerning_pairs(&data[range.clone()])?,
+            None => HashMap::new(),
+        };
+
+        Ok(TrueTypeFont {
+            name,
+            units_per_em: read_u16(head, 18)?.max(1),
+            ascent: read_u16(hhea, 4)? as i16,
+            descent: read_u16(hhea, 6)? as i16,
+            advances,
+            glyphs: character_map(table(b"cmap")?)?,
+            kerning,
+            tables,
+            data,
+        })
+    }
+
+    /// The font file as it was read.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the glyph drawn for `c`, if the font has one.
+    pub fn glyph(&self, c: char) -> Option<u16> {
+        self.glyphs.get(&c).copied()
+    }
+
+    /// Returns the advance width of `glyph` in font units.
+    pub fn advance(&self, glyph: u16) -> u16 {
+        self.advances.get(glyph as usize).copied().unwrap_or(0)
+    }
+
+    /// Returns a copy of the font with the outlines of all glyphs but `glyphs`, the
+    /// glyphs they are composed of and the `.notdef` glyph left out. Glyph ids stay
+    /// the same, so text drawn with the full font can be shown with the subset. The
+    /// glyphs after the last one kept are left out of `loca` and `hmtx`, but those
+    /// tables keep an entry for each glyph before it. The `cmap` table and the hinting
+    /// tables `cvt `, `fpgm` and `prep` are kept whole, as the instructions of the
+    /// kept glyphs may call any function the font defines.
+    pub fn subset(&self, glyphs: &BTreeSet<u16>) -> Result<Vec<u8>, RustaTexError> {
+        let glyf = self.table(b"glyf")?;
+        let loca = self.glyph_offsets()?;
+        let outline = |glyph: u16| -> Result<&[u8], RustaTexError> {
+            let range = loca[glyph as usize] as usize..loca[glyph as usize + 1] as usize;
+            read(glyf, range)
+        };
+
+        let mut kept = BTreeSet::new();
+        let mut pending: Vec<u16> = glyphs.iter().copied().chain([0]).collect();
+        while let Some(glyph) = pending.pop() {
+            if glyph as usize >= self.advances.len() || !kept.insert(glyph) {
+                continue;
+            }
+            pending.extend(components(outline(glyph)?)?);
+        }
+
+        // `.notdef` is always kept.
+        let count = kept.last().map_or(1, |&glyph| glyph as usize + 1);
+        let mut new_glyf = Vec::new();
+        let mut new_loca = Vec::with_capacity(4 * (count + 1));
+        for glyph in 0..count as u16 {
+            new_loca.extend_from_slice(&(new_glyf.len() as u32).to_be_bytes());
+            if kept.contains(&glyph) {
+                new_glyf.extend_from_slice(outline(glyph)?);
+                new_glyf.resize(new_glyf.len().next_multiple_of(4), 0);
+            }
+        }
+        new_loca.extend_from_slice(&(new_glyf.len() as u32).to_be_bytes());
+
+        let mut head = self.table(b"head")?.to_vec();
+        read(&head, 0..54)?;
+        // The checksum adjustment is filled in once the whole file is written, and the
+        // new `loca` table has long offsets.
+        head[8..12].copy_from_slice(&[0; 4]);
+        head[50..52].copy_from_slice(&1u16.to_be_bytes());
+        // `hmtx` has the advance and left side bearing of each of the first
+        // `metric_count` glyphs, then the left side bearings of the rest.
+        let mut hhea = self.table(b"hhea")?.to_vec();
+        let metric_count = (read_u16(&hhea, 34)? as usize).clamp(1, self.advances.len());
+        let new_metric_count = metric_count.min(count);
+        hhea[34..36].copy_from_slice(&(new_metric_count as u16).to_be_bytes());
+        let hmtx = read(
+            self.table(b"hmtx")?,
+            0..4 * new_metric_count + 2 * (count - new_metric_count),
+        )?
+        .to_vec();
+        let mut maxp = self.table(b"maxp")?.to_vec();
+        maxp[4..6].copy_from_slice(&(count as u16).to_be_bytes());
+
+        let mut tables = Vec::new();
+        for tag in SUBSET_TABLES {
+            let table = match tag {
+                b"glyf" => new_glyf.clone(),
+                b"loca" => new_loca.clone(),
+                b"head" => head.clone(),
+                b"hhea" => hhea.clone(),
+                b"hmtx" => hmtx.clone(),
+                b"maxp" => maxp.clone(),
+                _ => match self.tables.get(tag) {
+                    Some(range) => self.data[range.clone()].to_vec(),
+                    None => continue,
+                },
+            };
+            tables.push((tag, table));
+        }
+
+        let count = tables.len() as u16;
+        let selector = 15 - count.leading_zeros() as u16;
+        let search_range = 16 << selector;
+        let mut font = Vec::new();
+        font.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+        for value in [count, search_range, selector, count * 16 - search_range] {
+            font.extend_from_slice(&value.to_be_bytes());
+        }
+        let mut offset = 12 + 16 * tables.len();
+        let mut head_offset = 0;
+        for (tag, table) in &tables {
+            if *tag == b"head" {
+                head_offset = offset;
+            }
+            font.extend_from_slice(*tag);
+            font.extend_from_slice(&checksum(table).to_be_bytes());
+            font.extend_from_slice(&(offset as u32).to_be_bytes());
+            font.extend_from_slice(&(table.len() as u32).to_be_bytes());
+            offset += table.len().next_multiple_of(4);
+        }
+        for (_, table) in &tables {
+            font.extend_from_slice(table);
+            font.resize(font.len().next_multiple_of(4), 0);
+        }
+        let adjustment = 0xB1B0_AFBAu32.wrapping_sub(checksum(&font));
+        font[head_offset + 8..head_offset + 12].copy_from_slice(&adjustment.to_be_bytes());
+        Ok(font)
+    }
+
+    fn table(&self, tag: &[u8; 4]) -> Result<&[u8], RustaTexError> {
+        find_table(&self.data, &self.tables, tag)
+    }
+
+    /// Reads where the outline of each glyph starts in the `glyf` table, followed by
+    /// where the last one ends.
+    fn glyph_offsets(&self) -> Result<Vec<u32>, RustaTexError> {
+        let loca = self.table(b"loca")?;
+        let long = read_u16(self.table(b"head")?, 50)? != 0;
+        (0..=self.advances.len())
+            .map(|index| match long {
+                true => read_u32(loca, 4 * index),
+                false => Ok(read_u16(loca, 2 * index)? as u32 * 2),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Debug for TrueTypeFont {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrueTypeFont")
+            .field("name", &self.name)
+            .field("glyphs", &self.advances.len())
+            .finish()
+    }
+}
+
+impl TextMetrics for TrueTypeFont {
+    fn encodes(&self, c: char) -> bool {
+        self.glyphs.contains_key(&c)
+    }
+
+    /// PDF viewers take the widths from the font dictionary, where printpdf writes them
+    /// rounded down to whole thousandths, so text is measured the same way.
+    fn char_width(&self, c: char) -> f64 {
+        self.glyph(c).map_or(0.0, |glyph| {
+            (self.advance(glyph) as u32 * 1000 / self.units_per_em as u32) as f64
+        })
+    }
+
+    fn kerning(&self, left: char, right: char) -> f64 {
+        match (self.glyph(left), self.glyph(right)) {
+            (Some(left), Some(right)) => self.kerning.get(&(left, right)).map_or(0.0, |&value| {
+                value as f64 * 1000.0 / self.units_per_em as f64
+            }),
+            _ => 0.0,
+        }
+    }
+}
+
+fn find_table<'a>(
+    data: &'a [u8],
+    tables: &HashMap<[u8; 4], Range<usize>>,
+    tag: &[u8; 4],
+) -> Result<&'a [u8], RustaTexError> {
+    match tables.get(tag) {
+        Some(range) => Ok(&data[range.clone()]),
+        None => Err(RustaTexError::FontError(format!(
+            "Font has no '{}' table",
+            String::from_utf8_lossy(tag)
+        ))),
+    }
+}
+
+/// Reads the glyph of each character from the best Unicode subtable of a `cmap` table.
+fn character_map(cmap: &[u8]) -> Result<HashMap<char, u16>, RustaTexError> {
+    let mut best = None;
+    for index in 0..read_u16(cmap, 2)? as usize {
+        let record = 4 + 8 * index;
+        let platform = read_u16(cmap, record)?;
+        let encoding = read_u16(cmap, record + 2)?;
+        let offset = read_u32(cmap, record + 4)? as usize;
+        let format = read_u16(cmap, offset)?;
+        let unicode = platform == 0 || (platform == 3 && matches!(encoding, 1 | 10));
+        let rank = match format {
+            12 if unicode => 3,
+            4 if unicode => 2,
+            4 if platform == 3 && encoding == 0 => 1,
+            _ => continue,
+        };
+        if best.is_none_or(|(best_rank, _)| rank > best_rank) {
+            best = Some((rank, offset));
+        }
+    }
+    let Some((_, offset)) = best else {
+        return Err(RustaTexError::FontError(
+            "Font has no Unicode character map".to_string(),
+        ));
+    };
+
+    let mut glyphs = HashMap::new();
+    let subtable = &cmap[offset..];
+    if read_u16(subtable, 0)? == 12 {
+        for group in 0..read_u32(subtable, 12)? as usize {
+            let record = 16 + 12 * group;
+            let start = read_u32(subtable, record)?;
+            let end = read_u32(subtable, record + 4)?;
+            let first_glyph = read_u32(subtable, record + 8)?;
+            for code in start..=end.min(start.saturating_add(0xFFFF)) {
+                let glyph = first_glyph + (code - start);
+                if let (Some(c), Ok(glyph)) = (char::from_u32(code), u16::try_from(glyph)) {
+                    glyphs.insert(c, glyph);
+                }
+            }
+        }
+    } else {
+        let segments = read_u16(subtable, 6)? as usize / 2;
+        let ends = 14;
+        let starts = ends + 2 * segments + 2;
+        let deltas = starts + 2 * segments;
+        let range_offsets = deltas + 2 * segments;
+        for segment in 0..segments {
+            let end = read_u16(subtable, ends + 2 * segment)?;
+            let start = read_u16(subtable, starts + 2 * segment)?;
+            let delta = read_u16(subtable, deltas + 2 * segment)?;
+            let range_offset = read_u16(subtable, range_offsets + 2 * segment)? as usize;
+            for code in start..=end.min(0xFFFE) {
+                let glyph = if range_offset == 0 {
+                    code.wrapping_add(delta)
+                } else {
+                    let at =
+                        range_offsets + 2 * segment + range_offset + 2 * (code - start) as usize;
+                    match read_u16(subtable, at)? {
+                        0 => 0,
+                        glyph => glyph.wrapping_add(delta),
+                    }
+                };
+                if let (Some(c), true) = (char::from_u32(code as u32), glyph != 0) {
+                    glyphs.insert(c, glyph);
+                }
+            }
+        }
+    }
+    Ok(glyphs)
+}
+
+/// Reads the horizontal kerning pairs of a `kern` table.
+fn kerning_pairs(kern: &[u8]) -> Result<HashMap<(u16, u16), i16>, RustaTexError> {
+    let mut pairs = HashMap::new();
+    // Only the Microsoft version of the table is read; Apple's begins with a 32-bit
+    // version 1.
+    if read_u16(kern, 0)? != 0 {
+        return Ok(pairs);
+    }
+    let mut offset = 4;
+    for _ in 0..read_u16(kern, 2)? {
+        let length = read_u16(kern, offset + 2)? as usize;
+        let coverage = read_u16(kern, offset + 4)?;
+        // Format 0 with horizontal kerning values, rather than minimum values or
+        // cross-stream kerning.
+        if coverage >> 8 == 0 && coverage & 0x7 == 0x1 {
+            for pair in 0..read_u16(kern, offset + 6)? as usize {
+                let record = offset + 14 + 6 * pair;
+                let left = read_u16(kern, record)?;
+                let right = read_u16(kern, record + 2)?;
+                pairs.insert((left, right), read_u16(kern, record + 4)? as i16);
+            }
+        }
+        offset += length.max(6);
+    }
+    Ok(pairs)
+}
+
+/// Reads the PostScript name from a `name` table.
+fn postscript_name(name: &[u8]) -> Result<String, RustaTexError> {
+    let strings = read_u16(name, 4)? as usize;
+    for index in 0..read_u16(name, 2)? as usize {
+        let record = 6 + 12 * index;
+        if read_u16(name, record + 6)? != 6 {
+            continue;
+        }
+        let platform = read_u16(name, record)?;
+        let length = read_u16(name, record + 8)? as usize;
+        let offset = strings + read_u16(name, record + 10)? as usize;
+        let bytes = read(name, offset..offset + length)?;
+        return Ok(match platform {
+            // Macintosh names are single-byte Roman text, the others UTF-16.
+            1 => bytes.iter().map(|&byte| byte as char).collect(),
+            _ => char::decode_utf16(
+                bytes
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]])),
+            )
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect(),
+        });
+    }
+    Ok(String::new())
+}
+
+/// Returns the glyphs a composite glyph's outline is made of, or none for a simple
+/// glyph.
+fn components(outline: &[u8]) -> Result<Vec<u16>, RustaTexError> {
+    let mut glyphs = Vec::new();
+    if outline.is_empty() || read_u16(outline, 0)? as i16 >= 0 {
+        return Ok(glyphs);
+    }
+    let mut offset = 10;
+    loop {
+        let flags = read_u16(outline, offset)?;
+        glyphs.push(read_u16(outline, offset + 2)?);
+        offset += 4;
+        // The arguments are words or bytes, followed by a scale, an x and y scale or
+        // a 2 by 2 matrix.
+        offset += if flags & 0x1 != 0 { 4 } else { 2 };
+        if flags & 0x8 != 0 {
+            offset += 2;
+        } else if flags & 0x40 != 0 {
+            offset += 4;
+        } else if flags & 0x80 != 0 {
+            offset += 8;
+        }
+        if flags & 0x20 == 0 {
+            return Ok(glyphs);
+        }
+    }
+}
+
+/// Returns the sum of the big-endian 32-bit words of `table`, padded with zeros.
+fn checksum(table: &[u8]) -> u32 {
+    table.chunks(4).fold(0u32, |sum, chunk| {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        sum.wrapping_add(u32::from_be_bytes(word))
+    })
+}
+
+fn read(data: &[u8], range: Range<usize>) -> Result<&[u8], RustaTexError> {
+    data.get(range).ok_or_else(|| {
+        RustaTexError::FontError("Font data ends in the middle of a table".to_string())
+    })
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, RustaTexError> {
+    let bytes = read(data, offset..offset + 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, RustaTexError> {
+    let bytes = read(data, offset..offset + 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a font with the glyphs `.notdef`, `A`, `B` and a composite `C` made of
+    /// `B`, a kerning pair `A B` and the PostScript name `Test-Regular`.
+    fn font_file() -> Vec<u8> {
+        let u16s = |values: &[u16]| -> Vec<u8> {
+            values
+                .iter()
+                .flat_map(|value| value.to_be_bytes())
+                .collect()
+        };
+        let simple = [u16s(&[1]), vec![0; 10]].concat();
+        let composite = [u16s(&[0xFFFF, 0, 0, 0, 0, 0x0001, 2, 0, 0]), vec![0; 2]].concat();
+        let outlines = [simple.clone(), simple.clone(), simple, composite];
+        let mut glyf = Vec::new();
+        let mut loca = Vec::new();
+        for outline in &outlines {
+            loca.push((glyf.len() / 2) as u16);
+            glyf.extend_from_slice(outline);
+        }
+        loca.push((glyf.len() / 2) as u16);
+
+        let mut head = vec![0; 54];
+        head[18..20].copy_from_slice(&2048u16.to_be_bytes());
+        let mut hhea = vec![0; 36];
+        hhea[4..6].copy_from_slice(&1900u16.to_be_bytes());
+        hhea[6..8].copy_from_slice(&(-500i16).to_be_bytes());
+        hhea[34..36].copy_from_slice(&3u16.to_be_bytes());
+        let cmap = [
+            u16s(&[0, 1, 3, 1, 0, 12]),
+            u16s(&[4, 32, 0, 4, 4, 1, 0]),
+            u16s(&[
+                0x43,
+                0xFFFF,
+                0,
+                0x41,
+                0xFFFF,
+                1u16.wrapping_sub(0x41),
+                1,
+                0,
+                0,
+            ]),
+        ]
+        .concat();
+        let name = [
+            u16s(&[0, 1, 18, 3, 1, 0x409, 6, 24, 0]),
+            "Test-Regular"
+                .encode_utf16()
+                .flat_map(u16::to_be_bytes)
+                .collect(),
+        ]
+        .concat();
+        let kern = u16s(&[0, 1, 0, 20, 0x0001, 1, 6, 0, 0, 1, 2, (-205i16) as u16]);
+        let tables: [(&[u8; 4], Vec<u8>); 10] = [
+            (b"cmap", cmap),
+            (b"glyf", glyf),
+            (b"head", head),
+            (b"hhea", hhea),
+            (b"hmtx", u16s(&[500, 0, 1300, 0, 1200, 0, 1100])),
+            (b"kern", kern),
+            (b"loca", u16s(&loca)),
+            (b"maxp", u16s(&[0x0001, 0, 4])),
+            (b"name", name),
+            (b"post", u16s(&[3, 0])),
+        ];
+
+        let mut font = u16s(&[1, 0, tables.len() as u16, 128, 3, 32]);
+        let mut offset = 12 + 16 * tables.len();
+        for (tag, table) in &tables {
+            font.extend_from_slice(*tag);
+            font.extend_from_slice(&checksum(table).to_be_bytes());
+            font.extend_from_slice(&(offset as u32).to_be_bytes());
+            font.extend_from_slice(&(table.len() as u32).to_be_bytes());
+            offset += table.len().next_multiple_of(4);
+        }
+        for (_, table) in &tables {
+            font.extend_from_slice(table);
+            font.resize(font.len().next_multiple_of(4), 0);
+        }
+        font
+    }
+
+    #[test]
+    fn parses_metrics_and_kerning() {
+        let font = TrueTypeFont::parse(font_file()).unwrap();
+        assert_eq!(font.name, "Test-Regular");
+        assert_eq!(
+            (font.units_per_em, font.ascent, font.descent),
+            (2048, 1900, -500)
+        );
+        assert_eq!(font.glyph('A'), Some(1));
+        assert_eq!(font.glyph('C'), Some(3));
+        assert_eq!(font.glyph('D'), None);
+        // Glyphs past the horizontal metrics share the last advance.
+        assert_eq!(font.advance(3), 1200);
+        assert_eq!(font.char_width('A'), 634.0);
+        assert_eq!(font.kerning('A', 'B'), -205.0 * 1000.0 / 2048.0);
+        assert_eq!(font.kerning('B', 'A'), 0.0);
+    }
+
+    #[test]
+    fn subsets_keep_used_glyphs_and_their_components() {
+        let font = TrueTypeFont::parse(font_file()).unwrap();
+        let data = font.subset(&BTreeSet::from([3])).unwrap();
+        assert_eq!(checksum(&data), 0xB1B0_AFBA);
+
+        let subset = TrueTypeFont::parse(data).unwrap();
+        assert_eq!(subset.glyph('C'), Some(3));
+        assert_eq!(subset.advance(1), font.advance(1));
+        let offsets = subset.glyph_offsets().unwrap();
+        let lengths: Vec<u32> = offsets.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        // `.notdef`, the composite and its component are kept, `A` is left out.
+        assert_eq!(lengths, [12, 0, 12, 20]);
+        assert!(!subset.tables.contains_key(b"kern"));
+        assert_eq!(
+            subset.subset(&BTreeSet::from([3])).unwrap(),
+            subset.data(),
+            "subsetting a subset changes nothing"
+        );
+
+        // The glyphs after `A` are left out of `loca` and `hmtx`.
+        let subset = TrueTypeFont::parse(font.subset(&BTreeSet::from([1])).unwrap()).unwrap();
+        assert_eq!(subset.advances, [500, 1300]);
+        assert_eq!(subset.glyph_offsets().unwrap(), [0, 12, 24]);
+        assert_eq!(read_u16(subset.table(b"hhea").unwrap(), 34).unwrap(), 2);
+        assert_eq!(subset.table(b"hmtx").unwrap().len(), 8);
+    }
+
+    #[test]
+    fn rejects_cff_fonts_and_collections() {
+        for data in [b"OTTO".to_vec(), b"ttcf".to_vec(), b"wOFF".to_vec()] {
+            assert!(matches!(
+                TrueTypeFont::parse(data),
+                Err(RustaTexError::FontError(_))
+            ));
+        }
+    }
+}